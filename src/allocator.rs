@@ -0,0 +1,57 @@
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Debug allocator that counts allocations made on the process thread while it's in the process
+ * callback, build with --features alloc-debug to have these reported
+ */
+pub struct CountingAllocator;
+
+thread_local! {
+    // Const initialized, so accessing it from the allocator does not allocate itself
+    static IN_PROCESS: Cell<bool> = const { Cell::new(false) };
+}
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn count() {
+    if IN_PROCESS.try_with(|in_process| in_process.get()).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    // Freeing is just as bad as allocating on the process thread
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count();
+        System.dealloc(ptr, layout)
+    }
+}
+
+// Start counting allocations of this thread
+pub fn enter() {
+    IN_PROCESS.with(|in_process| in_process.set(true));
+}
+
+// Stop counting, returns how many allocations were made since enter
+pub fn leave() -> usize {
+    IN_PROCESS.with(|in_process| in_process.set(false));
+    ALLOCATIONS.swap(0, Ordering::Relaxed)
+}
@@ -0,0 +1,412 @@
+
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::time::Duration;
+use crossbeam_queue::ArrayQueue;
+use super::{TickRange, TimebaseHandler};
+use super::cycle::ProcessCycle;
+use super::sequencer::Sequencer;
+use super::surface::Surface;
+use super::project::Project;
+use super::loopable::*;
+use super::events::*;
+use super::history::EditTarget;
+use super::message::{TimedMessage, Message};
+
+/*
+ * Commands frontends like a terminal ui or osc server send to the process thread, which owns all
+ * state. Commands are Copy, so they can be passed through a lock-free queue without allocating
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Start,
+    Stop,
+    Reset,
+    SetTempo(f64),
+    QueueSequence(usize),
+
+    // Phrase sequence plays for track, None plays nothing
+    SetSequencePhrase { sequence: usize, track: usize, phrase: Option<u8> },
+    ToggleSequenceTrack { sequence: usize, track: usize },
+
+    AddNote { track: usize, pattern: u8, start: u32, stop: u32, note: u8, velocity: u8 },
+    RemoveNote { track: usize, pattern: u8, start: u32, note: u8 },
+    ClearPattern { track: usize, pattern: u8 },
+
+    AddPatternEvent { track: usize, phrase: u8, start: u32, stop: u32, pattern: u8 },
+    RemovePatternEvent { track: usize, phrase: u8, start: u32, pattern: u8 },
+    ClearPhrase { track: usize, phrase: u8 },
+
+    AddPhraseEvent { track: usize, start: u32, stop: u32, phrase: u8 },
+    RemovePhraseEvent { track: usize, start: u32, phrase: u8 },
+    ClearTimeline { track: usize },
+
+    Undo,
+    Redo,
+    ShowTrack(usize),
+    Save,
+    // Ask for a snapshot of the whole session
+    Snapshot,
+}
+
+// Things that changed every cycle, cheap enough to send every cycle
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Status {
+    pub is_rolling: bool,
+    pub tick: u32,
+    pub beats_per_minute: f64,
+    pub sequence_playing: usize,
+    pub sequence_queued: Option<usize>,
+    pub track_count: usize,
+    pub track_shown: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Status(Status),
+    Failed(Command, &'static str),
+    // Midi message that changed a led of controller with index
+    Led { controller: usize, message: [u8; 3] },
+}
+
+// Copy of the session, only sent when frontend asks for it
+pub struct Snapshot {
+    pub project: Project,
+    pub status: Status,
+}
+
+/*
+ * Process side of the bus, frontends are connected before the process handler is handed to jack,
+ * so the process thread never has to allocate queues
+ */
+pub struct Bus {
+    // Commands of all frontends, with the index of the frontend that sent it
+    commands: Arc<ArrayQueue<(usize, Command)>>,
+    events: Vec<Arc<ArrayQueue<Event>>>,
+    // Bounded, as sending through unbounded channels allocates
+    snapshots: Vec<SyncSender<Snapshot>>,
+    // Projects frontends loaded, these replace the session
+    projects: Receiver<Project>,
+    project_sender: SyncSender<Project>,
+}
+
+// Frontend side of the bus, one for every frontend as every frontend wants to see all events
+pub struct Frontend {
+    index: usize,
+    commands: Arc<ArrayQueue<(usize, Command)>>,
+    events: Arc<ArrayQueue<Event>>,
+    snapshots: Receiver<Snapshot>,
+    projects: SyncSender<Project>,
+}
+
+impl Bus {
+    const COMMAND_CAPACITY: usize = 256;
+    // Room for a full redraw of the leds of a couple of controllers
+    const EVENT_CAPACITY: usize = 1024;
+    // Frontends throw away snapshots they did not pick up before asking for a new one
+    const SNAPSHOT_CAPACITY: usize = 2;
+    // Frontends wait for the process thread to pick up projects when they load more
+    const PROJECT_CAPACITY: usize = 2;
+
+    pub fn new() -> Self {
+        let (project_sender, projects) = sync_channel(Self::PROJECT_CAPACITY);
+
+        Bus { commands: Arc::new(ArrayQueue::new(Self::COMMAND_CAPACITY)), events: vec![], snapshots: vec![], projects, project_sender }
+    }
+
+    pub fn connect(&mut self) -> Frontend {
+        let events = Arc::new(ArrayQueue::new(Self::EVENT_CAPACITY));
+        let (snapshot_sender, snapshot_receiver) = sync_channel(Self::SNAPSHOT_CAPACITY);
+
+        self.events.push(events.clone());
+        self.snapshots.push(snapshot_sender);
+
+        Frontend {
+            index: self.events.len() - 1,
+            commands: self.commands.clone(),
+            events,
+            snapshots: snapshot_receiver,
+            projects: self.project_sender.clone(),
+        }
+    }
+
+    pub fn frontend_count(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn next_command(&mut self) -> Option<(usize, Command)> {
+        self.commands.pop()
+    }
+
+    pub fn next_project(&mut self) -> Option<Project> {
+        self.projects.try_recv().ok()
+    }
+
+    // Frontends that don't keep up lose their oldest events
+    fn send(&self, frontend: usize, event: Event) {
+        if let Some(events) = self.events.get(frontend) {
+            events.force_push(event);
+        }
+    }
+
+    pub fn failed(&self, frontend: usize, command: Command, reason: &'static str) {
+        self.send(frontend, Event::Failed(command, reason));
+    }
+
+    pub fn publish(&self, status: Status) {
+        for frontend in 0 .. self.events.len() {
+            self.send(frontend, Event::Status(status));
+        }
+    }
+
+    pub fn publish_leds(&self, controller: usize, messages: &[TimedMessage]) {
+        for timed_message in messages {
+            // Identification sysex does not change leds
+            if let Message::Note(message) = timed_message.message {
+                for frontend in 0 .. self.events.len() {
+                    self.send(frontend, Event::Led { controller, message });
+                }
+            }
+        }
+    }
+
+    pub fn send_snapshot(&self, frontend: usize, snapshot: Snapshot) {
+        if let Some(sender) = self.snapshots.get(frontend) {
+            sender.try_send(snapshot).ok();
+        }
+    }
+}
+
+impl Frontend {
+    pub fn send(&self, command: Command) -> Result<(), String> {
+        self.commands.push((self.index, command))
+            .map_err(|_| "Command queue is full, is the process thread running?".to_string())
+    }
+
+    // Events that arrived since last call
+    pub fn events(&self) -> impl Iterator<Item = Event> + '_ {
+        std::iter::from_fn(move || self.events.pop())
+    }
+
+    // Replace session with project
+    pub fn load(&self, project: Project) -> Result<(), String> {
+        self.projects.send(project).map_err(|_| "Process thread is gone".to_string())
+    }
+
+    pub fn snapshot(&self, timeout: Duration) -> Result<Snapshot, String> {
+        // Snapshots we did not pick up in time are outdated
+        while self.snapshots.try_recv().is_ok() {}
+
+        self.send(Command::Snapshot)?;
+        self.snapshots.recv_timeout(timeout)
+            .map_err(|_| "No snapshot received, is the process thread running?".to_string())
+    }
+}
+
+fn check_track(sequencer: &Sequencer, track: usize) -> Result<(), &'static str> {
+    if track < sequencer.track_count() { Ok(()) } else { Err("track does not exist") }
+}
+
+fn check_loopable(index: u8) -> Result<(), &'static str> {
+    if index < 5 { Ok(()) } else { Err("patterns & phrases are numbered 0 - 4") }
+}
+
+// Loopable lengths are calculated from ticks of events, keep them far away from overflowing
+const MAX_TICK: u32 = TimebaseHandler::TICKS_PER_BEAT as u32 * 4 * 10000;
+
+fn check_tick(tick: u32) -> Result<(), &'static str> {
+    if tick < MAX_TICK { Ok(()) } else { Err("ticks have to stay within 10000 bars") }
+}
+
+fn check_range(start: u32, stop: u32) -> Result<(), &'static str> {
+    check_tick(stop)?;
+    if start < stop { Ok(()) } else { Err("start has to be before stop") }
+}
+
+// Ticks where events start that are removed
+fn removed_range(start: u32) -> Result<TickRange, &'static str> {
+    check_tick(start)?;
+    Ok(TickRange::new(start, start.saturating_add(1)))
+}
+
+fn check_sequence(sequence: usize) -> Result<(), &'static str> {
+    if sequence < 5 { Ok(()) } else { Err("sequences are numbered 0 - 4") }
+}
+
+/*
+ * Apply command to session, this does the same as the controllers do, edits are remembered so
+ * they can be undone from the controllers aswell
+ */
+pub fn apply(command: Command, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) -> Result<(), &'static str> {
+    match command {
+        Command::Start => sequencer.start(cycle),
+        Command::Stop => sequencer.stop(cycle),
+        Command::Reset => sequencer.reset(cycle),
+        Command::SetTempo(_) if cycle.follows_transport => return Err("tempo follows timebase master"),
+        Command::SetTempo(beats_per_minute) => {
+            if ! (1.0 ..= 999.0).contains(&beats_per_minute) {
+                return Err("tempo has to be between 1 & 999 bpm")
+            }
+            sequencer.set_tempo(beats_per_minute);
+        },
+        Command::QueueSequence(sequence) => {
+            check_sequence(sequence)?;
+            sequencer.sequence_queued = Some(sequence);
+        },
+        Command::SetSequencePhrase { sequence, track, phrase } => {
+            check_sequence(sequence)?;
+            check_track(sequencer, track)?;
+            if let Some(phrase) = phrase {
+                check_loopable(phrase)?;
+            }
+
+            sequencer.remember(EditTarget::Sequence(sequence));
+            match phrase {
+                Some(phrase) => sequencer.get_sequence(sequence).set_phrase(track, phrase),
+                None => sequencer.get_sequence(sequence).unset_phrase(track),
+            }
+        },
+        Command::ToggleSequenceTrack { sequence, track } => {
+            check_sequence(sequence)?;
+            check_track(sequencer, track)?;
+            sequencer.remember(EditTarget::Sequence(sequence));
+            sequencer.get_sequence(sequence).toggle_active(track);
+        },
+        Command::AddNote { track, pattern, start, stop, note, velocity } => {
+            check_track(sequencer, track)?;
+            check_loopable(pattern)?;
+            check_range(start, stop)?;
+            if note > 127 || velocity > 127 {
+                return Err("notes & velocities are in midi range 0 - 127")
+            }
+
+            let mut event = LoopableNoteEvent::new(start, note, velocity);
+            event.set_stop(stop);
+            event.stop_velocity = Some(127);
+            sequencer.remember(EditTarget::Pattern(track, pattern));
+            sequencer.track_mut(track).pattern_mut(pattern).add_complete_event(event);
+        },
+        Command::RemoveNote { track, pattern, start, note } => {
+            check_track(sequencer, track)?;
+            check_loopable(pattern)?;
+            let range = removed_range(start)?;
+            if ! sequencer.track_mut(track).pattern_mut(pattern).contains_events_starting_in(range, note) {
+                return Err("no note starts there")
+            }
+
+            sequencer.remember(EditTarget::Pattern(track, pattern));
+            sequencer.track_mut(track).pattern_mut(pattern).remove_events_starting_in(range, note);
+        },
+        Command::ClearPattern { track, pattern } => {
+            check_track(sequencer, track)?;
+            check_loopable(pattern)?;
+            if ! sequencer.track(track).pattern(pattern).events().is_empty() {
+                sequencer.remember(EditTarget::Pattern(track, pattern));
+                sequencer.track_mut(track).pattern_mut(pattern).clear_events();
+            }
+        },
+        Command::AddPatternEvent { track, phrase, start, stop, pattern } => {
+            check_track(sequencer, track)?;
+            check_loopable(phrase)?;
+            check_loopable(pattern)?;
+            check_range(start, stop)?;
+
+            let mut event = LoopablePatternEvent::new(start, pattern);
+            event.set_stop(stop);
+            sequencer.remember(EditTarget::Phrase(track, phrase));
+            sequencer.track_mut(track).phrase_mut(phrase).add_complete_event(event);
+        },
+        Command::RemovePatternEvent { track, phrase, start, pattern } => {
+            check_track(sequencer, track)?;
+            check_loopable(phrase)?;
+            let range = removed_range(start)?;
+            if ! sequencer.track_mut(track).phrase_mut(phrase).contains_events_starting_in(range, pattern) {
+                return Err("no pattern starts there")
+            }
+
+            sequencer.remember(EditTarget::Phrase(track, phrase));
+            sequencer.track_mut(track).phrase_mut(phrase).remove_events_starting_in(range, pattern);
+        },
+        Command::ClearPhrase { track, phrase } => {
+            check_track(sequencer, track)?;
+            check_loopable(phrase)?;
+            if ! sequencer.track(track).phrase(phrase).events().is_empty() {
+                sequencer.remember(EditTarget::Phrase(track, phrase));
+                sequencer.track_mut(track).phrase_mut(phrase).clear_events();
+            }
+        },
+        Command::AddPhraseEvent { track, start, stop, phrase } => {
+            check_track(sequencer, track)?;
+            check_loopable(phrase)?;
+            check_range(start, stop)?;
+            sequencer.remember(EditTarget::Timeline(track));
+            sequencer.track_mut(track).timeline.add_complete_event(LoopablePhraseEvent::new(start, stop, phrase));
+        },
+        Command::RemovePhraseEvent { track, start, phrase } => {
+            check_track(sequencer, track)?;
+            let range = removed_range(start)?;
+            if ! sequencer.track_mut(track).timeline.contains_events_starting_in(range, phrase) {
+                return Err("no phrase starts there")
+            }
+
+            sequencer.remember(EditTarget::Timeline(track));
+            sequencer.track_mut(track).timeline.remove_events_starting_in(range, phrase);
+        },
+        Command::ClearTimeline { track } => {
+            check_track(sequencer, track)?;
+            if ! sequencer.track(track).timeline.events().is_empty() {
+                sequencer.remember(EditTarget::Timeline(track));
+                sequencer.track_mut(track).timeline.clear_events();
+            }
+        },
+        Command::Undo => if ! sequencer.undo() { return Err("nothing to undo") },
+        Command::Redo => if ! sequencer.redo() { return Err("nothing to redo") },
+        Command::ShowTrack(track) => {
+            check_track(sequencer, track)?;
+            surface.show_track(track);
+        },
+        Command::Save => surface.save_requested = true,
+        // Process handler takes care of snapshots, as it knows what's in a project
+        Command::Snapshot => (),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events() {
+        let mut bus = Bus::new();
+        let first = bus.connect();
+        let second = bus.connect();
+
+        first.send(Command::Start).unwrap();
+        second.send(Command::QueueSequence(2)).unwrap();
+        assert_eq!(bus.next_command(), Some((0, Command::Start)));
+        assert_eq!(bus.next_command(), Some((1, Command::QueueSequence(2))));
+        assert_eq!(bus.next_command(), None);
+
+        // Failures go to sender, status goes to everyone
+        bus.failed(1, Command::QueueSequence(2), "nope");
+        bus.publish(Status::default());
+        assert_eq!(first.events().collect::<Vec<Event>>(), vec![Event::Status(Status::default())]);
+        assert_eq!(second.events().count(), 2);
+
+        // Slow frontends lose oldest events
+        (0 .. Bus::EVENT_CAPACITY + 1).for_each(|tick| bus.publish(Status { tick: tick as u32, ..Status::default() }));
+        assert_eq!(first.events().next(), Some(Event::Status(Status { tick: 1, ..Status::default() })));
+    }
+
+    #[test]
+    fn ticks() {
+        // Ticks near the end of u32 would overflow loopable lengths & ranges
+        assert!(check_range(0, u32::MAX).is_err());
+        assert!(check_range(10, 10).is_err());
+        assert!(check_range(0, 1920).is_ok());
+        assert!(removed_range(u32::MAX).is_err());
+        assert_eq!(removed_range(1920).map(|range| (range.start, range.stop)), Ok((1920, 1921)));
+    }
+}
@@ -0,0 +1,279 @@
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use super::controller::{ControllerSetup, Role};
+use super::controller::profile::DeviceProfile;
+use super::project::Project;
+use super::mixer::VelocityCurve;
+use super::sequencer::Sequencer;
+use super::patchbay::Connect;
+use super::osc;
+
+// Read from working directory when no config is given on the command line
+const DEFAULT_FILE: &str = "octothorpe.toml";
+
+/*
+ * Command line arguments, these override what's in the config file
+ */
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "Midi sequencer for jack, driven by APC's")]
+pub struct Args {
+    /// Config file, defaults to octothorpe.toml when it exists
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Project to load on start & save to on exit
+    pub project: Option<PathBuf>,
+
+    /// Name of the jack client
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Amount of tracks to start with when there's no project
+    #[arg(short, long)]
+    pub tracks: Option<usize>,
+
+    /// Tempo we start with
+    #[arg(short, long)]
+    pub bpm: Option<f64>,
+
+    /// Start a jack server when none is running
+    #[arg(long)]
+    pub start_server: bool,
+
+    /// Address the OSC server listens on
+    #[arg(long)]
+    pub osc: Option<String>,
+
+    /// Don't start the OSC server
+    #[arg(long)]
+    pub no_osc: bool,
+
+    /// Be timebase master, only when there's none or follow the master
+    #[arg(long, value_enum)]
+    pub timebase: Option<TimebaseMode>,
+
+    /// How faders scale velocities of tracks that use velocity scaling, overrides the project
+    #[arg(long, value_enum)]
+    pub velocity_curve: Option<VelocityCurve>,
+}
+
+// Who decides tempo & position of jack transport
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TimebaseMode {
+    // Take over from other masters
+    Master,
+    // Become master when there's none
+    Conditional,
+    // Follow tempo & position of master
+    Slave,
+}
+
+// Names of the ports we register, tracks are numbered starting at 1
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortNames {
+    pub track: String,
+    pub mixer: String,
+}
+
+impl Default for PortNames {
+    fn default() -> Self {
+        PortNames { track: "Track".to_string(), mixer: "Mixer".to_string() }
+    }
+}
+
+// Controller as it's written in the config file, profile is looked up by name
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControllerConfig {
+    pub name: String,
+    pub profile: String,
+    pub role: Role,
+    #[serde(default)]
+    pub track_offset: u8,
+}
+
+/*
+ * Things that used to be hardcoded, all of them can be left out of the config file
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub client_name: String,
+    pub start_server: bool,
+    pub tracks: usize,
+    pub beats_per_minute: f64,
+    pub timebase: TimebaseMode,
+    // Curve saved in project is used when there's none
+    pub velocity_curve: Option<VelocityCurve>,
+    pub project: PathBuf,
+    // Empty address disables the OSC server
+    pub osc_address: String,
+    pub ports: PortNames,
+    // Default APC20 & APC40 are used when there are none
+    #[serde(rename = "controller")]
+    pub controllers: Vec<ControllerConfig>,
+    // Connect our ports to other jack ports, like "APC40 in" to "system:midi_capture_1"
+    pub connect: Vec<Connect>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            client_name: "Octothorpe".to_string(),
+            start_server: false,
+            tracks: 16,
+            beats_per_minute: 137.0,
+            timebase: TimebaseMode::Master,
+            velocity_curve: None,
+            project: PathBuf::from(Project::DEFAULT_FILE),
+            osc_address: osc::DEFAULT_ADDRESS.to_string(),
+            ports: PortNames::default(),
+            controllers: vec![],
+            connect: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(source: &str, contents: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(contents)
+            .map_err(|error| format!("Could not parse config {}: {}", source, error))?;
+
+        if config.tracks == 0 || config.tracks > Sequencer::TRACK_CAPACITY {
+            return Err(format!("Invalid config {}: we need 1 - {} tracks", source, Sequencer::TRACK_CAPACITY));
+        }
+        if ! (1.0 ..= 999.0).contains(&config.beats_per_minute) {
+            return Err(format!("Invalid config {}: beats_per_minute has to be between 1 & 999", source));
+        }
+
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| format!("Could not read config {}: {}", path.display(), error))?;
+
+        Self::parse(&path.display().to_string(), &contents)
+    }
+
+    // Config file given on command line or default one when it's there, overridden by arguments
+    pub fn from_args(args: Args) -> Result<Self, String> {
+        let config = match &args.config {
+            Some(path) => Self::load(path)?,
+            None if Path::new(DEFAULT_FILE).exists() => Self::load(Path::new(DEFAULT_FILE))?,
+            None => Self::default(),
+        };
+
+        config.with_args(args)
+    }
+
+    fn with_args(mut self, args: Args) -> Result<Self, String> {
+        if let Some(name) = args.name { self.client_name = name; }
+        if let Some(project) = args.project { self.project = project; }
+        if let Some(address) = args.osc { self.osc_address = address; }
+        if args.no_osc { self.osc_address = String::new(); }
+        if args.start_server { self.start_server = true; }
+        if let Some(timebase) = args.timebase { self.timebase = timebase; }
+        if let Some(curve) = args.velocity_curve { self.velocity_curve = Some(curve); }
+
+        if let Some(tracks) = args.tracks {
+            if tracks == 0 || tracks > Sequencer::TRACK_CAPACITY {
+                return Err(format!("We need 1 - {} tracks", Sequencer::TRACK_CAPACITY));
+            }
+            self.tracks = tracks;
+        }
+        if let Some(bpm) = args.bpm {
+            if ! (1.0 ..= 999.0).contains(&bpm) {
+                return Err("Tempo has to be between 1 & 999 bpm".to_string());
+            }
+            self.beats_per_minute = bpm;
+        }
+
+        Ok(self)
+    }
+
+    pub fn client_options(&self) -> jack::ClientOptions {
+        if self.start_server { jack::ClientOptions::empty() } else { jack::ClientOptions::NO_START_SERVER }
+    }
+
+    // Look up profiles of configured controllers
+    pub fn controller_setups(&self) -> Result<Vec<ControllerSetup>, String> {
+        if self.controllers.is_empty() {
+            return ControllerSetup::defaults();
+        }
+
+        self.controllers.iter()
+            .map(|controller| {
+                Ok(ControllerSetup {
+                    name: controller.name.clone(),
+                    profile: DeviceProfile::find(&controller.profile)?,
+                    role: controller.role,
+                    track_offset: controller.track_offset,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let config = Config::parse("test", r#"
+            client_name = "Octo"
+            tracks = 8
+            timebase = "conditional"
+            velocity_curve = "linear"
+
+            [ports]
+            track = "Out"
+
+            [[controller]]
+            name = "Mini"
+            profile = "apc_mini"
+            role = "pattern"
+
+            [[connect]]
+            port = "Mini in"
+            to = ["system:midi_capture_1"]
+        "#).unwrap();
+
+        assert_eq!(config.client_name, "Octo");
+        assert_eq!(config.tracks, 8);
+        assert_eq!(config.beats_per_minute, 137.0);
+        assert_eq!(config.timebase, TimebaseMode::Conditional);
+        assert_eq!(config.velocity_curve, Some(VelocityCurve::Linear));
+        assert_eq!(config.ports, PortNames { track: "Out".to_string(), mixer: "Mixer".to_string() });
+        assert_eq!(config.controllers[0].role, Role::Pattern);
+        assert_eq!(config.connect[0].to, vec!["system:midi_capture_1".to_string()]);
+
+        let setups = config.controller_setups().unwrap();
+        assert_eq!((setups.len(), setups[0].track_offset), (1, 0));
+        assert_eq!(ControllerSetup::track_bank_size(&setups), 8);
+        assert_eq!(ControllerSetup::track_bank_size(&ControllerSetup::defaults().unwrap()), 16);
+
+        assert!(Config::parse("test", "tracks = 0").is_err());
+        assert!(Config::parse("test", "tracks = 65").is_err());
+        assert!(Config::parse("test", "trakcs = 4").is_err());
+        assert!(Config::parse("test", "beats_per_minute = 1e9").is_err());
+        assert!(Config::parse("test", "beats_per_minute = nan").is_err());
+        assert!(Config::default().with_args(Args::parse_from(["octothorpe", "--bpm", "NaN"])).is_err());
+
+        // Arguments win from file
+        let args = Args::parse_from(["octothorpe", "--bpm", "120", "--no-osc", "--timebase", "slave", "--velocity-curve", "logarithmic", "song.oct"]);
+        let config = config.with_args(args).unwrap();
+        assert_eq!(config.timebase, TimebaseMode::Slave);
+        assert_eq!(config.velocity_curve, Some(VelocityCurve::Logarithmic));
+        assert_eq!(config.beats_per_minute, 120.0);
+        assert_eq!(config.osc_address, "");
+        assert_eq!(config.project, PathBuf::from("song.oct"));
+        assert_eq!(config.client_name, "Octo");
+    }
+}
@@ -1,8 +1,36 @@
 
+
+use super::profile::{DeviceProfile, Takeover};
+
 pub struct CueKnob {
     delta: i8,
 }
 
+// What a knob is controlling
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum KnobTarget {
+    Pan(usize),
+    Send(usize),
+    // Knob of track
+    Parameter(usize, u8),
+}
+
+#[derive(Clone, Copy)]
+struct KnobState {
+    // Last value physical knob sent
+    position: Option<u8>,
+    // Target & value knob is holding, knob lets go when target changes or value changed elsewhere
+    holding: Option<(KnobTarget, u8)>,
+}
+
+/*
+ * Keeps knobs from jumping values when the value they control differs from their position
+ */
+pub struct KnobPickup {
+    takeover: Takeover,
+    knobs: Vec<KnobState>,
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ButtonType {
     Grid(u8, u8),
@@ -12,8 +40,11 @@ pub enum ButtonType {
     Activator(u8),
     Solo(u8),
     Arm(u8),
+    KnobPage(u8),
     Shift,
     Quantization,
+    TapTempo,
+    Record,
     Play,
     Stop,
     Up,
@@ -52,65 +83,48 @@ pub struct InputEvent {
     pub event_type: InputEventType,
 }
 
-impl ButtonType {
-    fn new(channel: u8, note: u8) -> Self {
-        match note {
-            0x5B => ButtonType::Play,
-            0x5C => ButtonType::Stop,
-            0x33 => ButtonType::Track(channel),
-            0x3F => ButtonType::Quantization,
-            // These used to be sequence buttons, but will now be more control groups for plugin parameters
-            //0x57 ..= 0x5A => ButtonType::Sequence(note - 0x57),
-            // Side grid is turned upside down as we draw the phrases upside down as we draw notes
-            // updside down due to lower midi nodes having lower numbers, therefore the 4 -
-            0x52 ..= 0x56 => ButtonType::Side(4 - (note - 0x52)),
-            0x51 => ButtonType::Shift,
-            0x50 => ButtonType::Master,
-            // Grid should add notes & add phrases
-            0x35 ..= 0x39 => ButtonType::Grid(channel, 4 - (note - 0x35)),
-            0x5E => ButtonType::Up,
-            0x5F => ButtonType::Down,
-            0x60 => ButtonType::Right,
-            0x61 => ButtonType::Left,
-            0x62 => ButtonType::Shift,
-            0x30 => ButtonType::Arm(channel),
-            0x31 => ButtonType::Solo(channel),
-            0x32 => ButtonType::Activator(channel),
-            _ => ButtonType::Unknown,
-        }
-    }
-}
-
 /*
- * Get input event type from sent bytes
+ * Get input event type from sent bytes, profile tells us what the bytes mean
  */
 impl InputEventType {
-    pub fn new(bytes: &[u8]) -> Self {
-         match bytes[0] {
+    pub fn new(bytes: &[u8], profile: &DeviceProfile) -> Self {
+        // Notes & control changes we look at are 3 bytes, ignore whatever got cut off
+        if bytes.is_empty() || (bytes[0] != 0xF0 && bytes.len() < 3) {
+            return Self::Unknown
+        }
+
+        let channel = bytes[0] & 0x0F;
+
+        match bytes[0] {
             0xF0 => {
-                // 0x06 = inquiry e, 0x02 = inquiry response 0x47 = akai manufacturer, 0x73 = APC40, 0x7b = APC20
-                if bytes[3] == 0x06 && bytes[4] == 0x02 && bytes[5] == 0x47 && (bytes[6] == 0x73 || bytes[6] == 0x7b) {
+                // 0x06 = inquiry e, 0x02 = inquiry response 0x47 = akai manufacturer
+                if bytes.len() > 13 && bytes[3] == 0x06 && bytes[4] == 0x02 && bytes[5] == 0x47 && Some(bytes[6]) == profile.device_id {
                     Self::InquiryResponse(bytes[13], bytes[6])
                 } else {
                     Self::Unknown
                 }
             },
-            0x90 ..= 0x9F => Self::ButtonPressed(ButtonType::new(bytes[0] - 0x90, bytes[1])),
-            0x80 ..= 0x8F => Self::ButtonReleased(ButtonType::new(bytes[0] - 0x80, bytes[1])),
-            0xB0 ..= 0xB8 => {
-                match bytes[1] {
-                    0x30 ..= 0x37 | 0x10 ..= 0x17 => {
-                        // APC effect knobs are ordered weird, reorder them from to 0..16
-                        let modifier = if (0x30 ..= 0x37).contains(&bytes[1]) { 48 } else { 8 };
-                        let index = bytes[1] - modifier;
-
-                        Self::KnobTurned { value: bytes[2], knob_type: KnobType::Effect(index) }
-                    },
-                    0x7 => Self::FaderMoved { value: bytes[2], fader_type: FaderType::Track(bytes[0] - 0xB0) },
-                    0xE => Self::FaderMoved { value: bytes[2], fader_type: FaderType::Master },
-                    0xF => Self::FaderMoved { value: bytes[2], fader_type: FaderType::CrossFade },
-                    0x2F => Self::KnobTurned { value: bytes[2], knob_type: KnobType::Cue },
-                    _ => Self::Unknown,
+            // Most devices send note on with velocity 0 when releasing a button
+            0x90 ..= 0x9F if bytes[2] == 0 => Self::ButtonReleased(profile.note_button(channel, bytes[1])),
+            0x90 ..= 0x9F => Self::ButtonPressed(profile.note_button(channel, bytes[1])),
+            0x80 ..= 0x8F => Self::ButtonReleased(profile.note_button(channel, bytes[1])),
+            0xB0 ..= 0xBF => {
+                let (control, value) = (bytes[1], bytes[2]);
+
+                if let Some(button_type) = profile.control_button(channel, control) {
+                    if value > 0 { Self::ButtonPressed(button_type) } else { Self::ButtonReleased(button_type) }
+                } else if let Some(index) = profile.knobs.iter().position(|knob| *knob == control) {
+                    Self::KnobTurned { value, knob_type: KnobType::Effect(index as u8) }
+                } else if let Some(index) = profile.track_faders.and_then(|layout| layout.index(channel, control)) {
+                    Self::FaderMoved { value, fader_type: FaderType::Track(index) }
+                } else if Some(control) == profile.master_fader {
+                    Self::FaderMoved { value, fader_type: FaderType::Master }
+                } else if Some(control) == profile.crossfader {
+                    Self::FaderMoved { value, fader_type: FaderType::CrossFade }
+                } else if Some(control) == profile.cue_knob {
+                    Self::KnobTurned { value, knob_type: KnobType::Cue }
+                } else {
+                    Self::Unknown
                 }
             },
             _ => Self::Unknown,
@@ -119,8 +133,8 @@ impl InputEventType {
 }
 
 impl InputEvent {
-    pub fn new(time: u32, bytes: &[u8]) -> Self {
-        Self { time, event_type: InputEventType::new(bytes) }
+    pub fn new(time: u32, bytes: &[u8], profile: &DeviceProfile) -> Self {
+        Self { time, event_type: InputEventType::new(bytes, profile) }
     }
 
     pub fn is_cue_knob(event_type: &InputEventType) -> bool { 
@@ -135,6 +149,10 @@ impl InputEvent {
         matches!(event_type, InputEventType::ButtonPressed(ButtonType::Activator(_)))
     }
 
+    pub fn is_indicator_button(event_type: &InputEventType) -> bool {
+        matches!(event_type, InputEventType::ButtonPressed(ButtonType::Indicator(_)))
+    }
+
     pub fn is_track_button(event_type: &InputEventType) -> bool {
         matches!(event_type, InputEventType::ButtonPressed(ButtonType::Track(_)))
     }
@@ -147,6 +165,14 @@ impl InputEvent {
         matches!(event_type, InputEventType::ButtonPressed(ButtonType::Grid(_, _)))
     }
 
+    pub fn is_up_button(event_type: &InputEventType) -> bool { 
+        matches!(event_type, InputEventType::ButtonPressed(ButtonType::Up))
+    }
+
+    pub fn is_down_button(event_type: &InputEventType) -> bool { 
+        matches!(event_type, InputEventType::ButtonPressed(ButtonType::Down))
+    }
+
     pub fn is_right_button(event_type: &InputEventType) -> bool { 
         matches!(event_type, InputEventType::ButtonPressed(ButtonType::Right))
     }
@@ -189,3 +215,66 @@ impl CueKnob {
     }
 }
 
+impl KnobPickup {
+    pub fn new(knob_count: usize, takeover: Takeover) -> Self {
+        KnobPickup { takeover, knobs: vec![KnobState { position: None, holding: None }; knob_count] }
+    }
+
+    // Get value target should get when knob is turned, if any
+    pub fn turn(&mut self, index: u8, target: KnobTarget, value: u8, stored: u8) -> Option<u8> {
+        let knob = self.knobs.get_mut(index as usize)?;
+        let is_holding = knob.holding == Some((target, stored));
+        // Knob passed stored value since it's last position
+        let has_passed = match knob.position {
+            Some(position) => position.min(value) <= stored && stored <= position.max(value),
+            None => value == stored,
+        };
+
+        knob.position = Some(value);
+
+        if self.takeover == Takeover::Jump || is_holding || has_passed {
+            knob.holding = Some((target, value));
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pickup() {
+        let mut pickup = KnobPickup::new(1, Takeover::Pickup);
+        let target = KnobTarget::Parameter(0, 0);
+
+        // Knob does nothing till it passes stored value
+        assert_eq!(pickup.turn(0, target, 10, 64), None);
+        assert_eq!(pickup.turn(0, target, 40, 64), None);
+        assert_eq!(pickup.turn(0, target, 70, 64), Some(70));
+        assert_eq!(pickup.turn(0, target, 20, 70), Some(20));
+
+        // Other target has to be picked up again
+        assert_eq!(pickup.turn(0, KnobTarget::Parameter(1, 0), 21, 100), None);
+        assert_eq!(pickup.turn(0, KnobTarget::Parameter(1, 0), 100, 100), Some(100));
+
+        // Value changed elsewhere
+        assert_eq!(pickup.turn(0, KnobTarget::Parameter(1, 0), 101, 0), None);
+
+        let mut jump = KnobPickup::new(1, Takeover::Jump);
+        assert_eq!(jump.turn(0, target, 10, 64), Some(10));
+    }
+
+    #[test]
+    fn short_messages() {
+        let profile = DeviceProfile::find("apc40").unwrap();
+
+        assert_eq!(InputEventType::new(&[], &profile), InputEventType::Unknown);
+        assert_eq!(InputEventType::new(&[0x90, 0x35], &profile), InputEventType::Unknown);
+        assert_eq!(InputEventType::new(&[0xB0, 0x07], &profile), InputEventType::Unknown);
+        assert_eq!(InputEventType::new(&[0xF0, 0x7E], &profile), InputEventType::Unknown);
+        assert_eq!(InputEventType::new(&[0x92, 0x35, 0x7F], &profile), InputEventType::ButtonPressed(ButtonType::Grid(2, 4)));
+    }
+}
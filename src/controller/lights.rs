@@ -1,66 +1,119 @@
 
 use super::super::message::*;
+use super::profile::{GridLayout, LineLayout};
 
-// TODO - We could probably macro these grids, but.. alas, i'm not familiar enough with macros
+// State of leds that have to be redrawn, 255 is out of midi range so no palette color matches it
+const REDRAW: u8 = 255;
 
 pub trait Drawable {
-    fn output_messages(&mut self, frame: u32) -> Vec<TimedMessage> {
-        self.output().into_iter()
-            .map(|(channel, note, velocity)| TimedMessage::new(frame, Message::Note([channel, note, velocity])))
-            .collect()
-    }
+    // Messages are added to given messages, so buffers can be reused every cycle
+    fn output_messages(&mut self, frame: u32, messages: &mut Vec<TimedMessage>);
 
-    fn output(&mut self) -> Vec<(u8, u8, u8)>;
+    // Forget what leds show, so every led is drawn again next output
+    fn reset(&mut self);
 }
 
-// 40 boi at the top
+// Main grid, 8x5 on the APC's
 pub struct Grid {
-    state: [u8; 40],
-    next_state: [u8; 40],
+    layout: GridLayout,
+    state: Vec<u8>,
+    next_state: Vec<u8>,
 }
 
-// 5 high grid
+// Column of buttons right of the grid
 pub struct Side {
-    state: [u8; 5],
-    next_state: [u8; 5],
+    layout: LineLayout,
+    on: u8,
+    state: Vec<u8>,
+    next_state: Vec<u8>,
 }
 
-// 8 wide grid
+// Row of buttons below the grid
 pub struct WideRow {
-    state: [u8; 8],
-    next_state: [u8; 8],
-
-    note: u8,
+    layout: LineLayout,
+    on: u8,
+    state: Vec<u8>,
+    next_state: Vec<u8>,
 }
 
-// 4 wide grid
-/*
-pub struct NarrowRow {
-    state: [u8; 4],
-    next_state: [u8; 4],
+// Led rings around knobs, ring values are sent on the control change of the knob
+pub struct Rings {
+    knobs: Vec<u8>,
+    styles: Vec<u8>,
+    // (value, style) per ring
+    state: Vec<(u8, u8)>,
+    next_state: Vec<(u8, u8)>,
+}
 
-    note: u8,
+// Ring styles of the APC40
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RingStyle {
+    Off = 0,
+    Single = 1,
+    Volume = 2,
+    Pan = 3,
 }
-*/
 
 pub struct Single {
     state: u8,
     next_state: u8,
     
-    note: u8,
+    note: Option<u8>,
+    on: u8,
+}
+
+// Note on when led is lit, note off otherwise
+fn note_message(frame: u32, channel: u8, note: u8, value: u8) -> TimedMessage {
+    let status = if value > 0 { 0x90 } else { 0x80 };
+    control_message(frame, status + channel, note, value)
+}
+
+fn control_message(frame: u32, status: u8, control: u8, value: u8) -> TimedMessage {
+    TimedMessage::new(frame, Message::Note([status, control, value]))
+}
+
+// Output changed leds of a line of buttons, lines are drawn with 1 for on, which is translated to
+// the velocity device uses for on
+fn output_line(layout: &LineLayout, on: u8, state: &mut Vec<u8>, next_state: &mut Vec<u8>, frame: u32, output: &mut Vec<TimedMessage>) {
+    if next_state != state {
+        for index in 0 .. layout.length {
+            let value = next_state[index as usize];
+
+            if value != state[index as usize] {
+                let value = if value > 0 { on } else { 0 };
+                let (channel, note) = layout.address(index);
+
+                if layout.is_control_change() {
+                    output.push(control_message(frame, 0xB0 + channel, note, value));
+                } else {
+                    output.push(note_message(frame, channel, note, value));
+                }
+            }
+        }
+    }
+
+    state.copy_from_slice(next_state);
+    next_state.iter_mut().for_each(|value| *value = 0);
 }
 
 impl Grid {
-    pub fn new() -> Self {
-        // 9 does not exist, this way we force redraw of *all* leds first run
-        Grid { state: [9; 40], next_state: [0; 40] }
+    pub fn new(layout: GridLayout) -> Self {
+        let size = layout.width as usize * layout.height as usize;
+
+        // Force redraw of *all* leds first run
+        Grid { layout, state: vec![REDRAW; size], next_state: vec![0; size] }
     }
 
-    pub fn width(&self) -> u8 { 8 }
-    pub fn height(&self) -> u8 { 5 }
+    pub fn width(&self) -> u8 { self.layout.width }
+    pub fn height(&self) -> u8 { self.layout.height }
+
+    fn index(&self, x: u8, y: u8) -> usize {
+        y as usize * self.width() as usize + x as usize
+    }
 
-    fn index(x: u8, y: u8) -> usize {
-        y as usize * 8 + x as usize
+    // Forget what was drawn, so something else can take over the grid
+    pub fn clear(&mut self) {
+        self.next_state.iter_mut().for_each(|value| *value = 0);
     }
 
     pub fn try_draw(&mut self, x: i32, y: u8, value: u8) {
@@ -71,81 +124,68 @@ impl Grid {
 
     pub fn draw(&mut self, x: u8, y: u8, value: u8) {
         if x < self.width() && y < self.height() {
-            // 4 - as grid & side are flipped upside down to make MIDI notes go up..
-            self.next_state[Self::index(x, 4 - y)] = value;
+            let index = self.index(x, y);
+            self.next_state[index] = value;
         }
     }
 }
 
 impl Drawable for Grid {
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
-        let mut output = vec![];
-
-        // Meeeeeh, rust array comparison works up to 32 elements...
-        // https://doc.rust-lang.org/std/primitive.array.html#impl-PartialEq%3C%5BB%3B%20N%5D%3E
-        if self.next_state[0..20] != self.state[0..20] || self.next_state[20..] != self.state[20..]  {
+    fn output_messages(&mut self, frame: u32, output: &mut Vec<TimedMessage>) {
+        if self.next_state != self.state {
             for x in 0 .. self.width() {
                 for y in 0 .. self.height() {
-                    let index = Self::index(x, y);
+                    let index = self.index(x, y);
 
                     if self.next_state[index] != self.state[index] {
-                        let channel = x as u8 + if self.next_state[index] > 0 { 0x90 } else { 0x80 };
-                        let note = 0x35 + y as u8;
-
-                        output.push((channel, note, self.next_state[index]));
+                        // Layout takes care of flipping the grid on devices that count rows from the top
+                        let (channel, note) = self.layout.address(x, y);
+                        output.push(note_message(frame, channel, note, self.next_state[index]));
                     }
                 }
             }
         }
 
-        self.state = self.next_state;
-        self.next_state = [0; 40];
-        output
+        self.state.copy_from_slice(&self.next_state);
+        self.next_state.iter_mut().for_each(|value| *value = 0);
+    }
+
+    fn reset(&mut self) {
+        // Force redraw of *all* leds
+        self.state.iter_mut().for_each(|value| *value = REDRAW);
     }
 }
 
 impl Side {
-    pub fn new() -> Self {
-        Side { state: [9; 5], next_state: [0; 5] }
+    pub fn new(layout: LineLayout, on: u8) -> Self {
+        Side { layout, on, state: vec![REDRAW; layout.length as usize], next_state: vec![0; layout.length as usize] }
     }
 
-    pub fn height(&self) -> u8 { 5 }
+    pub fn height(&self) -> u8 { self.layout.length }
 
     pub fn draw(&mut self, index: u8, value: u8) {
         if index < self.height() {
-            // 4 - as grid & side are flipped upside down to make MIDI notes go up..
-            self.next_state[4 - index as usize] = value;
+            self.next_state[index as usize] = value;
         }
     }
 }
 
 impl Drawable for Side {
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
-        let mut output = vec![];
-
-        if self.next_state != self.state {
-            for index in 0 .. self.height() as usize {
-                if self.next_state[index] != self.state[index] {
-                    let channel = if self.next_state[index] == 1 { 0x90 } else { 0x80 };
-                    let note = 0x52 + index as u8;
-
-                    output.push((channel, note, self.next_state[index]));
-                }
-            }
-        }
+    fn output_messages(&mut self, frame: u32, output: &mut Vec<TimedMessage>) {
+        output_line(&self.layout, self.on, &mut self.state, &mut self.next_state, frame, output)
+    }
 
-        self.state = self.next_state;
-        self.next_state = [0; 5];
-        output
+    fn reset(&mut self) {
+        self.state.iter_mut().for_each(|value| *value = REDRAW);
     }
 }
 
 impl WideRow {
-    pub fn new(note: u8) -> Self {
-        WideRow { state: [9; 8], next_state: [0; 8], note, }
+    pub fn new(layout: LineLayout, on: u8) -> Self {
+        WideRow { layout, on, state: vec![REDRAW; layout.length as usize], next_state: vec![0; layout.length as usize] }
     }
 
-    pub fn width(&self) -> u8 { 8 }
+    pub fn width(&self) -> u8 { self.layout.length }
 
     pub fn draw(&mut self, index: u8, value: u8) {
         if index < self.width() {
@@ -155,44 +195,56 @@ impl WideRow {
 }
 
 impl Drawable for WideRow {
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
-        let mut output = vec![];
+    fn output_messages(&mut self, frame: u32, output: &mut Vec<TimedMessage>) {
+        output_line(&self.layout, self.on, &mut self.state, &mut self.next_state, frame, output)
+    }
 
-        if self.next_state != self.state {
-            for index in 0 .. self.width() as usize {
-                if self.next_state[index] != self.state[index] {
-                    let channel = if self.next_state[index] == 1 { 0x90 } else { 0x80 };
+    fn reset(&mut self) {
+        self.state.iter_mut().for_each(|value| *value = REDRAW);
+    }
+}
 
-                    output.push((channel + index as u8, self.note, self.next_state[index]));
-                }
-            }
-        }
+impl Rings {
+    // Devices without ring styles don't have rings, they get rings that never output
+    pub fn new(knobs: Vec<u8>, styles: Vec<u8>) -> Self {
+        let count = styles.len().min(knobs.len());
 
-        self.state = self.next_state;
-        self.next_state = [0; 8];
-        output
+        // Force redraw of all rings first run
+        Rings { knobs, styles, state: vec![(REDRAW, REDRAW); count], next_state: vec![(0, RingStyle::Off as u8); count] }
     }
-}
 
-/*
-impl NarrowRow {
-    pub fn new(note: u8) -> Self {
-        NarrowRow { state: [9; 4], next_state: [0; 4], note, }
+    pub fn draw(&mut self, index: u8, value: u8, style: RingStyle) {
+        if let Some(ring) = self.next_state.get_mut(index as usize) {
+            *ring = (value, style as u8);
+        }
     }
+}
 
-    pub fn width(&self) -> u8 { 4 }
+impl Drawable for Rings {
+    fn output_messages(&mut self, frame: u32, output: &mut Vec<TimedMessage>) {
+        for (index, ((value, style), (next_value, next_style))) in self.state.iter().zip(self.next_state.iter()).enumerate() {
+            if style != next_style {
+                output.push(control_message(frame, 0xB0, self.styles[index], *next_style));
+            }
 
-    pub fn draw(&mut self, index: u8, value: u8) {
-        if index < self.width() {
-            self.next_state[index as usize] = value;
+            if value != next_value {
+                output.push(control_message(frame, 0xB0, self.knobs[index], *next_value));
+            }
         }
+
+        self.state.copy_from_slice(&self.next_state);
+        self.next_state.iter_mut().for_each(|ring| *ring = (0, RingStyle::Off as u8));
+    }
+
+    fn reset(&mut self) {
+        self.state.iter_mut().for_each(|ring| *ring = (REDRAW, REDRAW));
     }
 }
-*/
 
 impl Single {
-    pub fn new(note: u8) -> Self {
-        Single { state: 9, next_state: 0, note, }
+    // Devices without the button get a light that never outputs
+    pub fn new(note: Option<u8>, on: u8) -> Self {
+        Single { state: REDRAW, next_state: 0, note, on, }
     }
 
     pub fn draw(&mut self, value: u8) {
@@ -201,16 +253,19 @@ impl Single {
 }
 
 impl Drawable for Single {
-    fn output(&mut self) -> Vec<(u8, u8, u8)> {
-        let mut output = vec![];
-
-        if self.next_state != self.state {
-            let channel = if self.next_state == 1 { 0x90 } else { 0x80 };
-            output.push((channel, self.note, self.next_state));
+    fn output_messages(&mut self, frame: u32, output: &mut Vec<TimedMessage>) {
+        if let Some(note) = self.note {
+            if self.next_state != self.state {
+                let value = if self.next_state > 0 { self.on } else { 0 };
+                output.push(note_message(frame, 0, note, value));
+            }
         }
 
         self.state = self.next_state;
         self.next_state = 0;
-        output
+    }
+
+    fn reset(&mut self) {
+        self.state = REDRAW;
     }
 }
@@ -1,46 +1,67 @@
 
 pub mod input;
+pub mod profile;
 mod lights;
 
 use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use super::TickRange;
 use super::message::{TimedMessage, Message};
 use super::cycle::ProcessCycle;
 use super::loopable::*;
 use super::sequencer::*;
 use super::surface::*;
-use super::port::MidiOut;
+use super::port::{MidiOut, register_port};
+use super::error::StartupError;
 use super::mixer::*;
+use super::track::Track;
 use super::TimebaseHandler;
 use super::events::*;
+use super::history::EditTarget;
+use super::scroller::Scroller;
+use super::bus::Bus;
+use serde::Deserialize;
 use input::*;
 use lights::*;
+use profile::*;
 
-const SEQUENCE_COLOR: u8 = 1;
-const TIMELINE_HEAD_COLOR: u8 = 1;
-const TIMELINE_TAIL_COLOR: u8 = 3;
 // Same as default phrase length atm
 // Wait some cycles for sloooow apc's
 const IDENTIFY_CYCLES: u8 = 3;
 const LENGTH_INDICATOR_USECS: u64 = 200000;
+const BASE_NOTE_INDICATOR_USECS: u64 = 1000000;
 const DOUBLE_CLICK_USECS: u64 = 300000;
 const PLAYING_LOOPABLE_INDICATOR_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32;
 const PLAYING_SEQUENCE_INDICATOR_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32;
 const QUEUED_SEQUENCE_INDICATOR_TICKS: u32 = TimebaseHandler::TICKS_PER_BEAT as u32 / 2;
-
-pub trait APC {
+// Tracks have 5 patterns & phrases and there's 5 sequences, taller grids don't show more of them
+const LOOPABLE_COUNT: u8 = 5;
+
+/*
+ * Editors implement the workflow of a role on any device, what a device looks like is described by
+ * it's profile
+ */
+pub trait Editor {
     type Loopable: Loopable;
 
-    const TRACK_OFFSET: u8;
-    const HEAD_COLOR: u8;
-    const TAIL_COLOR: u8;
+    fn state(&self) -> &EditorState;
+    fn state_mut(&mut self) -> &mut EditorState;
+
+    fn head_color(&self) -> u8;
+    fn tail_color(&self) -> u8;
 
-    fn identified_cycles(&self) -> u8;
-    fn set_identified_cycles(&mut self, cycles: u8);
-    fn local_id(&self) -> u8;
-    fn set_local_id(&mut self, local_id: u8);
-    fn device_id(&self) -> u8;
-    fn set_device_id(&mut self, device_id: u8);
+    fn profile(&self) -> &DeviceProfile { &self.state().profile }
+    fn track_offset(&self) -> u8 { self.state().track_offset }
+
+    // Set by notification handler when ports of controller were connected
+    fn reconnected(&self) -> &Arc<AtomicBool> { &self.state().reconnected }
+    fn identified_cycles(&self) -> u8 { self.state().identified_cycles }
+    fn set_identified_cycles(&mut self, cycles: u8) { self.state_mut().identified_cycles = cycles }
+    fn local_id(&self) -> u8 { self.state().local_id }
+    fn set_local_id(&mut self, local_id: u8) { self.state_mut().local_id = local_id }
+    fn device_id(&self) -> u8 { self.state().device_id }
+    fn set_device_id(&mut self, device_id: u8) { self.state_mut().device_id = device_id }
 
     fn loopable_grid<'a>(&self, surface: &'a Surface) -> &'a LoopableGrid;
     fn loopable_grid_mut<'a>(&self, surface: &'a mut Surface) -> &'a mut LoopableGrid;
@@ -48,17 +69,48 @@ pub trait APC {
     fn shown_loopable_index(&self, surface: &Surface) -> u8;
     fn shown_loopable<'a>(&self, sequencer: &'a Sequencer, surface: &Surface) -> &'a Self::Loopable;
     fn shown_loopable_mut<'a>(&self, sequencer: &'a mut Sequencer, surface: &mut Surface) -> &'a mut Self::Loopable;
-    fn playing_loopable_indexes(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<u8>;
-    fn playing_loopable_ranges(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<(TickRange, u32)>;
-
-    fn cue_knob(&mut self) -> &mut CueKnob;
-    fn master(&mut self) -> &mut Single;
-    fn grid(&mut self) -> &mut Grid;
-    fn side(&mut self) -> &mut Side;
-    fn track(&mut self) -> &mut WideRow;
-    fn indicator(&mut self) -> &mut WideRow;
-    fn activator(&mut self) -> &mut WideRow;
-    fn solo(&mut self) -> &mut WideRow;
+    // Playing loopables are added to given buffers, so they can be reused every cycle
+    fn playing_loopable_indexes(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface, indexes: &mut Vec<u8>);
+    fn playing_loopable_ranges(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface, ranges: &mut Vec<(TickRange, u32)>);
+
+    fn cue_knob(&mut self) -> &mut CueKnob { &mut self.state_mut().cue_knob }
+    fn knob_pickup(&mut self) -> &mut KnobPickup { &mut self.state_mut().knob_pickup }
+    fn rings(&mut self) -> &mut Rings { &mut self.state_mut().rings }
+    fn master(&mut self) -> &mut Single { &mut self.state_mut().master }
+    fn grid(&mut self) -> &mut Grid { &mut self.state_mut().grid }
+    fn side(&mut self) -> &mut Side { &mut self.state_mut().side }
+    fn track(&mut self) -> &mut WideRow { &mut self.state_mut().track }
+    fn indicator(&mut self) -> &mut WideRow { &mut self.state_mut().indicator }
+    fn activator(&mut self) -> &mut WideRow { &mut self.state_mut().activator }
+    fn solo(&mut self) -> &mut WideRow { &mut self.state_mut().solo }
+    fn arm(&mut self) -> &mut WideRow { &mut self.state_mut().arm }
+    fn knob_pages(&mut self) -> &mut WideRow { &mut self.state_mut().knob_pages }
+
+    // Cycles identification starts at, devices without device id don't need introduction
+    fn unidentified_cycles(&self) -> u8 {
+        if self.profile().device_id.is_some() { 0 } else { IDENTIFY_CYCLES }
+    }
+
+    // Redraw every led on next output
+    fn reset_lights(&mut self) {
+        self.master().reset();
+        self.grid().reset();
+        self.side().reset();
+        self.track().reset();
+        self.indicator().reset();
+        self.activator().reset();
+        self.solo().reset();
+        self.arm().reset();
+        self.knob_pages().reset();
+        self.rings().reset();
+    }
+
+    // Identify controller again & redraw everything, as it could be a different device now
+    fn reset(&mut self) {
+        let cycles = self.unidentified_cycles();
+        self.set_identified_cycles(cycles);
+        self.reset_lights();
+    }
 
     /*
      * Remove existing events when there's starting events in tick range, otherwise, remove tick
@@ -87,21 +139,37 @@ pub trait APC {
     /*
      * Output side indicator, show what patterns/phrases are playing and selected
      */
-    fn output_side(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) -> Vec<TimedMessage> {
+    fn output_side(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, messages: &mut Vec<TimedMessage>) {
         // Default to output immediately
         let mut frame = 0;
 
+        // Side shows octave of pattern grid for a while after scrolling it
+        let usecs = cycle.time_stop.saturating_sub(BASE_NOTE_INDICATOR_USECS);
+        let is_scrolling = matches!(self.loopable_grid(surface).loopable_type, LoopableType::Pattern { .. })
+            && surface.event_memory
+                .last_occurred_controller_event_after(self.track_offset(), &[InputEvent::is_up_button, InputEvent::is_down_button], usecs)
+                .is_some();
+
         match surface.view {
+            View::Track if is_scrolling => {
+                // Center button is the octave we start out with
+                let octaves = (surface.pattern_grid.offset_y() as i32 - Surface::DEFAULT_BASE_NOTE as i32).div_euclid(12);
+                let center = self.side().height() as i32 / 2;
+                let index = (center + octaves).max(0).min(self.side().height() as i32 - 1);
+                self.side().draw(index as u8, 1);
+            },
             View::Track => {
-                let playing_indexes = self.playing_loopable_indexes(cycle, sequencer, surface);
+                let mut playing_indexes = std::mem::take(&mut self.state_mut().playing_indexes);
+                self.playing_loopable_indexes(cycle, sequencer, surface, &mut playing_indexes);
                 let showed_index = self.shown_loopable_index(surface);
 
                 let state = 1 - (cycle.tick_range.start / PLAYING_LOOPABLE_INDICATOR_TICKS) % 2;
 
                 // Draw blinking playing loopables
-                for index in playing_indexes.into_iter() {
+                for index in playing_indexes.drain(..) {
                     self.side().draw(index, state as u8);
                 }
+                self.state_mut().playing_indexes = playing_indexes;
 
                 // Always show selected loopable
                 self.side().draw(showed_index, 1);
@@ -133,22 +201,23 @@ pub trait APC {
             _ => (),
         }
 
-        self.side().output_messages(frame)
+        self.side().output_messages(frame, messages);
     }
 
     // TODO - only draw length indicator at position 0 only when we are precisely at 0
-    fn output_indicator(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) -> Vec<TimedMessage> {
+    fn output_indicator(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, messages: &mut Vec<TimedMessage>) {
         // Default to output immediately
         let mut frame = 0;
 
         match surface.view {
             View::Track => {
                 let loopable_grid = self.loopable_grid(surface);
-                let usecs = cycle.time_stop - LENGTH_INDICATOR_USECS;
+                let usecs = cycle.time_stop.saturating_sub(LENGTH_INDICATOR_USECS);
                 let ticks_per_button = loopable_grid.ticks_per_button();
                 let offset_buttons = loopable_grid.offset_x() / ticks_per_button;
                 let controller_filters = [
                     InputEvent::is_cue_knob,
+                    InputEvent::is_indicator_button,
                     InputEvent::is_solo_button,
                     InputEvent::is_activator_button,
                     InputEvent::is_right_button,
@@ -157,31 +226,34 @@ pub trait APC {
                 let global_filters = [InputEvent::is_crossfader];
                 // Show length/offset indicator when events occurred that changed length/offset
                 let last_occurred_controller_event = surface.event_memory
-                    .last_occurred_controller_event_after(Self::TRACK_OFFSET, &controller_filters, usecs)
+                    .last_occurred_controller_event_after(self.track_offset(), &controller_filters, usecs)
                     .or_else(|| surface.event_memory.last_occurred_global_event_after(&global_filters, usecs));
 
                 // TODO - move this timing logic to seperate function when we need it for other things
                 // Do we need to draw length indicator, and when?
                 if let Some(usecs) = last_occurred_controller_event {
-                    let usecs_ago = cycle.time_stop - usecs;
-                    let hide_in_usecs = LENGTH_INDICATOR_USECS - usecs_ago;
+                    let usecs_ago = cycle.time_stop.saturating_sub(usecs);
+                    let hide_in_usecs = LENGTH_INDICATOR_USECS.saturating_sub(usecs_ago);
 
                     if hide_in_usecs < cycle.usecs() {
                         frame = hide_in_usecs as u32 * cycle.scope.n_frames() / cycle.usecs() as u32;
                     } else {
                         let loopable = self.shown_loopable(sequencer, surface);
-                        let length_buttons = (self.indicator().width() as u32 * loopable_grid.ticks_in_grid(8) / loopable.length()) as u8;
-                        let start_button = offset_buttons as u8 * length_buttons / self.indicator().width();
-                        let stop_button = start_button + length_buttons;
+                        // Loopables shorter than the grid fill the whole indicator
+                        let width = self.indicator().width() as u32;
+                        let length_buttons = (width * loopable_grid.ticks_in_grid(8) / loopable.length().max(1)).min(width);
+                        let start_button = (offset_buttons * length_buttons / width.max(1)).min(width);
+                        let stop_button = (start_button + length_buttons).min(width);
                         for index in start_button .. stop_button {
                             self.indicator().draw(index as u8, 1);
                         }
                     }
                 } else {
                     // As we don't have to show any time based indicators, show transport position indicator
-                    let ranges = self.playing_loopable_ranges(cycle, sequencer, surface);
+                    let mut ranges = std::mem::take(&mut self.state_mut().playing_ranges);
+                    self.playing_loopable_ranges(cycle, sequencer, surface, &mut ranges);
 
-                    for (range, start) in ranges {
+                    for (range, start) in ranges.drain(..) {
                         let ticks_into_playable = range.stop - start;
                         let button = ticks_into_playable / ticks_per_button;
 
@@ -194,11 +266,13 @@ pub trait APC {
                             frame = (((range.stop % ticks_per_button) as f64 / range.length() as f64) * cycle.scope.n_frames() as f64) as u32;
                         }
                     }
+
+                    self.state_mut().playing_ranges = ranges;
                 }
             },
             View::Timeline => {
                 let button = cycle.tick_range.start / surface.timeline_grid.ticks_per_button();
-                let offset_buttons = surface.timeline_grid.offset_x() / surface.timeline_grid.ticks_per_button() + Self::TRACK_OFFSET as u32;
+                let offset_buttons = surface.timeline_grid.offset_x() / surface.timeline_grid.ticks_per_button() + self.track_offset() as u32;
 
                 if button >= offset_buttons {
                     self.indicator().draw((button - offset_buttons) as u8, 1);
@@ -212,14 +286,15 @@ pub trait APC {
             _ => (),
         }
 
-        self.indicator().output_messages(frame)
+        self.indicator().output_messages(frame, messages);
     }
 
     /*
-     * Draw note or pattern events into main grid of controller
+     * Draw note or pattern events into main grid of controller, head color is picked per event so
+     * rgb devices can show velocity or track colors
      */
-    fn draw_loopable_events<'a>(&mut self, events: impl Iterator<Item = &'a (impl LoopableEvent + 'a)>, 
-        offset_x: u32, offset_y: u8, ticks_in_grid: u32, head_color: u8, tail_color: u8) 
+    fn draw_loopable_events<'a, E: LoopableEvent + 'a>(&mut self, events: impl Iterator<Item = &'a E>, 
+        offset_x: u32, offset_y: u8, ticks_in_grid: u32, head_color: impl Fn(&DeviceProfile, &E) -> u8, tail_color: u8) 
     {
         let grid_stop = offset_x + ticks_in_grid;
         let ticks_per_button = (ticks_in_grid / 8) as i32;
@@ -245,10 +320,14 @@ pub trait APC {
                 };
 
                 // Flip grid around to show higher notes higher on the grid (for patterns this does not matter)
-                let row = event.row(offset_y);
+                let row = match event.row(offset_y) {
+                    Some(row) => row,
+                    None => return,
+                };
 
                 // Always draw first button head
-                self.grid().try_draw(start_button, row, head_color);
+                let color = head_color(self.profile(), event);
+                self.grid().try_draw(start_button, row, color);
                 // Draw tail depending on wether this is looping note
                 if stop_button >= start_button {
                     self.draw_tail((start_button + 1) .. stop_button, row, tail_color);
@@ -264,17 +343,133 @@ pub trait APC {
 
         // Draw main grid
         let events = track.timeline.events().iter();
-        let offset = surface.timeline_grid.ticks_per_button() * Self::TRACK_OFFSET as u32 + surface.timeline_grid.offset_x();
-        self.draw_loopable_events(events, offset, 0, surface.timeline_grid.ticks_per_button() * 8, TIMELINE_HEAD_COLOR, TIMELINE_TAIL_COLOR);
+        let offset = surface.timeline_grid.ticks_per_button() * self.track_offset() as u32 + surface.timeline_grid.offset_x();
+        let palette = self.profile().palette;
+        let head_color = |profile: &DeviceProfile, _: &LoopablePhraseEvent| profile.track_color(surface.track_shown(), palette.timeline_head);
+        self.draw_loopable_events(events, offset, 0, surface.timeline_grid.ticks_per_button() * 8, head_color, palette.timeline_tail);
     }
 
     /*
      * Draw grid that we can use to select what phrases are playing
      */
-    fn draw_phrases(&mut self, phrases: &[Option<u8>; 16]) {
-        for (index, option) in phrases[Self::TRACK_OFFSET as usize .. (Self::TRACK_OFFSET + 8) as usize].iter().enumerate() {
+    fn draw_phrases(&mut self, phrases: &[Option<u8>], track_offset: usize) {
+        let color = self.profile().palette.sequence;
+
+        for (index, option) in phrases.iter().skip(track_offset).take(8).enumerate() {
             if let Some(phrase) = option {
-                self.grid().try_draw(index as i32, *phrase, SEQUENCE_COLOR);
+                let color = self.profile().track_color(track_offset + index, color);
+                self.grid().try_draw(index as i32, *phrase, color);
+            }
+        }
+    }
+
+    /*
+     * Activator, solo & arm rows control track mute, solo & arm in mixer mode, returns wether
+     * button was handled. Shift + activator switches velocity scaling for track
+     */
+    fn process_mixer_button(&self, button_type: ButtonType, sequencer: &mut Sequencer, surface: &Surface, mixer: &mut Mixer) -> bool {
+        let index = match button_type {
+            ButtonType::Activator(index) | ButtonType::Solo(index) | ButtonType::Arm(index) => index,
+            _ => return false,
+        };
+
+        let track_index = self.track_index(surface, index);
+        let global_modifier = surface.button_memory.global_modifier(button_type);
+        let is_shift_pressed = matches!(global_modifier, Some(ButtonPress { button_type: ButtonType::Shift, .. }));
+
+        if track_index < sequencer.track_count() {
+            let track = sequencer.track_mut(track_index);
+
+            match button_type {
+                ButtonType::Activator(_) if is_shift_pressed => mixer.toggle_velocity_scaling(track_index),
+                ButtonType::Activator(_) => track.toggle_muted(),
+                ButtonType::Solo(_) => track.toggle_soloed(),
+                _ => track.toggle_armed(),
+            }
+        }
+
+        true
+    }
+
+    fn draw_mixer_rows(&mut self, sequencer: &Sequencer, surface: &Surface) {
+        let track_offset = self.track_index(surface, 0);
+
+        for (index, track) in sequencer.tracks.iter().skip(track_offset).take(8).enumerate() {
+            // Activator shows track is active, like it does in ableton
+            self.activator().draw(index as u8, if track.muted { 0 } else { 1 });
+            self.solo().draw(index as u8, if track.soloed { 1 } else { 0 });
+            self.arm().draw(index as u8, if track.armed { 1 } else { 0 });
+        }
+    }
+
+    /*
+     * Top row of knobs controls pan of tracks, or send when shift is held, other knobs control the
+     * knobs on selected page of shown track
+     */
+    fn knob_target(&self, index: u8, sequencer: &Sequencer, surface: &Surface) -> Option<KnobTarget> {
+        if index < 8 {
+            let track_index = self.track_index(surface, index);
+            let is_shift_pressed = matches!(surface.button_memory.global_modifier(ButtonType::Unknown), Some(ButtonPress { button_type: ButtonType::Shift, .. }));
+
+            if track_index >= sequencer.track_count() {
+                None
+            } else if is_shift_pressed {
+                Some(KnobTarget::Send(track_index))
+            } else {
+                Some(KnobTarget::Pan(track_index))
+            }
+        } else if index - 8 < Track::KNOBS_PER_PAGE {
+            let page = surface.knob_page_shown(surface.track_shown());
+            Some(KnobTarget::Parameter(surface.track_shown(), Track::knob_index(page, index - 8)))
+        } else {
+            None
+        }
+    }
+
+    fn knob_value(&self, target: KnobTarget, sequencer: &Sequencer, mixer: &Mixer) -> u8 {
+        match target {
+            KnobTarget::Pan(track_index) => mixer.channel(track_index).pan,
+            KnobTarget::Send(track_index) => mixer.channel(track_index).send,
+            KnobTarget::Parameter(track_index, index) => sequencer.track(track_index).knob_value(index),
+        }
+    }
+
+    // Rings show the values knobs control, so they are in line with our state when track changes
+    fn draw_rings(&mut self, sequencer: &Sequencer, surface: &Surface, mixer: &Mixer) {
+        for index in 0 .. self.profile().knobs.len() as u8 {
+            if let Some(target) = self.knob_target(index, sequencer, surface) {
+                let value = self.knob_value(target, sequencer, mixer);
+                let style = if let KnobTarget::Pan(_) = target { RingStyle::Pan } else { RingStyle::Volume };
+                self.rings().draw(index, value, style);
+            }
+        }
+    }
+
+    // Get index of track in sequencer based on button index on this controller
+    fn track_index(&self, surface: &Surface, index: u8) -> usize {
+        surface.track_bank_offset() + self.track_offset() as usize + index as usize
+    }
+
+    /*
+     * Flashed text takes over the grid until it scrolled by, text runs over the grids of all
+     * controllers as we offset it by the track offset
+     */
+    fn draw_flash(&mut self, cycle: &ProcessCycle, surface: &mut Surface) {
+        let track_offset = self.track_offset() as usize;
+        let color = self.head_color();
+
+        if let Some((scroller, scrolled)) = surface.flash_frame(cycle.time_stop) {
+            let grid = self.grid();
+            let height = grid.height() as usize;
+            grid.clear();
+
+            for x in 0 .. grid.width() {
+                for row in 0 .. Scroller::HEIGHT.min(height) {
+                    if scroller.is_lit(scrolled + track_offset + x as usize, row) {
+                        // Text is drawn at the top of the grid
+                        grid.draw(x, (height - 1 - row) as u8, color);
+                    }
+                }
             }
         }
     }
@@ -283,14 +478,16 @@ pub trait APC {
         while let Some(x) = x_range.next() { self.grid().try_draw(x, y, color) }
     }
 
-    fn new(client: &jack::Client) -> Self;
-
     /*
      * Process incoming midi, handle generic midi here, pass controller specific input to
      * controller via process_inputevent
      */ 
     fn process_midi_input(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, mixer: &mut Mixer) {
-        for event in self.input_events(cycle.scope) {
+        let mut events = std::mem::take(&mut self.state_mut().input_events);
+        let profile = self.profile();
+        events.extend(self.input().iter(cycle.scope).map(|message| InputEvent::new(message.time, message.bytes, profile)));
+
+        for event in events.drain(..) {
             // Only process channel note messages
             match event.event_type {
                 InputEventType::InquiryResponse(local_id, device_id) => {
@@ -300,18 +497,33 @@ pub trait APC {
                     self.set_device_id(device_id);
                     self.set_local_id(local_id);
                     // Make sure we stop inquiring
-                    // TODO - Make sure every grid is re-initialized after identifying
                     self.set_identified_cycles(1);
+                    // Controller could have been power cycled, so it shows nothing we think it shows
+                    self.reset_lights();
                 },
                 InputEventType::FaderMoved { value, fader_type: FaderType::Track(index) } => {
-                    mixer.fader_adjusted(event.time, index + Self::TRACK_OFFSET, value);
+                    mixer.fader_adjusted(event.time, self.track_index(surface, index), value);
+                },
+                InputEventType::KnobTurned { value, knob_type: KnobType::Effect(index) } => {
+                    if let Some(target) = self.knob_target(index, sequencer, surface) {
+                        let stored = self.knob_value(target, sequencer, mixer);
+
+                        // Knob could still have to pick up stored value
+                        if let Some(value) = self.knob_pickup().turn(index, target, value, stored) {
+                            match target {
+                                KnobTarget::Pan(track_index) => mixer.pan_adjusted(event.time, track_index, value),
+                                KnobTarget::Send(track_index) => mixer.send_adjusted(event.time, track_index, value),
+                                KnobTarget::Parameter(track_index, index) => sequencer.track_mut(track_index).knob_turned(event.time, index, value),
+                            }
+                        }
+                    }
                 },
                 // TODO - Shift events in loopable to right/left when holding shift
                 InputEventType::KnobTurned { value, knob_type: KnobType::Cue } => {
                     // Check if cueknob should respond immediately
-                    let usecs = cycle.time_at_frame(event.time) - LENGTH_INDICATOR_USECS;
+                    let usecs = cycle.time_at_frame(event.time).saturating_sub(LENGTH_INDICATOR_USECS);
                     let is_first_turn = surface.event_memory
-                        .last_occurred_controller_event_after(Self::TRACK_OFFSET, &[InputEvent::is_cue_knob], usecs)
+                        .last_occurred_controller_event_after(self.track_offset(), &[InputEvent::is_cue_knob], usecs)
                         .is_none();
 
                     let delta_buttons = self.cue_knob().process_turn(value, is_first_turn);
@@ -332,7 +544,7 @@ pub trait APC {
 
                             if new_offset >= 0 {
                                 let max_offset_x = surface.timeline_grid.max_offset_x(sequencer.timeline_end(), 8);
-                                surface.timeline_grid.set_offset_x(max_offset_x, new_offset as u32);
+                                surface.timeline_grid.set_offset_x(new_offset as u32, max_offset_x);
                             }
                         },
                         _ => (),
@@ -340,11 +552,17 @@ pub trait APC {
                 },
                 InputEventType::ButtonPressed(button_type) => {
                     // Register press in memory to keep track of modifing buttons
-                    surface.button_memory.press(Self::TRACK_OFFSET, button_type);
+                    surface.button_memory.press(self.track_offset(), button_type);
+
+                    // Mixer buttons work the same in every view
+                    let is_mixer_button = surface.row_mode == RowMode::Mixer
+                        && self.process_mixer_button(button_type, sequencer, surface, mixer);
                     let global_modifier = surface.button_memory.global_modifier(button_type);
+                    let is_shift_pressed = matches!(global_modifier, Some(ButtonPress { button_type: ButtonType::Shift, .. }));
 
                     // Do the right thing in the right visualization
                     match surface.view {
+                        _ if is_mixer_button => (),
                         View::Track => {
                             match button_type {
                                 ButtonType::Solo(index) => {
@@ -356,48 +574,63 @@ pub trait APC {
                             }
                         },
                         View::Sequence => {
-                            let sequence = sequencer.get_sequence(surface.sequence_shown());
+                            let track_count = sequencer.track_count();
+                            let sequence_index = surface.sequence_shown();
 
                             match button_type {
                                 ButtonType::Grid(x, row) => {
-                                    let track = (x + Self::TRACK_OFFSET) as usize;
-                                    
-                                    if let Some(true) = sequence.get_phrase(track).and_then(|phrase| Some(phrase == row)) {
-                                        sequence.unset_phrase(track)
-                                    } else {
-                                        sequence.set_phrase(track, row);
+                                    let track = self.track_index(surface, x);
+
+                                    // Button could be outside of available tracks or phrases
+                                    if track < track_count && row < LOOPABLE_COUNT {
+                                        sequencer.remember(EditTarget::Sequence(sequence_index));
+                                        let sequence = sequencer.get_sequence(sequence_index);
+
+                                        if let Some(true) = sequence.get_phrase(track).and_then(|phrase| Some(phrase == row)) {
+                                            sequence.unset_phrase(track)
+                                        } else {
+                                            sequence.set_phrase(track, row);
+                                        }
                                     }
                                 },
-                                ButtonType::Side(index) => {
+                                ButtonType::Side(index) if index < LOOPABLE_COUNT => {
                                     // TODO - Move double click logic to surface
-                                    let filters = vec![|event_type: &InputEventType| -> bool {
+                                    let filters = [|event_type: &InputEventType| -> bool {
                                         *event_type == event.event_type
                                     }];
-                                    let usecs = cycle.time_stop - DOUBLE_CLICK_USECS;
-                                    let last_occurred_event = surface.event_memory.last_occurred_controller_event_after(Self::TRACK_OFFSET, &filters, usecs);
+                                    let usecs = cycle.time_stop.saturating_sub(DOUBLE_CLICK_USECS);
+                                    let last_occurred_event = surface.event_memory.last_occurred_controller_event_after(self.track_offset(), &filters, usecs);
 
                                     if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
-                                        sequence.set_phrases(index);
+                                        sequencer.remember(EditTarget::Sequence(sequence_index));
+                                        sequencer.get_sequence(sequence_index).set_phrases(index);
                                     } else if let Some(_) = last_occurred_event {
                                         // If we double clicked sequence button, queue it
                                         sequencer.sequence_queued = Some(index as usize);
+                                        surface.flash(format_args!("seq {}", index + 1), cycle.time_stop);
                                     } else {
                                         surface.show_sequence(index);
                                     }
                                 },
-                                ButtonType::Activator(track) => {
-                                    sequence.toggle_active((track + Self::TRACK_OFFSET) as usize)
+                                ButtonType::Activator(index) => {
+                                    let track = self.track_index(surface, index);
+
+                                    if track < track_count {
+                                        sequencer.remember(EditTarget::Sequence(sequence_index));
+                                        sequencer.get_sequence(sequence_index).toggle_active(track)
+                                    }
                                 },
                                 _ => (),
                             }
                         },
                         View::Timeline => {
                             match button_type {
-                                ButtonType::Grid(x, y) => {
+                                ButtonType::Grid(x, y) if y < LOOPABLE_COUNT => {
+                                    sequencer.remember(EditTarget::Timeline(surface.track_shown()));
                                     let track = sequencer.track_mut(surface.track_shown());
 
                                     // Add track offset to make it possible to draw across multiple controllers
-                                    let start = (Self::TRACK_OFFSET + x) as u32 * surface.timeline_grid.ticks_per_button() + surface.timeline_grid.offset_x();
+                                    let start = (self.track_offset() + x) as u32 * surface.timeline_grid.ticks_per_button() + surface.timeline_grid.offset_x();
                                     let mut tick_range = TickRange::new(start, start + surface.timeline_grid.ticks_per_button());
 
                                     // Should we delete the event we're clicking?
@@ -431,53 +664,96 @@ pub trait APC {
 
                     // Independent of current view
                     match button_type {
+                        _ if is_mixer_button => (),
                         ButtonType::Track(index) => {
-                            match surface.view {
-                                View::Track | View::Timeline => {
-                                    if surface.track_shown() == index as usize {
-                                        let view = if matches!(surface.view, View::Timeline) { View::Track } else { View::Timeline };
-                                        surface.switch_view(view);
-                                    } else {
-                                        surface.show_track(index + Self::TRACK_OFFSET);
-                                    }
-                                },
-                                _ => {
-                                    surface.switch_view(View::Timeline);
-                                    surface.show_track(index + Self::TRACK_OFFSET);
-                                },
+                            let track = self.track_index(surface, index);
+
+                            if track < sequencer.track_count() {
+                                match surface.view {
+                                    View::Track | View::Timeline => {
+                                        if surface.track_shown() == track {
+                                            let view = if matches!(surface.view, View::Timeline) { View::Track } else { View::Timeline };
+                                            surface.switch_view(view);
+                                        } else {
+                                            surface.show_track(track);
+                                        }
+                                    },
+                                    _ => {
+                                        surface.switch_view(View::Timeline);
+                                        surface.show_track(track);
+                                    },
+                                }
                             }
                         },
                         // Switch to timeline, when timeline already shown, switch to track
+                        // Shift + master toggles mixer mode for activator, solo & arm rows
                         ButtonType::Master => {
-                            let view = match surface.view {
-                                View::Sequence => View::Track,
-                                _ => View::Sequence,
-                            };
-                            surface.switch_view(view);
+                            if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = surface.button_memory.global_modifier(button_type) {
+                                surface.toggle_row_mode();
+                            } else {
+                                let view = match surface.view {
+                                    View::Sequence => View::Track,
+                                    _ => View::Sequence,
+                                };
+                                surface.switch_view(view);
+                            }
+                        },
+                        ButtonType::KnobPage(index) if index < Track::KNOB_PAGES => {
+                            surface.show_knob_page(surface.track_shown(), index);
+                        },
+                        ButtonType::TapTempo => {
+                            if let Some(beats_per_minute) = surface.tap_tempo(cycle.time_at_frame(event.time)) {
+                                let beats_per_minute = beats_per_minute.round();
+                                sequencer.set_tempo(beats_per_minute);
+                                surface.flash(format_args!("{}", beats_per_minute as u32), cycle.time_stop);
+                            }
+                        },
+                        // Shift + left & right undo & redo edits
+                        ButtonType::Left | ButtonType::Right if is_shift_pressed => {
+                            let is_undo = button_type == ButtonType::Left;
+                            let is_done = if is_undo { sequencer.undo() } else { sequencer.redo() };
+
+                            if is_done {
+                                surface.flash(format_args!("{}", if is_undo { "undo" } else { "redo" }), cycle.time_stop);
+                            }
+                        },
+                        // Shift + record saves project
+                        ButtonType::Record => {
+                            if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = surface.button_memory.global_modifier(button_type) {
+                                surface.save_requested = true;
+                            }
                         },
                         _ => self.process_inputevent(&event, cycle, sequencer, surface, mixer),
                     }
                 },
                 InputEventType::ButtonReleased(button_type) => {
-                    surface.button_memory.release(Self::TRACK_OFFSET, cycle.time_at_frame(event.time), button_type);
+                    surface.button_memory.release(self.track_offset(), cycle.time_at_frame(event.time), button_type);
                 },
                 // This message is controller specific, handle it accordingly
                 _ => self.process_inputevent(&event, cycle, sequencer, surface, mixer),
             }
 
             // Keep track of event so we can use it to calculate double presses etc.
-            surface.event_memory.register_event(Self::TRACK_OFFSET, cycle.time_at_frame(event.time), event.event_type);
+            surface.event_memory.register_event(self.track_offset(), cycle.time_at_frame(event.time), event.event_type);
         }
+
+        self.state_mut().input_events = events;
     }
 
-    fn output_midi(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface) {
-        let mut messages = vec![];
+    // Leds we output are published on bus as controller with index, so frontends can show them
+    fn output_midi(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, mixer: &Mixer, bus: &Bus, index: usize) {
+        let mut messages = self.output().take_buffer();
+
+        // Ports were (re)connected, this could be a replugged controller
+        if self.reconnected().swap(false, Ordering::Relaxed) {
+            self.reset();
+        }
 
         // Identify when no controller found yet
         if self.identified_cycles() == 0 {
             messages.push(TimedMessage::new(0, Message::Inquiry([0xF0, 0x7E, 0x00, 0x06, 0x01, 0xF7])));
         } else if self.identified_cycles() < IDENTIFY_CYCLES {
-            // Output introduction if APC just responded to inquiry
+            // Output introduction if controller just responded to inquiry
             if self.identified_cycles() == 1 {
                 let message = Message::Introduction([0xF0, 0x47, self.local_id(), self.device_id(), 0x60, 0x00, 0x04, 0x41, 0x00, 0x00, 0x00, 0xF7]);
                 messages.push(TimedMessage::new(0, message));
@@ -485,67 +761,90 @@ pub trait APC {
 
             self.set_identified_cycles(self.identified_cycles() + 1);
         } else {
-            // APC 40 / 20 specific messages
+            // Editor specific messages
             self.draw(sequencer, surface);
 
             // Always draw track grid
             // This if statement is here to see if we can subtract TRACK_OFFSET
-            if surface.track_shown() >= Self::TRACK_OFFSET as usize && ! matches!(surface.view, View::Sequence) {
-                let track = surface.track_shown() - Self::TRACK_OFFSET as usize;
-                self.track().draw(track as u8, 1);
+            let track_offset = self.track_index(surface, 0);
+            if surface.track_shown() >= track_offset && ! matches!(surface.view, View::Sequence) {
+                let track = surface.track_shown() - track_offset;
+                if track < self.track().width() as usize {
+                    self.track().draw(track as u8, 1);
+                }
+            }
+            self.track().output_messages(0, &mut messages);
+
+            if surface.row_mode == RowMode::Mixer {
+                self.draw_mixer_rows(sequencer, surface);
             }
-            messages.append(&mut self.track().output_messages(0));
 
             match surface.view {
                 View::Track => {
                     // Draw zoom grid
-                    for index in 0 .. self.loopable_grid(surface).zoom_level() { self.solo().draw(index, 1); }
+                    if surface.row_mode == RowMode::Loopable {
+                        for index in 0 .. self.loopable_grid(surface).zoom_level() { self.solo().draw(index, 1); }
+                    }
                 },
                 View::Timeline => {
                     self.draw_timeline(sequencer, surface);
                 },
                 View::Sequence => {
                     self.master().draw(1);
+                    let track_offset = self.track_index(surface, 0);
                     let phrases = sequencer.get_sequence(surface.sequence_shown()).phrases();
-                    self.draw_phrases(phrases);
+                    self.draw_phrases(phrases, track_offset);
                     self.side().draw(surface.sequence_shown() as u8, 1);
                 },
             };
 
-            messages.append(&mut self.master().output_messages(0));
-            messages.append(&mut self.solo().output_messages(0));
-            messages.append(&mut self.grid().output_messages(0));
-            messages.append(&mut self.activator().output_messages(0));
-            messages.append(&mut self.output_side(cycle, sequencer, surface));
-            messages.append(&mut self.output_indicator(cycle, sequencer, surface));
+            self.master().output_messages(0, &mut messages);
+            self.solo().output_messages(0, &mut messages);
+            self.draw_flash(cycle, surface);
+
+            self.grid().output_messages(0, &mut messages);
+            self.activator().output_messages(0, &mut messages);
+            self.arm().output_messages(0, &mut messages);
+            self.draw_rings(sequencer, surface, mixer);
+            self.rings().output_messages(0, &mut messages);
+            let page = surface.knob_page_shown(surface.track_shown());
+            self.knob_pages().draw(page, 1);
+            self.knob_pages().output_messages(0, &mut messages);
+            self.output_side(cycle, sequencer, surface, &mut messages);
+            self.output_indicator(cycle, sequencer, surface, &mut messages);
         }
 
         // from this function
-        self.output().write_midi(cycle.scope, &mut messages);
+        bus.publish_leds(index, &messages);
+        self.output().write_buffer(cycle.scope, messages);
     }
 
-    fn output(&mut self) -> &mut MidiOut;
-    fn input(&self) -> &jack::Port<jack::MidiIn>;
-
-    fn input_events(&self, scope: &jack::ProcessScope) -> Vec<InputEvent> {
-        self.input().iter(scope).map(|message| InputEvent::new(message.time, message.bytes)).collect()
-    }
+    fn output(&mut self) -> &mut MidiOut { &mut self.state_mut().output }
+    fn input(&self) -> &jack::Port<jack::MidiIn> { &self.state().input }
 
     fn process_inputevent(&mut self, event: &InputEvent, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, mixer: &mut Mixer);
     fn draw(&mut self, sequencer: &mut Sequencer, surface: &mut Surface);
 }
 
-pub struct APC40 {
-    // Ports that connect to APC
+/*
+ * Ports, identification & lights every editor has, whatever role it plays
+ */
+pub struct EditorState {
+    // Ports that connect to controller
     input: jack::Port<jack::MidiIn>,
     output: MidiOut,
 
+    profile: DeviceProfile,
+    track_offset: u8,
+
+    reconnected: Arc<AtomicBool>,
     identified_cycles: u8,
     device_id: u8,
     local_id: u8,
-    //knob_offset: u8,
 
     cue_knob: CueKnob,
+    knob_pickup: KnobPickup,
+    rings: Rings,
     master: Single,
 
     grid: Grid,
@@ -554,29 +853,83 @@ pub struct APC40 {
     track: WideRow,
     activator: WideRow,
     solo: WideRow,
-    //arm: WideRow,
+    arm: WideRow,
+    knob_pages: WideRow,
+
+    // Reused every cycle, so we don't allocate on the process thread
+    input_events: Vec<InputEvent>,
+    playing_indexes: Vec<u8>,
+    playing_ranges: Vec<(TickRange, u32)>,
 }
 
-impl APC for APC40 {
+impl EditorState {
+    // Input events & playing loopables per cycle, more of them will allocate
+    const INPUT_EVENTS_CAPACITY: usize = 256;
+    const PLAYING_CAPACITY: usize = 64;
+
+    pub fn new(client: &jack::Client, setup: &ControllerSetup) -> Result<Self, StartupError> {
+        let input = register_port(client, &format!("{} in", setup.name), jack::MidiIn::default())?;
+        let output = register_port(client, &format!("{} out", setup.name), jack::MidiOut::default())?;
+        let profile = setup.profile.clone();
+        let on = profile.palette.on;
+        let line = |layout: Option<LineLayout>| layout.unwrap_or_else(LineLayout::empty);
+
+        Ok(EditorState {
+            input,
+            output: MidiOut::new(output),
+
+            reconnected: Arc::new(AtomicBool::new(false)),
+            // Devices we don't have to introduce ourselves to are identified from the start
+            identified_cycles: if profile.device_id.is_some() { 0 } else { IDENTIFY_CYCLES },
+            local_id: 0,
+            device_id: 0,
+
+            cue_knob: CueKnob::new(),
+            knob_pickup: KnobPickup::new(profile.knobs.len(), profile.takeover),
+            rings: Rings::new(profile.knobs.clone(), profile.rings.clone()),
+            master: Single::new(profile.button_note(ButtonType::Master), on),
+
+            grid: Grid::new(profile.grid),
+            side: Side::new(line(profile.side), on),
+            indicator: WideRow::new(line(profile.indicator), on),
+            track: WideRow::new(line(profile.track), on),
+            activator: WideRow::new(line(profile.activator), on),
+            solo: WideRow::new(line(profile.solo), on),
+            arm: WideRow::new(line(profile.arm), on),
+            knob_pages: WideRow::new(line(profile.knob_pages), on),
+
+            input_events: Vec::with_capacity(Self::INPUT_EVENTS_CAPACITY),
+            playing_indexes: Vec::with_capacity(Self::PLAYING_CAPACITY),
+            playing_ranges: Vec::with_capacity(Self::PLAYING_CAPACITY),
+
+            profile,
+            track_offset: setup.track_offset,
+        })
+    }
+}
+
+pub struct PatternEditor {
+    state: EditorState,
+}
+
+impl PatternEditor {
+    pub fn new(client: &jack::Client, setup: &ControllerSetup) -> Result<Self, StartupError> {
+        Ok(Self { state: EditorState::new(client, setup)? })
+    }
+}
+
+impl Editor for PatternEditor {
     type Loopable = Pattern;
 
-    const TRACK_OFFSET: u8 = 8;
-    const HEAD_COLOR: u8 = 1;
-    const TAIL_COLOR: u8 = 5;
+    fn state(&self) -> &EditorState { &self.state }
+    fn state_mut(&mut self) -> &mut EditorState { &mut self.state }
 
-    fn identified_cycles(&self) -> u8 { self.identified_cycles }
-    fn set_identified_cycles(&mut self, cycles: u8) { self.identified_cycles = cycles }
-    fn local_id(&self) -> u8 { self.local_id }
-    fn set_local_id(&mut self, local_id: u8) { self.local_id = local_id }
-    fn device_id(&self) -> u8 { self.device_id }
-    fn set_device_id(&mut self, device_id: u8) { self.device_id = device_id }
+    fn head_color(&self) -> u8 { self.state.profile.palette.pattern_head }
+    fn tail_color(&self) -> u8 { self.state.profile.palette.pattern_tail }
 
     fn loopable_grid<'a>(&self, surface: &'a Surface) -> &'a LoopableGrid { &surface.pattern_grid }
     fn loopable_grid_mut<'a>(&self, surface: &'a mut Surface) -> &'a mut LoopableGrid { &mut surface.pattern_grid }
 
-    fn output(&mut self) -> &mut MidiOut { &mut self.output }
-    fn input(&self) -> &jack::Port<jack::MidiIn> { &self.input }
-
     fn shown_loopable_index(&self, surface: &Surface) -> u8 { surface.pattern_shown(surface.track_shown()) }
 
     fn shown_loopable<'a>(&self, sequencer: &'a Sequencer, surface: &Surface) -> &'a Self::Loopable { 
@@ -588,23 +941,24 @@ impl APC for APC40 {
         track.pattern_mut(self.shown_loopable_index(surface))
     }
 
-    fn playing_loopable_indexes(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<u8> {
-        sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range).into_iter()
+    fn playing_loopable_indexes(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface, indexes: &mut Vec<u8>) {
+        let playing = sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range)
             .flat_map(|(tick_range, sequence_start, phrase_index)| {
-                sequencer.playing_patterns(&tick_range, surface.track_shown(), phrase_index, sequence_start).into_iter()
+                sequencer.playing_patterns(&tick_range, surface.track_shown(), phrase_index, sequence_start)
                     .map(|(pattern_index, _, _, _, _)| pattern_index)
-            })
-            .collect()
+            });
+
+        indexes.extend(playing);
     }
 
-    fn playing_loopable_ranges(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<(TickRange, u32)> {
+    fn playing_loopable_ranges(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface, ranges: &mut Vec<(TickRange, u32)>) {
         // Get playing phrases of currently selected track
         let shown_pattern_index = self.shown_loopable_index(surface);
         let pattern = sequencer.track(surface.track_shown()).pattern(shown_pattern_index);
 
-        sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range).into_iter()
+        let playing = sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range)
             .flat_map(|(tick_range, sequence_start, phrase_index)| {
-                sequencer.playing_patterns(&tick_range, surface.track_shown(), phrase_index, sequence_start).into_iter()
+                sequencer.playing_patterns(&tick_range, surface.track_shown(), phrase_index, sequence_start)
                     .filter(|(pattern_index, _, _, _, _)| *pattern_index == shown_pattern_index)
                     .map(move |(_, absolute_start, relative_range, _, _)| {
                         let absolute_range = relative_range.plus(absolute_start);
@@ -620,49 +974,14 @@ impl APC for APC40 {
 
                         (absolute_range, start)
                     })
-            })
-            .collect()
-    }
-
-    fn cue_knob(&mut self) -> &mut CueKnob { &mut self.cue_knob }
-    fn master(&mut self) -> &mut Single { &mut self.master }
-    fn grid(&mut self) -> &mut Grid { &mut self.grid }
-    fn side(&mut self) -> &mut Side { &mut self.side }
-    fn track(&mut self) -> &mut WideRow { &mut self.track }
-    fn indicator(&mut self) -> &mut WideRow { &mut self.indicator }
-    fn activator(&mut self) -> &mut WideRow { &mut self.activator }
-    fn solo(&mut self) -> &mut WideRow { &mut self.solo }
-
-    fn new(client: &jack::Client) -> Self {
-        let input = client.register_port("APC40 in", jack::MidiIn::default()).unwrap();
-        let output = client.register_port("APC40 out", jack::MidiOut::default()).unwrap();
-        
-        Self {
-            input,
-            output: MidiOut::new(output),
-
-            identified_cycles: 0,
-            local_id: 0,
-            device_id: 0,
-            // Offset knobs by this value to support multiple groups
-            //knob_offset: 0,
+            });
 
-            cue_knob: CueKnob::new(),
-            master: Single::new(0x50),
-
-            grid: Grid::new(),
-            side: Side::new(),
-            indicator: WideRow::new(0x34),
-            track: WideRow::new(0x33),
-            activator: WideRow::new(0x32),
-            solo: WideRow::new(0x31),
-            // TODO - Put length indicator here, get length from longest LoopablePatternEvent in phrases?
-            //arm: WideRow::new(0x30),
-        }
+        ranges.extend(playing);
     }
 
+
     /*
-     * Process APC40 specific midi input, shared input is handled by APC trait
+     * Process pattern editor specific midi input, shared input is handled by Editor trait
      */
     fn process_inputevent(&mut self, event: &InputEvent, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, mixer: &mut Mixer) {
 
@@ -671,29 +990,21 @@ impl APC for APC40 {
             InputEventType::FaderMoved { value, fader_type: FaderType::Master } => {
                 mixer.master_adjusted(event.time, value);
             },
+            // Crossfader scrubs through loopables, length indicator shows position as crossfader
+            // events are registered in event memory
             InputEventType::FaderMoved { value, fader_type: FaderType::CrossFade } => {
                 let factor = value as f64 / 127.0;
-                //let max_offset = self.max_offset(self.shown_loopable(sequencer, surface).length());
-                
-                //let offset = (max_offset as f64 * factor) as u32;
                 surface.set_offsets_by_factor(sequencer, surface.track_shown(), factor);
-                //println!("{:?} {:?}", offset, max_offset);
-                
-                //self.set_offset(surface.track_shown(), offset);
-                //mixer.master_adjusted(event.time, value);
-            },
-            InputEventType::KnobTurned { value: _, knob_type: KnobType::Effect(_index) } => {
-                // TODO 
-                //sequencer.knob_turned(event.time, index + self.knob_offset, value);
             },
             InputEventType::ButtonPressed(button_type) => {
                 // Get modifier (other currently pressed key)
-                let modifier = surface.button_memory.modifier(Self::TRACK_OFFSET, button_type);
+                let modifier = surface.button_memory.modifier(self.track_offset(), button_type);
 
                 match surface.view {
                     View::Track => {
                         match button_type {
                             ButtonType::Grid(x, y) => {
+                                sequencer.remember(EditTarget::Pattern(surface.track_shown(), surface.pattern_shown(surface.track_shown())));
                                 let track = sequencer.track_mut(surface.track_shown());
                                 let pattern = track.pattern_mut(surface.pattern_shown(surface.track_shown()));
 
@@ -713,7 +1024,7 @@ impl APC for APC40 {
                                     pattern.add_complete_event(event);
                                 }
                             },
-                            ButtonType::Side(index) => {
+                            ButtonType::Side(index) if index < LOOPABLE_COUNT => {
                                 let global_modifier = surface.button_memory.global_modifier(button_type);
 
                                 // TODO - double press logic && recording logic
@@ -721,6 +1032,7 @@ impl APC for APC40 {
                                     //track.pattern_mut(index).switch_recording_state()
                                 } else {
                                     if let Some(ButtonType::Side(modifier_index)) = modifier {
+                                        sequencer.remember(EditTarget::Pattern(surface.track_shown(), index));
                                         let track = sequencer.track_mut(surface.track_shown());
                                         track.clone_pattern(modifier_index, index);
                                     } else if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
@@ -728,6 +1040,7 @@ impl APC for APC40 {
                                         let max_offset_x = surface.pattern_grid.max_offset_x(length, 8);
                                         surface.pattern_grid.set_offset_x(0, max_offset_x);
 
+                                        sequencer.remember(EditTarget::Pattern(surface.track_shown(), index));
                                         let track = sequencer.track_mut(surface.track_shown());
                                         track.pattern_mut(index).clear_events();
                                     } else {
@@ -736,6 +1049,7 @@ impl APC for APC40 {
                                 }
                             },
                             ButtonType::Activator(index) => {
+                                sequencer.remember(EditTarget::Pattern(surface.track_shown(), surface.pattern_shown(surface.track_shown())));
                                 let track = sequencer.track_mut(surface.track_shown());
                                 let pattern = track.pattern_mut(surface.pattern_shown(surface.track_shown()));
                                 let length = Pattern::minimum_length() * (index as u32 + 1);
@@ -780,7 +1094,24 @@ impl APC for APC40 {
                             _ => (),
                         }
                     },
-                    _ => ()
+                    View::Sequence | View::Timeline => {
+                        let global_modifier = surface.button_memory.global_modifier(button_type);
+                        let is_shift_pressed = matches!(global_modifier, Some(ButtonPress { button_type: ButtonType::Shift, .. }));
+
+                        match button_type {
+                            // Browse banks of tracks when there's more tracks as fit on the controllers
+                            ButtonType::Right => surface.next_track_bank(sequencer.track_count()),
+                            ButtonType::Left => surface.previous_track_bank(),
+                            // Add & remove tracks from sequence view
+                            ButtonType::Up if is_shift_pressed && surface.view == View::Sequence => { sequencer.request_track(); },
+                            ButtonType::Down if is_shift_pressed && surface.view == View::Sequence => {
+                                sequencer.remove_track();
+                                surface.resize_tracks(sequencer.track_count());
+                                mixer.resize_tracks(sequencer.track_count());
+                            },
+                            _ => (),
+                        }
+                    },
                 }
 
                 match button_type {
@@ -800,7 +1131,7 @@ impl APC for APC40 {
                                 _ => {
                                     sequencer.reset(cycle);
                                     let max_offset_x = surface.timeline_grid.max_offset_x(sequencer.timeline_end(), 8);
-                                    surface.timeline_grid.set_offset_x(max_offset_x, 0);
+                                    surface.timeline_grid.set_offset_x(0, max_offset_x);
                                 },
                             };
                         }
@@ -819,15 +1150,19 @@ impl APC for APC40 {
 
                 // Get base note of track, as we draw the grid with base note in vertical center
                 let offset_y = surface.pattern_grid.offset_y();
+                let offset_top = offset_y + self.grid().height() - 1;
                 let events = loopable.events().iter()
-                    .filter(|event| event.note >= offset_y && event.note <= offset_y + 4);
+                    .filter(|event| event.note >= offset_y && event.note <= offset_top);
 
-                self.draw_loopable_events(events, surface.pattern_grid.offset_x(), offset_y, surface.pattern_grid.ticks_per_button() * 8, Self::HEAD_COLOR, Self::TAIL_COLOR);
+                // Rgb devices show velocity of notes
+                let fallback = self.head_color();
+                let head_color = |profile: &DeviceProfile, event: &LoopableNoteEvent| profile.velocity_color(event.start_velocity, fallback);
+                self.draw_loopable_events(events, surface.pattern_grid.offset_x(), offset_y, surface.pattern_grid.ticks_per_button() * 8, head_color, self.tail_color());
 
                 // pattern length selector
-                if loopable.has_explicit_length() {
+                if loopable.has_explicit_length() && surface.row_mode == RowMode::Loopable {
                     for index in 0 .. (loopable.length() / Self::Loopable::minimum_length()) {
-                        self.activator.draw(index as u8, 1);
+                        self.activator().draw(index as u8, 1);
                     }
                 }
             },
@@ -843,48 +1178,60 @@ impl APC for APC40 {
 }
 
 
-pub struct APC20 {
-    // Ports that connect to APC
-    input: jack::Port<jack::MidiIn>,
-    output: MidiOut,
+pub struct PhraseEditor {
+    state: EditorState,
+}
 
-    identified_cycles: u8,
-    device_id: u8,
-    local_id: u8,
+impl PhraseEditor {
+    pub fn new(client: &jack::Client, setup: &ControllerSetup) -> Result<Self, StartupError> {
+        Ok(Self { state: EditorState::new(client, setup)? })
+    }
+}
 
-    cue_knob: CueKnob,
-    master: Single,
+impl PhraseEditor {
+    /*
+     * Jump to eighth of length, shift + indicator sets loop points for the view, holding another
+     * indicator loops the eighths in between. Shift pressing a looped eighth again stops looping
+     */
+    fn process_indicator_button(&self, index: u8, grid: &mut LoopableGrid, length: u32, modifier: Option<ButtonType>, global_modifier: Option<&ButtonPress>) {
+        let eighth = length / 8;
+        let max_offset_x = grid.max_offset_x(length, 8);
+
+        if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
+            let start = match modifier {
+                Some(ButtonType::Indicator(other)) => other.min(index),
+                _ => index,
+            };
+            let stop = match modifier {
+                Some(ButtonType::Indicator(other)) => other.max(index) + 1,
+                _ => index + 1,
+            };
+            let view_loop = Some((start as u32 * eighth, stop as u32 * eighth));
 
-    // Lights
-    grid: Grid,
-    side: Side,
-    indicator: WideRow,
-    track: WideRow,
-    activator: WideRow,
-    solo: WideRow,
-    //arm: WideRow,
+            if modifier.is_none() && grid.view_loop() == view_loop {
+                grid.set_view_loop(None);
+            } else {
+                grid.set_view_loop(view_loop);
+                grid.set_offset_x(start as u32 * eighth, max_offset_x);
+            }
+        } else {
+            grid.set_offset_x(index as u32 * eighth, max_offset_x);
+        }
+    }
 }
 
-impl APC for APC20 {
+impl Editor for PhraseEditor {
     type Loopable = Phrase;
 
-    const TRACK_OFFSET: u8 = 0;
-    const HEAD_COLOR: u8 = 3;
-    const TAIL_COLOR: u8 = 5;
+    fn state(&self) -> &EditorState { &self.state }
+    fn state_mut(&mut self) -> &mut EditorState { &mut self.state }
 
-    fn identified_cycles(&self) -> u8 { self.identified_cycles }
-    fn set_identified_cycles(&mut self, cycles: u8) { self.identified_cycles = cycles }
-    fn local_id(&self) -> u8 { self.local_id }
-    fn set_local_id(&mut self, local_id: u8) { self.local_id = local_id }
-    fn device_id(&self) -> u8 { self.device_id }
-    fn set_device_id(&mut self, device_id: u8) { self.device_id = device_id }
+    fn head_color(&self) -> u8 { self.state.profile.palette.phrase_head }
+    fn tail_color(&self) -> u8 { self.state.profile.palette.phrase_tail }
 
     fn loopable_grid<'a>(&self, surface: &'a Surface) -> &'a LoopableGrid { &surface.phrase_grid }
     fn loopable_grid_mut<'a>(&self, surface: &'a mut Surface) -> &'a mut LoopableGrid { &mut surface.phrase_grid }
 
-    fn output(&mut self) -> &mut MidiOut { &mut self.output }
-    fn input(&self) -> &jack::Port<jack::MidiIn> { &self.input }
-
     fn shown_loopable_index(&self, surface: &Surface) -> u8 { surface.phrase_shown(surface.track_shown()) }
 
     fn shown_loopable<'a>(&self, sequencer: &'a Sequencer, surface: &Surface) -> &'a Self::Loopable { 
@@ -897,80 +1244,61 @@ impl APC for APC20 {
     }
 
     // Get indexes of currently playing phrases in showed track
-    fn playing_loopable_indexes(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<u8> {
-        sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range).into_iter()
-            .map(|(_, _, phrase_index)| phrase_index)
-            .collect()
+    fn playing_loopable_indexes(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface, indexes: &mut Vec<u8>) {
+        indexes.extend(sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range).map(|(_, _, phrase_index)| phrase_index));
     }
 
-    fn playing_loopable_ranges(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface) -> Vec<(TickRange, u32)> {
+    fn playing_loopable_ranges(&self, cycle: &ProcessCycle, sequencer: &Sequencer, surface: &mut Surface, ranges: &mut Vec<(TickRange, u32)>) {
         // Get playing phrases for currently selected track
         let shown_phrase_index = self.shown_loopable_index(surface);
         let length = sequencer.track(surface.track_shown()).phrase(shown_phrase_index).length();
 
-        sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range).into_iter()
+        let playing = sequencer.playing_phrases(surface.track_shown(), &cycle.tick_range)
             .filter(|(_, _, index)| *index == shown_phrase_index)
             .map(|(range, sequence_start, _)| {
                 let iterations = (range.start - sequence_start) / length;
 
                 (range, sequence_start + iterations * length)
-            })
-            .collect()
-    }
-
-    fn cue_knob(&mut self) -> &mut CueKnob { &mut self.cue_knob }
-    fn master(&mut self) -> &mut Single { &mut self.master }
-    fn grid(&mut self) -> &mut Grid { &mut self.grid }
-    fn side(&mut self) -> &mut Side { &mut self.side }
-    fn track(&mut self) -> &mut WideRow { &mut self.track }
-    fn activator(&mut self) -> &mut WideRow { &mut self.activator }
-    fn indicator(&mut self) -> &mut WideRow { &mut self.indicator }
-    fn solo(&mut self) -> &mut WideRow { &mut self.solo }
-
-    fn new(client: &jack::Client) -> Self {
-        let input = client.register_port("APC20 in", jack::MidiIn::default()).unwrap();
-        let output = client.register_port("APC20 out", jack::MidiOut::default()).unwrap();
-        
-        Self {
-            input,
-            output: MidiOut::new(output),
-
-            identified_cycles: 0,
-            local_id: 0,
-            device_id: 0,
+            });
 
-            cue_knob: CueKnob::new(),
-            master: Single::new(0x50),
-
-            grid: Grid::new(),
-            side: Side::new(),
-            indicator: WideRow::new(0x34),
-            track: WideRow::new(0x33),
-            activator: WideRow::new(0x32),
-            solo: WideRow::new(0x31),
-            //arm: WideRow::new(0x30),
-        }
+        ranges.extend(playing);
     }
 
+
     fn process_inputevent(&mut self, event: &InputEvent, _cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, _mixer: &mut Mixer) {
-        let track = sequencer.track_mut(surface.track_shown());
-        let phrase = track.phrase_mut(surface.phrase_shown(surface.track_shown()));
+        let timeline_end = sequencer.timeline_end();
+        let track_index = surface.track_shown();
+        let phrase_index = surface.phrase_shown(track_index);
 
         // Only process channel note messages
         match event.event_type {
-            // TODO - Use indicator row as fast movement
+            // Indicator row moves view to eighths of phrase or timeline
+            InputEventType::ButtonPressed(ButtonType::Indicator(index)) if index < 8 => {
+                let (grid, length) = match surface.view {
+                    View::Track => (&mut surface.phrase_grid, sequencer.track(track_index).phrase(phrase_index).length()),
+                    View::Timeline => (&mut surface.timeline_grid, timeline_end),
+                    View::Sequence => return,
+                };
+
+                let modifier = surface.button_memory.modifier(self.track_offset(), ButtonType::Indicator(index));
+                let global_modifier = surface.button_memory.global_modifier(ButtonType::Indicator(index));
+                self.process_indicator_button(index, grid, length, modifier, global_modifier);
+            },
             InputEventType::ButtonPressed(button_type) => {
                 // Get modifier (other currently pressed key)
-                let modifier = surface.button_memory.modifier(Self::TRACK_OFFSET, button_type);
+                let modifier = surface.button_memory.modifier(self.track_offset(), button_type);
 
                 match surface.view {
                     View::Track => {
                         match button_type {
-                            ButtonType::Grid(x, y) => {
+                            ButtonType::Grid(x, y) if y < LOOPABLE_COUNT => {
                                 let offset = surface.phrase_grid.offset_x();
                                 // We draw grids from bottom to top
                                 let ticks_per_button = self.loopable_grid(surface).ticks_per_button();
 
+                                sequencer.remember(EditTarget::Phrase(track_index, phrase_index));
+                                let phrase = sequencer.track_mut(track_index).phrase_mut(phrase_index);
+
                                 if let Some(tick_range) = self.should_add_event(phrase, modifier, ticks_per_button, x, y, offset, y) {
                                     phrase.try_add_starting_event(LoopablePatternEvent::new(tick_range.start, y));
                                     let mut event = phrase.get_last_event_on_row(y);
@@ -979,19 +1307,22 @@ impl APC for APC20 {
                                     phrase.add_complete_event(event);
                                 }
                             },
-                            ButtonType::Side(index) => {
+                            ButtonType::Side(index) if index < LOOPABLE_COUNT => {
                                 let global_modifier = surface.button_memory.global_modifier(button_type);
 
                                 if let Some(ButtonType::Side(modifier_index)) = modifier {
-                                    track.clone_phrase(modifier_index, index);
+                                    sequencer.remember(EditTarget::Phrase(track_index, index));
+                                    sequencer.track_mut(track_index).clone_phrase(modifier_index, index);
                                 } else if let Some(ButtonPress { button_type: ButtonType::Shift, .. }) = global_modifier {
-                                    track.phrase_mut(index).clear_events();
+                                    sequencer.remember(EditTarget::Phrase(track_index, index));
+                                    sequencer.track_mut(track_index).phrase_mut(index).clear_events();
                                 } else {
                                     surface.show_phrase(surface.track_shown(), index);
                                 }
                             },
                             ButtonType::Activator(index) => {
-                                phrase.set_length(Phrase::default_length() * (index as u32 + 1));
+                                sequencer.remember(EditTarget::Phrase(track_index, phrase_index));
+                                sequencer.track_mut(track_index).phrase_mut(phrase_index).set_length(Phrase::default_length() * (index as u32 + 1));
                             },
                             _ => (),
                         }
@@ -1003,7 +1334,7 @@ impl APC for APC20 {
         }
     }
 
-    // Draw APC specific things
+    // Draw phrase editor specific things
     fn draw(&mut self, sequencer: &mut Sequencer, surface: &mut Surface) {
         match surface.view {
             View::Track => {
@@ -1012,11 +1343,16 @@ impl APC for APC20 {
                 // Draw main grid
                 let events = loopable.events().iter();
                 let loopable_grid = self.loopable_grid(surface);
-                self.draw_loopable_events(events, loopable_grid.offset_x(), 0, loopable_grid.ticks_in_grid(8), Self::HEAD_COLOR, Self::TAIL_COLOR);
+                // Rgb devices show patterns in color of track
+                let fallback = self.head_color();
+                let head_color = |profile: &DeviceProfile, _: &LoopablePatternEvent| profile.track_color(surface.track_shown(), fallback);
+                self.draw_loopable_events(events, loopable_grid.offset_x(), 0, loopable_grid.ticks_in_grid(8), head_color, self.tail_color());
 
                 // Length selector
-                for index in 0 .. (loopable.length() / Self::Loopable::default_length()) {
-                    self.activator.draw(index as u8, 1);
+                if surface.row_mode == RowMode::Loopable {
+                    for index in 0 .. (loopable.length() / Self::Loopable::default_length()) {
+                        self.activator().draw(index as u8, 1);
+                    }
                 }
             },
             View::Sequence => {
@@ -1026,3 +1362,87 @@ impl APC for APC20 {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Pattern,
+    Phrase,
+}
+
+/*
+ * What device is connected, what it's role is and which tracks it shows
+ */
+#[derive(Debug, Clone)]
+pub struct ControllerSetup {
+    // Jack ports are named after the setup
+    pub name: String,
+    pub profile: DeviceProfile,
+    pub role: Role,
+    pub track_offset: u8,
+}
+
+impl ControllerSetup {
+    // APC20 editing phrases of the first 8 tracks, APC40 editing patterns of the next 8
+    pub fn defaults() -> Result<Vec<Self>, String> {
+        Ok(vec![
+            Self { name: "APC20".to_string(), profile: DeviceProfile::find("apc20")?, role: Role::Phrase, track_offset: 0 },
+            Self { name: "APC40".to_string(), profile: DeviceProfile::find("apc40")?, role: Role::Pattern, track_offset: 8 },
+        ])
+    }
+
+    // Tracks shown at once, controllers show as many tracks as their grid is wide from their offset
+    pub fn track_bank_size(setups: &[Self]) -> usize {
+        setups.iter()
+            .map(|setup| setup.track_offset as usize + setup.profile.grid.width as usize)
+            .max()
+            .unwrap_or(8)
+    }
+}
+
+pub enum Controller {
+    Pattern(PatternEditor),
+    Phrase(PhraseEditor),
+}
+
+/*
+ * Notification handler uses this to tell controller it's ports were connected
+ */
+pub struct Connection {
+    // Full jack names of controller ports
+    pub port_names: Vec<String>,
+    pub reconnected: Arc<AtomicBool>,
+}
+
+impl Controller {
+    pub fn new(client: &jack::Client, setup: &ControllerSetup) -> Result<Self, StartupError> {
+        Ok(match setup.role {
+            Role::Pattern => Controller::Pattern(PatternEditor::new(client, setup)?),
+            Role::Phrase => Controller::Phrase(PhraseEditor::new(client, setup)?),
+        })
+    }
+
+    pub fn process_midi_input(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, mixer: &mut Mixer) {
+        match self {
+            Controller::Pattern(editor) => editor.process_midi_input(cycle, sequencer, surface, mixer),
+            Controller::Phrase(editor) => editor.process_midi_input(cycle, sequencer, surface, mixer),
+        }
+    }
+
+    pub fn output_midi(&mut self, cycle: &ProcessCycle, sequencer: &mut Sequencer, surface: &mut Surface, mixer: &Mixer, bus: &Bus, index: usize) {
+        match self {
+            Controller::Pattern(editor) => editor.output_midi(cycle, sequencer, surface, mixer, bus, index),
+            Controller::Phrase(editor) => editor.output_midi(cycle, sequencer, surface, mixer, bus, index),
+        }
+    }
+
+    pub fn connection(&self) -> Connection {
+        let (input, output, reconnected) = match self {
+            Controller::Pattern(PatternEditor { state }) | Controller::Phrase(PhraseEditor { state }) => {
+                (state.input.name(), state.output.port.name(), state.reconnected.clone())
+            },
+        };
+
+        Connection { port_names: input.into_iter().chain(output).collect(), reconnected }
+    }
+}
@@ -0,0 +1,590 @@
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+use super::input::ButtonType;
+
+/*
+ * Device profiles describe how a controller maps it's buttons, knobs, faders & leds to midi, this
+ * way editors don't have to know what device they are talking to. Profiles are read from toml
+ * files in the profiles directory, the profiles we ship with are compiled in
+ */
+const PROFILE_DIRECTORY: &str = "profiles";
+const BUILTIN_PROFILES: [(&str, &str); 6] = [
+    ("apc40", include_str!("../../profiles/apc40.toml")),
+    ("apc20", include_str!("../../profiles/apc20.toml")),
+    ("apc40_mkii", include_str!("../../profiles/apc40_mkii.toml")),
+    ("apc_mini", include_str!("../../profiles/apc_mini.toml")),
+    ("launchpad", include_str!("../../profiles/launchpad.toml")),
+    ("generic_grid", include_str!("../../profiles/generic_grid.toml")),
+];
+// Names of single buttons in profile files
+const BUTTON_NAMES: [(&str, ButtonType); 11] = [
+    ("shift", ButtonType::Shift),
+    ("master", ButtonType::Master),
+    ("play", ButtonType::Play),
+    ("stop", ButtonType::Stop),
+    ("quantization", ButtonType::Quantization),
+    ("tap_tempo", ButtonType::TapTempo),
+    ("record", ButtonType::Record),
+    ("up", ButtonType::Up),
+    ("down", ButtonType::Down),
+    ("right", ButtonType::Right),
+    ("left", ButtonType::Left),
+];
+
+// How buttons in a row or column are addressed
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Addressing {
+    // Every button uses the same note on it's own channel, like the track rows of the APC's
+    ChannelPerButton { note: u8 },
+    // Every button has it's own note, stride notes apart
+    NotePerButton { channel: u8, first_note: u8, stride: u8 },
+    // Every button has it's own control change, like the top row of the launchpad
+    ControlPerButton { channel: u8, first_control: u8 },
+}
+
+// Row or column of buttons
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LineLayout {
+    pub length: u8,
+    pub addressing: Addressing,
+    // Flipped lines count from the end, we count the side from the bottom like the grid
+    #[serde(default)]
+    pub flipped: bool,
+}
+
+// How buttons in the main grid are addressed
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum GridAddressing {
+    // Columns are channels, rows are notes, like the clip launch grid of APC40 & APC20
+    ChannelPerColumn { first_note: u8 },
+    // Every button has it's own note, rows are row_stride notes apart
+    NotePerButton { channel: u8, first_note: u8, row_stride: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GridLayout {
+    pub width: u8,
+    pub height: u8,
+    pub addressing: GridAddressing,
+    // Devices that count rows from the top are flipped, as we draw lower notes lower on the grid
+    #[serde(default)]
+    pub flip_y: bool,
+}
+
+// What happens when a knob is turned while it's position differs from the value it controls
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Takeover {
+    // Value jumps to knob, fine for knobs with led rings as they continue from the ring value
+    Jump,
+    // Knob does nothing until it passes the value it controls
+    Pickup,
+}
+
+impl Default for Takeover {
+    fn default() -> Self { Takeover::Pickup }
+}
+
+// Led velocities for the things we draw
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Palette {
+    // Leds in rows, side & single buttons
+    pub on: u8,
+    pub pattern_head: u8,
+    pub pattern_tail: u8,
+    pub phrase_head: u8,
+    pub phrase_tail: u8,
+    pub timeline_head: u8,
+    pub timeline_tail: u8,
+    pub sequence: u8,
+}
+
+// Devices with rgb leds pick colors from a palette of their own, when a device has one we show
+// velocity of notes & a color per track. Notes have no probability & patterns no transposition
+// yet, so there's no colors for those
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RgbPalette {
+    // Colors of notes from lowest to highest velocity
+    pub velocities: Vec<u8>,
+    // Colors of tracks, repeated when there's more tracks as colors
+    pub tracks: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceProfile {
+    pub name: String,
+    // Device id in response to sysex inquiry, devices with an id need an introduction before they
+    // listen to us
+    pub device_id: Option<u8>,
+
+    pub grid: GridLayout,
+    pub side: Option<LineLayout>,
+    pub track: Option<LineLayout>,
+    pub indicator: Option<LineLayout>,
+    pub activator: Option<LineLayout>,
+    pub solo: Option<LineLayout>,
+    pub arm: Option<LineLayout>,
+    // Buttons selecting the page of knobs
+    pub knob_pages: Option<LineLayout>,
+    // Single buttons by note
+    pub buttons: Vec<(u8, ButtonType)>,
+
+    // Control changes of effect knobs, position in vec is knob index
+    pub knobs: Vec<u8>,
+    // Control changes that set led ring style of knobs, rings show values on knob control changes
+    pub rings: Vec<u8>,
+    pub takeover: Takeover,
+    pub cue_knob: Option<u8>,
+    pub track_faders: Option<LineLayout>,
+    pub master_fader: Option<u8>,
+    pub crossfader: Option<u8>,
+
+    pub palette: Palette,
+    pub rgb: Option<RgbPalette>,
+}
+
+/*
+ * What a profile file looks like, it's validated before we turn it into a DeviceProfile
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    name: String,
+    device_id: Option<u8>,
+
+    grid: GridLayout,
+    side: Option<LineLayout>,
+    track: Option<LineLayout>,
+    indicator: Option<LineLayout>,
+    activator: Option<LineLayout>,
+    solo: Option<LineLayout>,
+    arm: Option<LineLayout>,
+    knob_pages: Option<LineLayout>,
+    // Button name to one or more notes
+    #[serde(default)]
+    buttons: BTreeMap<String, Notes>,
+
+    #[serde(default)]
+    controls: Controls,
+    palette: Palette,
+    rgb: Option<RgbPalette>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Notes {
+    One(u8),
+    Many(Vec<u8>),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Controls {
+    #[serde(default)]
+    knobs: Vec<u8>,
+    #[serde(default)]
+    rings: Vec<u8>,
+    #[serde(default)]
+    takeover: Takeover,
+    cue_knob: Option<u8>,
+    track_faders: Option<LineLayout>,
+    master_fader: Option<u8>,
+    crossfader: Option<u8>,
+}
+
+fn validate_midi_value(name: &str, value: u32) -> Result<(), String> {
+    if value > 127 { Err(format!("{} {} is out of midi range 0 - 127", name, value)) } else { Ok(()) }
+}
+
+fn validate_channel(channel: u8) -> Result<(), String> {
+    if channel > 15 { Err(format!("channel {} is out of midi range 0 - 15", channel)) } else { Ok(()) }
+}
+
+impl Notes {
+    fn to_vec(&self) -> Vec<u8> {
+        match self {
+            Notes::One(note) => vec![*note],
+            Notes::Many(notes) => notes.clone(),
+        }
+    }
+}
+
+impl LineLayout {
+    // Line without buttons, for rows a device does not have
+    pub fn empty() -> Self {
+        Self { length: 0, addressing: Addressing::ChannelPerButton { note: 0 }, flipped: false }
+    }
+
+    fn flip(&self, index: u8) -> u8 {
+        if self.flipped { self.length - 1 - index } else { index }
+    }
+
+    pub fn is_control_change(&self) -> bool {
+        matches!(self.addressing, Addressing::ControlPerButton { .. })
+    }
+
+    // Get index of button from note or control change
+    pub fn index(&self, channel: u8, note: u8) -> Option<u8> {
+        let index = match self.addressing {
+            Addressing::ChannelPerButton { note: line_note } => {
+                if note == line_note { Some(channel) } else { None }
+            },
+            Addressing::NotePerButton { channel: line_channel, first_note, stride } => {
+                if channel == line_channel && note >= first_note && (note - first_note) % stride == 0 {
+                    Some((note - first_note) / stride)
+                } else {
+                    None
+                }
+            },
+            Addressing::ControlPerButton { channel: line_channel, first_control } => {
+                if channel == line_channel && note >= first_control { Some(note - first_control) } else { None }
+            },
+        };
+
+        index.filter(|index| *index < self.length).map(|index| self.flip(index))
+    }
+
+    // Make sure every button of the line has a valid address
+    fn validate(&self) -> Result<(), String> {
+        if self.length == 0 {
+            return Ok(())
+        }
+
+        let last = self.length as u32 - 1;
+
+        match self.addressing {
+            Addressing::ChannelPerButton { note } => {
+                validate_midi_value("note", note as u32)?;
+                if self.length > 16 { Err(format!("length {} needs more than 16 channels", self.length)) } else { Ok(()) }
+            },
+            Addressing::NotePerButton { channel, first_note, stride } => {
+                validate_channel(channel)?;
+                if stride == 0 { return Err("stride has to be at least 1".to_string()) }
+                validate_midi_value("last note", first_note as u32 + last * stride as u32)
+            },
+            Addressing::ControlPerButton { channel, first_control } => {
+                validate_channel(channel)?;
+                validate_midi_value("last control", first_control as u32 + last)
+            },
+        }
+    }
+
+    // Get channel & note or control change of button at index
+    pub fn address(&self, index: u8) -> (u8, u8) {
+        let index = self.flip(index);
+
+        match self.addressing {
+            Addressing::ChannelPerButton { note } => (index, note),
+            Addressing::NotePerButton { channel, first_note, stride } => (channel, first_note + index * stride),
+            Addressing::ControlPerButton { channel, first_control } => (channel, first_control + index),
+        }
+    }
+}
+
+impl GridLayout {
+    fn flip(&self, y: u8) -> u8 {
+        if self.flip_y { self.height - 1 - y } else { y }
+    }
+
+    // Get x & y of button from note
+    pub fn button(&self, channel: u8, note: u8) -> Option<(u8, u8)> {
+        let (x, row) = match self.addressing {
+            GridAddressing::ChannelPerColumn { first_note } => {
+                if note < first_note { return None }
+                (channel, note - first_note)
+            },
+            GridAddressing::NotePerButton { channel: grid_channel, first_note, row_stride } => {
+                if channel != grid_channel || note < first_note { return None }
+                ((note - first_note) % row_stride, (note - first_note) / row_stride)
+            },
+        };
+
+        if x < self.width && row < self.height { Some((x, self.flip(row))) } else { None }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        // Surface scrolls & zooms loopables in steps of 8 buttons
+        if self.width != 8 { return Err(format!("width has to be 8, got {}", self.width)) }
+        if self.height == 0 { return Err("height has to be at least 1".to_string()) }
+
+        let (width, height) = (self.width as u32, self.height as u32);
+
+        match self.addressing {
+            GridAddressing::ChannelPerColumn { first_note } => {
+                validate_midi_value("last note", first_note as u32 + height - 1)
+            },
+            GridAddressing::NotePerButton { channel, first_note, row_stride } => {
+                validate_channel(channel)?;
+                if (row_stride as u32) < width {
+                    return Err(format!("row_stride {} is smaller than width {}", row_stride, width))
+                }
+                validate_midi_value("last note", first_note as u32 + (height - 1) * row_stride as u32 + width - 1)
+            },
+        }
+    }
+
+    // Get channel & note of button at x, y
+    pub fn address(&self, x: u8, y: u8) -> (u8, u8) {
+        let row = self.flip(y);
+
+        match self.addressing {
+            GridAddressing::ChannelPerColumn { first_note } => (x, first_note + row),
+            GridAddressing::NotePerButton { channel, first_note, row_stride } => (channel, first_note + row * row_stride + x),
+        }
+    }
+}
+
+impl Palette {
+    fn validate(&self) -> Result<(), String> {
+        let colors = [self.on, self.pattern_head, self.pattern_tail, self.phrase_head, self.phrase_tail, self.timeline_head, self.timeline_tail, self.sequence];
+        colors.iter().try_for_each(|color| validate_midi_value("velocity", *color as u32))
+    }
+}
+
+impl RgbPalette {
+    fn validate(&self) -> Result<(), String> {
+        if self.velocities.is_empty() || self.tracks.is_empty() {
+            return Err("velocities & tracks need at least 1 color".to_string())
+        }
+
+        self.velocities.iter().chain(self.tracks.iter())
+            .try_for_each(|color| validate_midi_value("color", *color as u32))
+    }
+}
+
+impl ProfileFile {
+    fn validate(&self) -> Result<(), String> {
+        let in_section = |section: &str, result: Result<(), String>| result.map_err(|message| format!("[{}] {}", section, message));
+        let lines = [
+            ("side", &self.side),
+            ("track", &self.track),
+            ("indicator", &self.indicator),
+            ("activator", &self.activator),
+            ("solo", &self.solo),
+            ("arm", &self.arm),
+            ("knob_pages", &self.knob_pages),
+            ("controls.track_faders", &self.controls.track_faders),
+        ];
+
+        if let Some(device_id) = self.device_id {
+            validate_midi_value("device_id", device_id as u32)?;
+        }
+        in_section("grid", self.grid.validate())?;
+        for (section, line) in lines.iter() {
+            if let Some(line) = line {
+                in_section(section, line.validate())?;
+            }
+        }
+
+        for (name, notes) in self.buttons.iter() {
+            if ! BUTTON_NAMES.iter().any(|(button_name, _)| button_name == name) {
+                let names: Vec<&str> = BUTTON_NAMES.iter().map(|(name, _)| *name).collect();
+                return Err(format!("[buttons] unknown button \"{}\", expected one of {}", name, names.join(", ")))
+            }
+
+            in_section("buttons", notes.to_vec().into_iter().try_for_each(|note| validate_midi_value(name, note as u32)))?;
+        }
+
+        if ! self.controls.rings.is_empty() && self.controls.rings.len() != self.controls.knobs.len() {
+            return Err(format!("[controls] {} rings given for {} knobs", self.controls.rings.len(), self.controls.knobs.len()))
+        }
+
+        let controls = self.controls.knobs.iter()
+            .chain(self.controls.rings.iter())
+            .chain(self.controls.cue_knob.iter())
+            .chain(self.controls.master_fader.iter())
+            .chain(self.controls.crossfader.iter());
+        in_section("controls", controls.into_iter().try_for_each(|control| validate_midi_value("control", *control as u32)))?;
+
+        if let Some(rgb) = &self.rgb {
+            in_section("rgb", rgb.validate())?;
+        }
+
+        in_section("palette", self.palette.validate())
+    }
+}
+
+impl DeviceProfile {
+    // Parse & validate profile, source is used to tell user what file is wrong
+    pub fn parse(source: &str, contents: &str) -> Result<Self, String> {
+        let file: ProfileFile = toml::from_str(contents)
+            .map_err(|error| format!("Could not parse controller profile {}: {}", source, error))?;
+
+        file.validate()
+            .map_err(|error| format!("Invalid controller profile {}: {}", source, error))?;
+
+        let buttons = file.buttons.iter()
+            .flat_map(|(name, notes)| {
+                let (_, button_type) = BUTTON_NAMES.iter().find(|(button_name, _)| button_name == name).unwrap();
+                notes.to_vec().into_iter().map(move |note| (note, *button_type))
+            })
+            .collect();
+
+        Ok(Self {
+            name: file.name,
+            device_id: file.device_id,
+
+            grid: file.grid,
+            side: file.side,
+            track: file.track,
+            indicator: file.indicator,
+            activator: file.activator,
+            solo: file.solo,
+            arm: file.arm,
+            knob_pages: file.knob_pages,
+            buttons,
+
+            knobs: file.controls.knobs,
+            rings: file.controls.rings,
+            takeover: file.controls.takeover,
+            cue_knob: file.controls.cue_knob,
+            track_faders: file.controls.track_faders,
+            master_fader: file.controls.master_fader,
+            crossfader: file.controls.crossfader,
+
+            palette: file.palette,
+            rgb: file.rgb,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| format!("Could not read controller profile {}: {}", path.display(), error))?;
+
+        Self::parse(&path.display().to_string(), &contents)
+    }
+
+    // Get profile from profiles directory, falling back to the profiles we ship with
+    pub fn find(name: &str) -> Result<Self, String> {
+        let path = Path::new(PROFILE_DIRECTORY).join(format!("{}.toml", name));
+
+        if path.exists() {
+            Self::load(&path)
+        } else {
+            let (_, contents) = BUILTIN_PROFILES.iter()
+                .find(|(builtin, _)| *builtin == name)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = BUILTIN_PROFILES.iter().map(|(name, _)| *name).collect();
+                    format!("No controller profile named {}, add {} or use one of {}", name, path.display(), names.join(", "))
+                })?;
+
+            Self::parse(name, contents)
+        }
+    }
+
+    // Color of note with velocity, devices without rgb palette use their single color palette
+    pub fn velocity_color(&self, velocity: u8, fallback: u8) -> u8 {
+        match &self.rgb {
+            Some(rgb) => rgb.velocities[velocity.min(127) as usize * rgb.velocities.len() / 128],
+            None => fallback,
+        }
+    }
+
+    pub fn track_color(&self, track_index: usize, fallback: u8) -> u8 {
+        match &self.rgb {
+            Some(rgb) => rgb.tracks[track_index % rgb.tracks.len()],
+            None => fallback,
+        }
+    }
+
+    // Single button by note
+    pub fn button(&self, note: u8) -> Option<ButtonType> {
+        self.buttons.iter().find(|(button_note, _)| *button_note == note).map(|(_, button_type)| *button_type)
+    }
+
+    pub fn button_note(&self, button_type: ButtonType) -> Option<u8> {
+        self.buttons.iter().find(|(_, other)| *other == button_type).map(|(note, _)| *note)
+    }
+
+    // Get button from note message
+    pub fn note_button(&self, channel: u8, note: u8) -> ButtonType {
+        let line = |layout: &Option<LineLayout>| layout.filter(|layout| ! layout.is_control_change()).and_then(|layout| layout.index(channel, note));
+
+        if let Some((x, y)) = self.grid.button(channel, note) {
+            ButtonType::Grid(x, y)
+        } else if let Some(index) = line(&self.side) {
+            ButtonType::Side(index)
+        } else if let Some(index) = line(&self.track) {
+            ButtonType::Track(index)
+        } else if let Some(index) = line(&self.indicator) {
+            ButtonType::Indicator(index)
+        } else if let Some(index) = line(&self.activator) {
+            ButtonType::Activator(index)
+        } else if let Some(index) = line(&self.solo) {
+            ButtonType::Solo(index)
+        } else if let Some(index) = line(&self.arm) {
+            ButtonType::Arm(index)
+        } else if let Some(index) = line(&self.knob_pages) {
+            ButtonType::KnobPage(index)
+        } else {
+            self.button(note).unwrap_or(ButtonType::Unknown)
+        }
+    }
+
+    // Some devices send control changes for buttons
+    pub fn control_button(&self, channel: u8, control: u8) -> Option<ButtonType> {
+        let line = |layout: &Option<LineLayout>| layout.filter(|layout| layout.is_control_change()).and_then(|layout| layout.index(channel, control));
+
+        line(&self.side).map(ButtonType::Side)
+            .or_else(|| line(&self.track).map(ButtonType::Track))
+            .or_else(|| line(&self.indicator).map(ButtonType::Indicator))
+            .or_else(|| line(&self.activator).map(ButtonType::Activator))
+            .or_else(|| line(&self.solo).map(ButtonType::Solo))
+            .or_else(|| line(&self.arm).map(ButtonType::Arm))
+            .or_else(|| line(&self.knob_pages).map(ButtonType::KnobPage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_profiles() {
+        for (name, contents) in BUILTIN_PROFILES.iter() {
+            assert!(DeviceProfile::parse(name, contents).is_ok(), "{}", name);
+        }
+
+        let apc40 = DeviceProfile::parse("apc40", BUILTIN_PROFILES[0].1).unwrap();
+        assert_eq!(apc40.note_button(2, 0x35), ButtonType::Grid(2, 4));
+        assert_eq!(apc40.note_button(0, 0x56), ButtonType::Side(0));
+        assert_eq!(apc40.note_button(3, 0x33), ButtonType::Track(3));
+        assert_eq!(apc40.note_button(0, 0x62), ButtonType::Shift);
+        assert_eq!(apc40.grid.address(2, 4), (2, 0x35));
+        // Original APC's fall back to their palette
+        assert_eq!(apc40.velocity_color(127, apc40.palette.pattern_head), 1);
+        // Knobs with rings continue from their ring value
+        assert_eq!(apc40.rings.len(), apc40.knobs.len());
+        assert_eq!(apc40.takeover, Takeover::Jump);
+        assert_eq!(apc40.note_button(0, 0x59), ButtonType::KnobPage(2));
+
+        let mkii = DeviceProfile::parse("apc40_mkii", BUILTIN_PROFILES[2].1).unwrap();
+        let rgb = mkii.rgb.clone().unwrap();
+        assert_eq!(mkii.velocity_color(0, 1), rgb.velocities[0]);
+        assert_eq!(mkii.velocity_color(127, 1), *rgb.velocities.last().unwrap());
+        assert_eq!(mkii.track_color(rgb.tracks.len() + 1, 1), rgb.tracks[1]);
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let contents = BUILTIN_PROFILES[5].1;
+
+        let error = DeviceProfile::parse("test", &contents.replace("row_stride = 8", "row_stride = 4")).unwrap_err();
+        assert!(error.contains("[grid] row_stride 4 is smaller than width 8"), "{}", error);
+
+        let error = DeviceProfile::parse("test", &format!("{}\n[buttons]\nshfit = 1\n", contents)).unwrap_err();
+        assert!(error.contains("unknown button \"shfit\""), "{}", error);
+
+        let error = DeviceProfile::parse("test", &contents.replace("first_note = 36", "first_note = 100")).unwrap_err();
+        assert!(error.contains("last note 163 is out of midi range"), "{}", error);
+    }
+}
@@ -1,5 +1,9 @@
 
-use super::TickRange;
+use super::{TickRange, TimebaseHandler};
+use super::log;
+
+// Master rounds it's ticks, cycles starting this close to where last one stopped continue from there
+const TICK_TOLERANCE: u32 = 2;
 
 pub struct ProcessCycle<'a> {
     pub client: &'a jack::Client,
@@ -8,52 +12,105 @@ pub struct ProcessCycle<'a> {
     pub time_stop: u64,
     pub time_start: u64,
     pub is_rolling: bool,
+    // Another client is timebase master, we read position & tempo from transport
+    pub follows_transport: bool,
 }
 
 impl<'a> ProcessCycle<'a> {
+    // Ticks in our resolution at the tempo of transport, counted from frame 0
     pub fn frame_to_tick(pos: jack::Position, frame: u32) -> f64 {
+        if pos.frame_rate == 0 {
+            return 0.0
+        }
+
         let second = frame as f64 / pos.frame_rate as f64;
-        second / 60.0 * pos.beats_per_minute * pos.ticks_per_beat
+        second / 60.0 * pos.beats_per_minute * TimebaseHandler::TICKS_PER_BEAT
+    }
+
+    // Masters bar, beat & tick converted to our ticks, none when nobody gives us bars & beats
+    pub fn bbt_to_tick(pos: &jack::Position) -> Option<f64> {
+        let is_valid = pos.valid & jack_sys::JackPositionBBT != 0 && pos.ticks_per_beat > 0.0 && pos.bar > 0 && pos.beat > 0;
+        if ! is_valid {
+            return None
+        }
+
+        // Not every master fills in bar start, count bars of current meter then
+        let bar_start = if pos.bar_start_tick > 0.0 {
+            pos.bar_start_tick / pos.ticks_per_beat
+        } else {
+            (pos.bar - 1) as f64 * pos.beats_per_bar as f64
+        };
+        let beats = bar_start + (pos.beat - 1) as f64 + pos.tick as f64 / pos.ticks_per_beat;
+
+        Some(beats * TimebaseHandler::TICKS_PER_BEAT)
     }
 
     // Save client as we pass this cycle thing everywhere
-    pub fn new(client: &'a jack::Client, scope: &'a jack::ProcessScope) -> Self {
-        let cycle_times = scope.cycle_times().unwrap();
+    pub fn new(client: &'a jack::Client, scope: &'a jack::ProcessScope, follows_transport: bool) -> Self {
         let (state, pos) = client.transport_query();
+        let (time_start, time_stop) = match scope.cycle_times() {
+            Ok(cycle_times) => (cycle_times.current_usecs, cycle_times.next_usecs),
+            // Estimate times from frames, we'd rather be off a bit than stop playing
+            Err(e) => {
+                log::message(format_args!("Could not get cycle times: {}", e));
+                let frame = scope.last_frame_time();
+                (client.frames_to_time(frame), client.frames_to_time(frame.wrapping_add(scope.n_frames())))
+            },
+        };
+
+        // Following master, we start at it's position & move at it's tempo
+        let tick_range = match Self::bbt_to_tick(&pos).filter(|_| follows_transport) {
+            Some(start) => TickRange::new(start as u32, (start + Self::frame_to_tick(pos, scope.n_frames())) as u32),
+            None => TickRange::new(
+                Self::frame_to_tick(pos, pos.frame) as u32,
+                Self::frame_to_tick(pos, pos.frame.saturating_add(scope.n_frames())) as u32,
+            ),
+        };
 
         Self {
             client,
             scope,
-            time_start: cycle_times.current_usecs,
-            time_stop: cycle_times.next_usecs,
-            tick_range: TickRange { 
-                start: Self::frame_to_tick(pos, pos.frame) as u32,
-                stop: Self::frame_to_tick(pos, pos.frame + scope.n_frames()) as u32,
-            },
+            time_start,
+            time_stop,
+            tick_range,
             is_rolling: state == 1,
+            follows_transport,
+        }
+    }
+
+    // Start where previous cycle stopped when master rounded us a tick or 2 off, so notes don't get skipped or repeated
+    pub fn continue_from(&mut self, previous: &TickRange) {
+        let is_close = self.tick_range.start.max(previous.stop) - self.tick_range.start.min(previous.stop) <= TICK_TOLERANCE;
+
+        if is_close && previous.stop < self.tick_range.stop {
+            self.tick_range.start = previous.stop;
         }
     }
 
     pub fn usecs(&self) -> u64 {
-        self.time_stop - self.time_start
+        self.time_stop.saturating_sub(self.time_start)
     }
 
     pub fn ticks(&self) -> u32 {
-        self.tick_range.stop - self.tick_range.start
+        self.tick_range.length()
     }
 
     pub fn time_at_frame(&self, frame: u32) -> u64 {
-        // TODO - When can this error?
-        let usecs_per_frame = self.usecs() as f32 / self.scope.n_frames() as f32;
+        let usecs_per_frame = self.usecs() as f32 / self.scope.n_frames().max(1) as f32;
         let usecs_since_period_start = frame as f32 * usecs_per_frame;
         self.time_start + usecs_since_period_start as u64
     }
 
-    // TODO - This can panic, is that what we want?
+    // Ticks outside of cycle end up on it's first or last frame
     pub fn tick_to_frame(&self, tick: u32) -> u32 {
-        let tick_in_cycle = tick - self.tick_range.start;
-        let frame_in_cycle = tick_in_cycle as f64 / self.ticks() as f64 * self.scope.n_frames() as f64;
-        frame_in_cycle as u32
+        let n_frames = self.scope.n_frames();
+        if self.ticks() == 0 {
+            return 0
+        }
+
+        let tick_in_cycle = tick.saturating_sub(self.tick_range.start);
+        let frame_in_cycle = tick_in_cycle as f64 / self.ticks() as f64 * n_frames as f64;
+        (frame_in_cycle as u32).min(n_frames.saturating_sub(1))
     }
 }
 
@@ -0,0 +1,31 @@
+
+use std::fmt;
+
+/*
+ * Things that keep us from starting, main prints these & exits instead of panicking
+ */
+#[derive(Debug)]
+pub enum StartupError {
+    // Config, arguments or controller profiles
+    Config(String),
+    Client { name: String, error: jack::Error },
+    Port { name: String, error: jack::Error },
+    Activate(jack::Error),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartupError::Config(error) => write!(f, "{}", error),
+            StartupError::Client { name, error } => write!(f, "Could not create jack client {}, is jack running? ({})", name, error),
+            StartupError::Port { name, error } => write!(f, "Could not register port {}: {}", name, error),
+            StartupError::Activate(error) => write!(f, "Could not activate jack client: {}", error),
+        }
+    }
+}
+
+impl From<String> for StartupError {
+    fn from(error: String) -> Self {
+        StartupError::Config(error)
+    }
+}
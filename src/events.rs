@@ -1,6 +1,16 @@
 
+use std::iter::{Chain, Once};
+use std::option;
+use serde::{Serialize, Deserialize};
 use super::TickRange;
 
+// One or two ranges with their offsets, iterated so we don't have to allocate them on the process thread
+pub type TickRanges = Chain<Once<(TickRange, u32)>, option::IntoIter<(TickRange, u32)>>;
+
+pub fn tick_ranges(first: (TickRange, u32), second: Option<(TickRange, u32)>) -> TickRanges {
+    std::iter::once(first).chain(second)
+}
+
 // All the things we can show in grid
 pub trait LoopableEvent: Clone + std::fmt::Debug {
     fn start(&self) -> u32;
@@ -9,7 +19,8 @@ pub trait LoopableEvent: Clone + std::fmt::Debug {
     fn set_start(&mut self, start: u32);
     fn is_on_row(&self, index: u8) -> bool;
     fn is_on_same_row(&self, other: &Self) -> bool;
-    fn row(&self, offset: u8) -> u8;
+    // Row of event on grid starting at offset, none when event is below it
+    fn row(&self, offset: u8) -> Option<u8>;
 
     fn is_looping(&self) -> bool {
         match self.stop() {
@@ -106,7 +117,7 @@ pub trait LoopableEvent: Clone + std::fmt::Debug {
 }
 
 // note, velocity
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct LoopableNoteEvent {
     pub note: u8,
     pub start: u32,
@@ -122,7 +133,7 @@ impl LoopableEvent for LoopableNoteEvent {
     fn set_stop(&mut self, tick: u32) { self.stop = Some(tick) }
     fn is_on_row(&self, index: u8) -> bool { self.note == index }
     fn is_on_same_row(&self, other: &Self) -> bool { self.note == other.note }
-    fn row(&self, offset: u8) -> u8 { self.note - offset }
+    fn row(&self, offset: u8) -> Option<u8> { self.note.checked_sub(offset) }
 }
 
 impl LoopableNoteEvent {
@@ -141,7 +152,7 @@ impl LoopableNoteEvent {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct LoopablePatternEvent {
     pub start: u32,
     pub stop: Option<u32>,
@@ -155,7 +166,7 @@ impl LoopableEvent for LoopablePatternEvent {
     fn set_stop(&mut self, tick: u32) { self.stop = Some(tick) }
     fn is_on_row(&self, index: u8) -> bool { self.pattern == index }
     fn is_on_same_row(&self, other: &Self) -> bool { self.pattern == other.pattern }
-    fn row(&self, offset: u8) -> u8 { self.pattern - offset }
+    fn row(&self, offset: u8) -> Option<u8> { self.pattern.checked_sub(offset) }
 }
 
 impl LoopablePatternEvent {
@@ -163,20 +174,20 @@ impl LoopablePatternEvent {
         LoopablePatternEvent { start, stop: None, pattern, }
     }
 
-    pub fn absolute_tick_ranges(&self, phrase_length: u32) -> Vec<(TickRange, u32)> {
+    pub fn absolute_tick_ranges(&self, phrase_length: u32) -> TickRanges {
         if self.is_looping() {
-            let offset = phrase_length - self.start();
-            vec![
+            let offset = phrase_length.saturating_sub(self.start());
+            tick_ranges(
                 (TickRange::new(0, self.stop().unwrap()), offset), 
-                (TickRange::new(self.start(), phrase_length), 0)
-            ]
+                Some((TickRange::new(self.start(), phrase_length), 0))
+            )
         } else {
-            vec![(TickRange::new(self.start(), self.stop().unwrap()), 0)]
+            tick_ranges((TickRange::new(self.start(), self.stop().unwrap()), 0), None)
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct LoopablePhraseEvent {
     pub start: u32,
     pub stop: Option<u32>,
@@ -190,7 +201,7 @@ impl LoopableEvent for LoopablePhraseEvent {
     fn set_stop(&mut self, tick: u32) { self.stop = Some(tick) }
     fn is_on_row(&self, index: u8) -> bool { self.phrase == index }
     fn is_on_same_row(&self, other: &Self) -> bool { self.phrase == other.phrase }
-    fn row(&self, offset: u8) -> u8 { self.phrase - offset }
+    fn row(&self, offset: u8) -> Option<u8> { self.phrase.checked_sub(offset) }
 }
 
 impl LoopablePhraseEvent {
@@ -0,0 +1,356 @@
+
+use super::loopable::*;
+use super::events::LoopablePhraseEvent;
+use super::sequence::Sequence;
+use super::track::Track;
+use super::sequencer::Sequencer;
+
+// What an edit changed, tracks & loopables by index
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditTarget {
+    Pattern(usize, u8),
+    Phrase(usize, u8),
+    Timeline(usize),
+    Sequence(usize),
+}
+
+/*
+ * Copy of edit target before it got edited, only the loopable or sequence of target is used
+ */
+struct Snapshot {
+    // Snapshots recorded in the same step are undone together
+    step: u32,
+    target: EditTarget,
+
+    pattern: Pattern,
+    phrase: Phrase,
+    timeline: Timeline,
+    sequence: Sequence,
+}
+
+impl Snapshot {
+    fn new() -> Self {
+        let mut snapshot = Snapshot {
+            step: 0,
+            target: EditTarget::Sequence(0),
+
+            pattern: Pattern::new(),
+            phrase: Phrase::new(),
+            timeline: Timeline::new(),
+            sequence: Sequence::new(0, 0),
+        };
+
+        snapshot.pattern.note_events.reserve(History::EVENT_CAPACITY);
+        snapshot.phrase.pattern_events.reserve(History::EVENT_CAPACITY);
+        snapshot.timeline.phrase_events.reserve(History::EVENT_CAPACITY);
+        snapshot.sequence.reserve(Sequencer::TRACK_CAPACITY);
+        snapshot
+    }
+
+    // Copy target into snapshot, copying reuses memory of snapshot
+    fn copy_from(&mut self, target: EditTarget, tracks: &[Track], sequences: &[Sequence]) {
+        self.target = target;
+
+        match target {
+            EditTarget::Pattern(track, index) => self.pattern.copy_from(tracks[track].pattern(index)),
+            EditTarget::Phrase(track, index) => self.phrase.copy_from(tracks[track].phrase(index)),
+            EditTarget::Timeline(track) => self.timeline.copy_from(&tracks[track].timeline),
+            EditTarget::Sequence(index) => self.sequence.copy_from(&sequences[index]),
+        }
+    }
+
+    fn is_unchanged(&self, tracks: &[Track], sequences: &[Sequence]) -> bool {
+        match self.target {
+            EditTarget::Pattern(track, index) => *tracks[track].pattern(index) == self.pattern,
+            EditTarget::Phrase(track, index) => *tracks[track].phrase(index) == self.phrase,
+            EditTarget::Timeline(track) => tracks[track].timeline == self.timeline,
+            EditTarget::Sequence(index) => sequences[index] == self.sequence,
+        }
+    }
+
+    fn copy_to(&self, tracks: &mut [Track], sequences: &mut [Sequence]) {
+        match self.target {
+            EditTarget::Pattern(track, index) => tracks[track].pattern_mut(index).copy_from(&self.pattern),
+            EditTarget::Phrase(track, index) => tracks[track].phrase_mut(index).copy_from(&self.phrase),
+            EditTarget::Timeline(track) => tracks[track].timeline.copy_from(&self.timeline),
+            EditTarget::Sequence(index) => sequences[index].copy_from(&self.sequence),
+        }
+    }
+}
+
+/*
+ * Undo & redo history of edits. Snapshots are allocated up front & copied into, so recording edits
+ * on the process thread does not allocate unless an edited loopable grows beyond the capacity
+ * snapshots start out with
+ */
+pub struct History {
+    // Ring of snapshots, oldest snapshots are overwritten when history is full
+    snapshots: Vec<Snapshot>,
+    // Used to swap snapshot with current state when undoing & redoing
+    scratch: Snapshot,
+    // Target remembered before an edit, it's only recorded once we know the edit changed it
+    pending: Snapshot,
+    is_pending: bool,
+
+    // Index of snapshot next edit is recorded in
+    head: usize,
+    undoable: usize,
+    redoable: usize,
+    step: u32,
+}
+
+impl History {
+    const SIZE: usize = 64;
+    const EVENT_CAPACITY: usize = 256;
+
+    pub fn new() -> Self {
+        History {
+            snapshots: (0 .. Self::SIZE).map(|_| Snapshot::new()).collect(),
+            scratch: Snapshot::new(),
+            pending: Snapshot::new(),
+            is_pending: false,
+
+            head: 0,
+            undoable: 0,
+            redoable: 0,
+            step: 0,
+        }
+    }
+
+    /*
+     * Remember target before it's edited. Controllers remember targets for presses that turn out
+     * to change nothing, so target is recorded as a step of it's own once we know it changed, that
+     * way those presses don't push out history or drop edits we could redo
+     */
+    pub fn remember(&mut self, target: EditTarget, tracks: &[Track], sequences: &[Sequence]) {
+        self.commit(tracks, sequences);
+        self.pending.copy_from(target, tracks, sequences);
+        self.is_pending = true;
+    }
+
+    // Record remembered target when it got edited since
+    fn commit(&mut self, tracks: &[Track], sequences: &[Sequence]) {
+        if ! self.is_pending {
+            return
+        }
+
+        self.is_pending = false;
+        if self.pending.is_unchanged(tracks, sequences) {
+            return
+        }
+
+        self.step = self.step.wrapping_add(1);
+        let evicted = self.evicted_step();
+        // Swapping keeps memory of snapshots around for next time
+        std::mem::swap(&mut self.snapshots[self.head], &mut self.pending);
+        self.snapshots[self.head].step = self.step;
+        self.advance(evicted);
+    }
+
+    /*
+     * Remember targets before they're edited, targets are undone together. A step with more targets
+     * than history has snapshots would overwrite itself, those are refused & clear history as the
+     * edit can't be undone. Returns wether step was recorded
+     */
+    pub fn record_step(&mut self, targets: impl Iterator<Item = EditTarget> + Clone, tracks: &[Track], sequences: &[Sequence]) -> bool {
+        self.commit(tracks, sequences);
+
+        if targets.clone().count() > Self::SIZE {
+            self.clear();
+            return false
+        }
+
+        self.step = self.step.wrapping_add(1);
+        targets.for_each(|target| self.record(target, tracks, sequences));
+        true
+    }
+
+    // Remember target before it's edited, this drops edits we could redo
+    fn record(&mut self, target: EditTarget, tracks: &[Track], sequences: &[Sequence]) {
+        let evicted = self.evicted_step();
+        let snapshot = &mut self.snapshots[self.head];
+        snapshot.step = self.step;
+        snapshot.copy_from(target, tracks, sequences);
+
+        self.advance(evicted);
+    }
+
+    // Step of the oldest snapshot when history is full, as the next snapshot overwrites it
+    fn evicted_step(&self) -> Option<u32> {
+        if self.undoable == Self::SIZE { Some(self.snapshots[self.head].step) } else { None }
+    }
+
+    // Move on to next snapshot, snapshots of a step that lost a snapshot to the new one can't be
+    // undone anymore, undoing them would only undo part of the step
+    fn advance(&mut self, evicted: Option<u32>) {
+        self.head = (self.head + 1) % Self::SIZE;
+        self.undoable = (self.undoable + 1).min(Self::SIZE);
+        self.redoable = 0;
+
+        if let Some(evicted) = evicted {
+            while self.undoable > 0 && self.snapshots[(self.head + Self::SIZE - self.undoable) % Self::SIZE].step == evicted {
+                self.undoable -= 1;
+            }
+        }
+    }
+
+    // Phrases queued by playback aren't edits, they're added to snapshots of the timeline aswell so
+    // undoing or redoing an edit doesn't drop them
+    pub fn add_phrase_event(&mut self, track_index: usize, event: LoopablePhraseEvent) {
+        if self.is_pending && self.pending.target == EditTarget::Timeline(track_index) {
+            self.pending.timeline.add_complete_event(event);
+        }
+
+        let oldest = self.head + Self::SIZE - self.undoable;
+
+        for offset in 0 .. self.undoable + self.redoable {
+            let snapshot = &mut self.snapshots[(oldest + offset) % Self::SIZE];
+
+            if snapshot.target == EditTarget::Timeline(track_index) {
+                snapshot.timeline.add_complete_event(event);
+            }
+        }
+    }
+
+    // Indexes no longer point to the same tracks when tracks are added or removed
+    pub fn clear(&mut self) {
+        self.is_pending = false;
+        self.undoable = 0;
+        self.redoable = 0;
+    }
+
+    // Swap snapshot with what it is a snapshot of, so it can be swapped back on redo / undo
+    fn swap(&mut self, index: usize, tracks: &mut [Track], sequences: &mut [Sequence]) {
+        let (step, target) = (self.snapshots[index].step, self.snapshots[index].target);
+
+        self.scratch.copy_from(target, tracks, sequences);
+        self.snapshots[index].copy_to(tracks, sequences);
+        // Swapping keeps memory of snapshots around for next time
+        std::mem::swap(&mut self.snapshots[index], &mut self.scratch);
+        self.snapshots[index].step = step;
+    }
+
+    // Undo last step, returns wether there was something to undo
+    pub fn undo(&mut self, tracks: &mut [Track], sequences: &mut [Sequence]) -> bool {
+        self.commit(tracks, sequences);
+
+        if self.undoable == 0 {
+            return false
+        }
+
+        let step = self.snapshots[(self.head + Self::SIZE - 1) % Self::SIZE].step;
+
+        while self.undoable > 0 && self.snapshots[(self.head + Self::SIZE - 1) % Self::SIZE].step == step {
+            self.head = (self.head + Self::SIZE - 1) % Self::SIZE;
+            self.swap(self.head, tracks, sequences);
+            self.undoable -= 1;
+            self.redoable += 1;
+        }
+
+        true
+    }
+
+    pub fn redo(&mut self, tracks: &mut [Track], sequences: &mut [Sequence]) -> bool {
+        self.commit(tracks, sequences);
+
+        if self.redoable == 0 {
+            return false
+        }
+
+        let step = self.snapshots[self.head].step;
+
+        while self.redoable > 0 && self.snapshots[self.head].step == step {
+            self.swap(self.head, tracks, sequences);
+            self.head = (self.head + 1) % Self::SIZE;
+            self.redoable -= 1;
+            self.undoable += 1;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequences() -> Vec<Sequence> {
+        (0 .. 5).map(|phrase| Sequence::new(phrase, 2)).collect()
+    }
+
+    #[test]
+    fn step_bounds() {
+        let mut history = History::new();
+        let mut sequences = sequences();
+
+        assert!(history.record_step(std::iter::once(EditTarget::Sequence(0)), &[], &sequences));
+        sequences[0].set_phrase(0, 3);
+
+        // Step that would overwrite itself can't be undone & clears what we could undo
+        let targets = std::iter::repeat(EditTarget::Sequence(1)).take(History::SIZE + 1);
+        assert!(! history.record_step(targets, &[], &sequences));
+        assert!(! history.undo(&mut [], &mut sequences));
+
+        // Biggest step that fits is undone in one go
+        assert!(history.record_step(std::iter::once(EditTarget::Sequence(0)), &[], &sequences));
+        let targets = (0 .. History::SIZE).map(|index| EditTarget::Sequence(index % 5));
+        assert!(history.record_step(targets, &[], &sequences));
+        sequences[1].set_phrase(1, 4);
+        assert!(history.undo(&mut [], &mut sequences));
+        assert_eq!(sequences[1].get_phrase(1), Some(1));
+        assert!(! history.undo(&mut [], &mut sequences));
+    }
+
+    #[test]
+    fn evicted_steps() {
+        let mut history = History::new();
+        let mut sequences = sequences();
+
+        // Step that lost a snapshot to newer steps can't be undone at all
+        history.record_step([EditTarget::Sequence(0), EditTarget::Sequence(1)].iter().copied(), &[], &sequences);
+        for _ in 0 .. History::SIZE - 1 {
+            history.record_step(std::iter::once(EditTarget::Sequence(2)), &[], &sequences);
+        }
+
+        for _ in 0 .. History::SIZE - 1 {
+            assert!(history.undo(&mut [], &mut sequences));
+        }
+        assert!(! history.undo(&mut [], &mut sequences));
+    }
+
+    #[test]
+    fn unchanged_targets() {
+        let mut history = History::new();
+        let mut sequences = sequences();
+
+        history.remember(EditTarget::Sequence(0), &[], &sequences);
+        sequences[0].set_phrase(0, 3);
+        assert!(history.undo(&mut [], &mut sequences));
+        assert_eq!(sequences[0].get_phrase(0), Some(0));
+
+        // Presses that change nothing aren't recorded & leave what we can redo alone
+        history.remember(EditTarget::Sequence(1), &[], &sequences);
+        assert!(history.redo(&mut [], &mut sequences));
+        assert_eq!(sequences[0].get_phrase(0), Some(3));
+        assert!(history.undo(&mut [], &mut sequences));
+        assert!(! history.undo(&mut [], &mut sequences));
+    }
+
+    #[test]
+    fn queued_phrases() {
+        let mut history = History::new();
+        history.snapshots[0].target = EditTarget::Timeline(1);
+        history.snapshots[1].target = EditTarget::Timeline(0);
+        history.head = 1;
+        history.undoable = 1;
+        history.redoable = 1;
+
+        history.add_phrase_event(1, LoopablePhraseEvent::new(0, 96, 2));
+        history.add_phrase_event(0, LoopablePhraseEvent::new(96, 192, 3));
+
+        // Both undoable & redoable snapshots of timeline get phrases playback queued
+        assert_eq!(history.snapshots[0].timeline.phrase_events.len(), 1);
+        assert_eq!(history.snapshots[1].timeline.phrase_events.len(), 1);
+        assert_eq!(history.snapshots[2].timeline.phrase_events.len(), 0);
+    }
+}
@@ -0,0 +1,98 @@
+
+use std::fmt;
+use std::fmt::Write;
+use std::str;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+use crossbeam_queue::ArrayQueue;
+
+/*
+ * Printing from the process thread could block on stdout, so messages are queued in a lock-free
+ * queue that is emptied by a logging thread. The queue & it's messages are allocated up front,
+ * so logging does not allocate either
+ */
+static QUEUE: OnceLock<ArrayQueue<LogMessage>> = OnceLock::new();
+
+// Messages that don't fit the queue are dropped, as we can't wait for the logging thread
+const QUEUE_SIZE: usize = 256;
+const PRINT_INTERVAL_MILLIS: u64 = 50;
+
+// Message formatted into a fixed size buffer, longer messages are cut off
+pub struct LogMessage {
+    bytes: [u8; LogMessage::SIZE],
+    length: usize,
+}
+
+impl LogMessage {
+    const SIZE: usize = 128;
+
+    fn new() -> Self {
+        LogMessage { bytes: [0; Self::SIZE], length: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are written, so this is always valid
+        str::from_utf8(&self.bytes[.. self.length]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for LogMessage {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for character in text.chars() {
+            let length = character.len_utf8();
+
+            if self.length + length > Self::SIZE {
+                return Err(fmt::Error)
+            }
+
+            character.encode_utf8(&mut self.bytes[self.length ..]);
+            self.length += length;
+        }
+
+        Ok(())
+    }
+}
+
+// Create queue & start thread that prints queued messages
+pub fn start() {
+    let queue = QUEUE.get_or_init(|| ArrayQueue::new(QUEUE_SIZE));
+
+    thread::spawn(move || {
+        loop {
+            while let Some(message) = queue.pop() {
+                println!("{}", message.as_str());
+            }
+
+            thread::sleep(Duration::from_millis(PRINT_INTERVAL_MILLIS));
+        }
+    });
+}
+
+// Queue message for logging thread, use with format_args!
+pub fn message(args: fmt::Arguments) {
+    let mut message = LogMessage::new();
+    // Cut off messages are still worth printing
+    message.write_fmt(args).ok();
+
+    match QUEUE.get() {
+        Some(queue) => { queue.push(message).ok(); },
+        // No logging thread (yet), we're not on the process thread either
+        None => println!("{}", message.as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cut_off() {
+        let mut message = LogMessage::new();
+        let text = "é".repeat(LogMessage::SIZE);
+
+        assert!(message.write_fmt(format_args!("{}", text)).is_err());
+        assert_eq!(message.as_str().len(), LogMessage::SIZE);
+        assert_eq!(message.as_str(), &text[.. LogMessage::SIZE]);
+    }
+}
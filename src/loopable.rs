@@ -1,4 +1,5 @@
 
+use serde::{Serialize, Deserialize};
 use super::TickRange;
 use super::events::*;
 use super::TimebaseHandler;
@@ -42,12 +43,16 @@ pub trait Loopable {
         });
 
         // Resize events around new event, add new event when previous event is split by current event
-        let mut split_events: Vec<Self::Event> = self.events_mut().iter_mut()
-            .filter(|other| other.is_on_same_row(&event))
-            .filter_map(|other| other.resize_to_fit(&event, length))
-            .collect();
+        for index in 0 .. self.events().len() {
+            let other = &mut self.events_mut()[index];
+
+            if other.is_on_same_row(&event) {
+                if let Some(split_event) = other.resize_to_fit(&event, length) {
+                    self.events_mut().push(split_event);
+                }
+            }
+        }
 
-        self.events_mut().append(&mut split_events);
         self.events_mut().push(event);
     }
 
@@ -58,18 +63,13 @@ pub trait Loopable {
     }
 
     fn remove_events_starting_in(&mut self, range: TickRange, index: u8) {
-        let indexes: Vec<usize> = self.events_mut().iter().enumerate()
-            .filter(|(_, event)| event.is_on_row(index) && range.contains(event.start()))
-            .map(|(index, _)| index)
-            .collect();
-
-        indexes.into_iter().for_each(|index| { self.events_mut().remove(index); () });
+        self.events_mut().retain(|event| ! event.is_on_row(index) || ! range.contains(event.start()));
     }
 
     /*
      * We want to loop phrases/patterns that are shorter as container phrase / pattern_event
      */
-    fn looping_ranges(&self, range: &TickRange) -> Vec<(TickRange, u32)> {
+    fn looping_ranges(&self, range: &TickRange) -> TickRanges {
         let iteration = range.start / self.length();
         let start = range.start % self.length();
 
@@ -80,17 +80,17 @@ pub trait Loopable {
         }
 
         if start > stop {
-            vec![
+            tick_ranges(
                 (TickRange::new(start, self.length()), iteration * self.length()), 
-                (TickRange::new(0, stop), (iteration + 1) * self.length())
-            ]
+                Some((TickRange::new(0, stop), (iteration + 1) * self.length()))
+            )
         } else {
-            vec![(TickRange::new(start, stop), iteration * self.length())]
+            tick_ranges((TickRange::new(start, stop), iteration * self.length()), None)
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Timeline {
     pub phrase_events: Vec<LoopablePhraseEvent>,
 }
@@ -121,6 +121,11 @@ impl Timeline {
         Timeline { phrase_events: vec![] }
     }
 
+    // Copy other timeline, reusing memory we have
+    pub fn copy_from(&mut self, other: &Self) {
+        self.phrase_events.clone_from(&other.phrase_events);
+    }
+
     pub fn get_last_stop(&self) -> u32 {
         self.events().iter().filter(|event| event.stop.is_some()).map(|event| event.stop.unwrap()).max()
             .or_else(|| Some(0))
@@ -128,7 +133,7 @@ impl Timeline {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Phrase {
     // Length in ticks
     length: u32,
@@ -150,6 +155,12 @@ impl Phrase {
 
     // Default phrase length = 4 bars
     pub fn default_length() -> u32 { TimebaseHandler::TICKS_PER_BEAT as u32 * 4 * 4 }
+
+    pub fn copy_from(&mut self, other: &Self) {
+        self.length = other.length;
+        self.pattern_events.clone_from(&other.pattern_events);
+    }
+
     pub fn set_length(&mut self, length: u32) { 
         self.length = length; 
 
@@ -169,7 +180,7 @@ impl Phrase {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Pattern {
     pub note_events: Vec<LoopableNoteEvent>,
     pub length: Option<u32>,
@@ -206,6 +217,11 @@ impl Pattern {
         Pattern { note_events: vec![], length: None }
     }
 
+    pub fn copy_from(&mut self, other: &Self) {
+        self.length = other.length;
+        self.note_events.clone_from(&other.note_events);
+    }
+
     pub fn has_explicit_length(&self) -> bool {
         self.length.is_some()
     }
@@ -219,13 +235,13 @@ impl Pattern {
     }
 
     pub fn starting_notes(&self, absolute_start: u32, relative_range: TickRange, pattern_event_length: u32) 
-        -> Vec<PlayingNoteEvent> 
+        -> impl Iterator<Item = PlayingNoteEvent> + '_
     {
         // Get looping ranges when pattern is a looping pattern
-        let ranges = if ! self.has_explicit_length() { vec![(relative_range, 0)] } else { self.looping_ranges(&relative_range) };
+        let ranges = if ! self.has_explicit_length() { tick_ranges((relative_range, 0), None) } else { self.looping_ranges(&relative_range) };
 
-        ranges.iter()
-            .flat_map(|(range, offset)| {
+        ranges
+            .flat_map(move |(range, offset)| {
                 self.note_events.iter()
                     .filter(move |note_event| {
                         range.contains(note_event.start())
@@ -246,7 +262,6 @@ impl Pattern {
                         }
                     })
             })
-            .collect()
     }
 }
 
@@ -277,4 +292,14 @@ mod tests {
         pattern.add_complete_event(event);
         assert_eq!(pattern.length(), length * 4);
     }
+
+    #[test]
+    fn remove_events() {
+        let mut pattern = Pattern::new();
+        pattern.note_events = vec![LoopableNoteEvent::new(0, 1, 1), LoopableNoteEvent::new(10, 1, 1), LoopableNoteEvent::new(20, 1, 1), LoopableNoteEvent::new(5, 2, 1)];
+
+        pattern.remove_events_starting_in(TickRange::new(0, 15), 1);
+        let starts: Vec<(u32, u8)> = pattern.note_events.iter().map(|event| (event.start, event.note)).collect();
+        assert_eq!(starts, vec![(20, 1), (5, 2)]);
+    }
 }
@@ -18,16 +18,49 @@ pub mod port;
 pub mod mixer;
 pub mod events;
 pub mod instrument;
-
-use std::io;
-use std::sync::mpsc::channel;
-use std::sync::mpsc::{Sender, Receiver};
+pub mod project;
+pub mod scroller;
+pub mod history;
+pub mod bus;
+pub mod tui;
+pub mod osc;
+pub mod repl;
+pub mod config;
+pub mod patchbay;
+pub mod error;
+pub mod log;
+#[cfg(feature = "alloc-debug")]
+pub mod allocator;
+
+use std::process;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::thread;
+use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::mpsc::{Sender, SyncSender, Receiver};
 use sequencer::Sequencer;
+use track::Track;
+use port::PortRequest;
+use project::{Project, ProjectRequest, ProjectRoom};
+use bus::*;
 use controller::*;
 use mixer::*;
 use surface::Surface;
+use repl::Repl;
+use osc::OscServer;
+use config::{Args, Config, PortNames, TimebaseMode};
+use patchbay::Patchbay;
+use error::StartupError;
+use clap::Parser;
 use cycle::*;
 
+// Counts allocations in the process callback
+#[cfg(feature = "alloc-debug")]
+#[global_allocator]
+static ALLOCATOR: allocator::CountingAllocator = allocator::CountingAllocator;
+
 #[derive(Copy, Clone, Debug)]
 pub struct TickRange {
     pub start: u32,
@@ -41,15 +74,15 @@ impl TickRange {
 
     pub fn plus(&self, delta: u32) -> Self {
         Self {
-            start: self.start + delta,
-            stop: self.stop + delta,
+            start: self.start.saturating_add(delta),
+            stop: self.stop.saturating_add(delta),
         }
     }
 
     pub fn minus(&self, delta: u32) -> Self {
         Self {
-            start: self.start - delta,
-            stop: self.stop - delta,
+            start: self.start.saturating_sub(delta),
+            stop: self.stop.saturating_sub(delta),
         }
     }
 
@@ -62,7 +95,7 @@ impl TickRange {
     }
 
     pub fn length(&self) -> u32 {
-        self.stop - self.start
+        self.stop.saturating_sub(self.start)
     }
 }
 
@@ -72,124 +105,580 @@ pub struct TimebaseHandler {
     beat_type: f32,
     is_up_to_date: bool,
 
-    //receiver: Receiver<f64>,
+    // Tempo set by tap tempo
+    receiver: Receiver<f64>,
 }
 
 impl TimebaseHandler {
     pub const TICKS_PER_BEAT: f64 = 1920.0;
 
-    pub fn new(_: Receiver<f64>) -> Self {
+    pub fn new(receiver: Receiver<f64>, beats_per_minute: f64) -> Self {
         TimebaseHandler {
-            beats_per_minute: 137.0,
+            beats_per_minute,
             is_up_to_date: false,
             beats_per_bar: 4.0,
             beat_type: 4.0,
-            //receiver,
+            receiver,
+        }
+    }
+
+    /*
+     * Register as timebase master, a conditional master only takes over when there's no master
+     * yet. Returns wether we became master, handler has to stay put & outlive client as jack calls it
+     */
+    pub fn register(handler: &mut TimebaseHandler, client: &jack::Client, is_conditional: bool) -> bool {
+        let arg = handler as *mut TimebaseHandler as *mut c_void;
+        unsafe { j::jack_set_timebase_callback(client.raw(), is_conditional as c_int, Some(timebase_callback), arg) == 0 }
+    }
+
+    fn timebase(&mut self, pos: &mut jack::Position, is_new_pos: bool) {
+        // Set position type
+        pos.valid = j::JackPositionBBT;
+
+        // Tempo was tapped
+        while let Ok(beats_per_minute) = self.receiver.try_recv() {
+            self.beats_per_minute = beats_per_minute;
+            self.is_up_to_date = false;
+        }
+
+        // Only update timebase when we are asked for it, or when our state changed
+        if is_new_pos || ! self.is_up_to_date {
+            pos.beats_per_bar = self.beats_per_bar;
+            pos.ticks_per_beat = Self::TICKS_PER_BEAT;
+            pos.beat_type = self.beat_type;
+            pos.beats_per_minute = self.beats_per_minute;
+
+            self.is_up_to_date = true;
         }
+
+        let abs_tick = ProcessCycle::frame_to_tick(*pos, pos.frame);
+        let abs_beat = (abs_tick / Self::TICKS_PER_BEAT).floor();
+        let abs_bar = (abs_beat / pos.beats_per_bar as f64).floor();
+
+        // Plus 1 as humans tend not to count from 0
+        pos.bar = abs_bar as i32 + 1;
+        pos.beat = (abs_beat - abs_bar * pos.beats_per_bar as f64) as i32 + 1;
+        pos.bar_start_tick = abs_bar * pos.beats_per_bar as f64 * Self::TICKS_PER_BEAT;
+        pos.tick = (abs_tick - abs_beat * Self::TICKS_PER_BEAT) as i32;
     }
 }
 
-impl jack::TimebaseHandler for TimebaseHandler {
-    fn timebase(&mut self, _: &jack::Client, _state: jack::TransportState, _n_frames: jack::Frames, pos: *mut jack::Position, is_new_pos: bool) {
-        unsafe {
-            // Set position type
-            (*pos).valid = j::JackPositionBBT;
-
-            // BPM changed?
-            //if ! is_new_pos && (*pos).beats_per_minute != self.beats_per_minute {
-                //println!("{:?}", (*pos).beats_per_minute);
-            //}
-
-            // Only update timebase when we are asked for it, or when our state changed
-            if is_new_pos || ! self.is_up_to_date {
-                (*pos).beats_per_bar = self.beats_per_bar;
-                (*pos).ticks_per_beat = Self::TICKS_PER_BEAT;
-                (*pos).beat_type = self.beat_type;
-                (*pos).beats_per_minute = self.beats_per_minute;
-
-                self.is_up_to_date = true;
-            }
+/*
+ * We register the timebase callback ourselves instead of handing it to the jack crate, as that
+ * would always make us master, kicking out the master that was there before us
+ */
+unsafe extern "C" fn timebase_callback(_state: j::jack_transport_state_t, _n_frames: j::jack_nframes_t, pos: *mut j::jack_position_t, new_pos: c_int, arg: *mut c_void) {
+    let handler = &mut *(arg as *mut TimebaseHandler);
+    handler.timebase(&mut *pos, new_pos != 0);
+}
+
+/*
+ * Tells controllers when their ports get connected, so they can identify & redraw a replugged
+ * controller
+ */
+pub struct NotificationHandler {
+    connections: Vec<Connection>,
+    // Main thread connects ports that show up, we're not allowed to
+    registered_ports: Sender<String>,
+}
 
-            let abs_tick = ProcessCycle::frame_to_tick(*pos, (*pos).frame);
-            let abs_beat = abs_tick / (*pos).ticks_per_beat;
+impl NotificationHandler {
+    pub fn new(connections: Vec<Connection>, registered_ports: Sender<String>) -> Self {
+        NotificationHandler { connections, registered_ports }
+    }
+}
+
+impl jack::NotificationHandler for NotificationHandler {
+    fn port_registration(&mut self, client: &jack::Client, port_id: jack::PortId, is_registered: bool) {
+        if let Some(name) = client.port_by_id(port_id).filter(|_| is_registered).and_then(|port| port.name().ok()) {
+            self.registered_ports.send(name).ok();
+        }
+    }
 
-            // Plus 1 as humans tend not to count from 0
-            (*pos).bar = (abs_beat / (*pos).beats_per_bar as f64) as i32 + 1;
-            (*pos).beat = (abs_beat % (*pos).beats_per_bar as f64) as i32 + 1;
-            (*pos).bar_start_tick = (abs_beat as i32 * (*pos).ticks_per_beat as i32) as f64;
-            (*pos).tick = abs_tick as i32 - (*pos).bar_start_tick as i32;
+    fn ports_connected(&mut self, client: &jack::Client, port_id_a: jack::PortId, port_id_b: jack::PortId, are_connected: bool) {
+        if ! are_connected {
+            return
         }
+
+        let names: Vec<String> = [port_id_a, port_id_b].iter()
+            .filter_map(|id| client.port_by_id(*id))
+            .filter_map(|port| port.name().ok())
+            .collect();
+
+        self.connections.iter()
+            .filter(|connection| connection.port_names.iter().any(|name| names.contains(name)))
+            .for_each(|connection| connection.reconnected.store(true, Ordering::Relaxed));
     }
 }
 
 
 pub struct ProcessHandler {
-    // Controllers
-    apc20: APC20,
-    apc40: APC40,
+    // Controllers, each editing a role on it's own tracks
+    controllers: Vec<Controller>,
 
     mixer: Mixer,
     sequencer: Sequencer,
     surface: Surface,
+
+    // Tracks built by main thread on request of sequencer, none when port could not be registered.
+    // Main thread prints errors, so we don't free their text
+    registered_tracks: Receiver<Option<Track>>,
+    // Main thread makes room in projects, saves & frees them for us and tells us how saving went
+    project_requests: SyncSender<ProjectRequest>,
+    save_results: Receiver<bool>,
+    // Project with room for the session, so we can copy session without allocating
+    spare_projects: Receiver<Project>,
+    spare_project: Option<Project>,
+    room_requested: bool,
+    // Project that is loaded once we have as many tracks as it has
+    project_loading: Option<Project>,
+    // Frontends that asked for a snapshot we could not make yet
+    snapshots_requested: Vec<bool>,
+
+    // Commands from & events to frontends running on other threads
+    bus: Bus,
+
+    // Another client is timebase master, we follow it's transport
+    follows_transport: bool,
+    previous_tick_range: TickRange,
 }
 
 impl ProcessHandler {
     pub fn new(
-        _timebase_sender: Sender<f64>,
+        timebase_sender: SyncSender<f64>,
+        port_requests: SyncSender<PortRequest>,
+        registered_tracks: Receiver<Option<Track>>,
+        project_requests: SyncSender<ProjectRequest>,
+        save_results: Receiver<bool>,
+        spare_projects: Receiver<Project>,
+        bus: Bus,
+        follows_transport: bool,
+        track_count: usize,
+        ports: &PortNames,
+        velocity_curve: Option<VelocityCurve>,
+        setups: &[ControllerSetup],
         client: &jack::Client
-    ) -> Self {
-        ProcessHandler { 
-            apc20: APC20::new(client),
-            apc40: APC40::new(client),
+    ) -> Result<Self, StartupError> {
+        Ok(ProcessHandler { 
+            controllers: setups.iter().map(|setup| Controller::new(client, setup)).collect::<Result<_, _>>()?,
+
+            mixer: Mixer::new(client, track_count, &ports.mixer, velocity_curve)?,
+            sequencer: Sequencer::new(client, track_count, &ports.track, port_requests, timebase_sender)?,
+            surface: Surface::new(track_count, ControllerSetup::track_bank_size(setups)),
+
+            registered_tracks,
+            project_requests,
+            save_results,
+            spare_projects,
+            spare_project: None,
+            room_requested: false,
+            project_loading: None,
+            snapshots_requested: vec![false; bus.frontend_count()],
+
+            bus,
+
+            follows_transport,
+            previous_tick_range: TickRange::new(0, 0),
+        })
+    }
+
+    fn status(&self, cycle: &ProcessCycle) -> Status {
+        let (_, pos) = cycle.client.transport_query();
+
+        Status {
+            is_rolling: cycle.is_rolling,
+            tick: cycle.tick_range.start,
+            beats_per_minute: pos.beats_per_minute,
+            sequence_playing: self.sequencer.sequence_playing,
+            sequence_queued: self.sequencer.sequence_queued,
+            track_count: self.sequencer.track_count(),
+            track_shown: self.surface.track_shown(),
+        }
+    }
+
+    // Apply commands frontends sent since last cycle
+    fn process_commands(&mut self, cycle: &ProcessCycle) {
+        while let Some(project) = self.bus.next_project() {
+            self.load_project(project);
+        }
+
+        while let Some((frontend, command)) = self.bus.next_command() {
+            if let Command::Snapshot = command {
+                self.snapshots_requested[frontend] = true;
+            } else if let Err(reason) = bus::apply(command, cycle, &mut self.sequencer, &mut self.surface) {
+                self.bus.failed(frontend, command, reason);
+            }
+        }
+
+        // Frontends that don't get their snapshot this cycle get it once we have room for it
+        while let Some(frontend) = self.snapshots_requested.iter().position(|requested| *requested) {
+            match self.take_project() {
+                Some(project) => {
+                    self.snapshots_requested[frontend] = false;
+                    self.bus.send_snapshot(frontend, Snapshot { project, status: self.status(cycle) });
+                },
+                None => break,
+            }
+        }
+    }
+
+    pub fn connections(&self) -> Vec<Connection> {
+        self.controllers.iter().map(|controller| controller.connection()).collect()
+    }
+
+    pub fn project_room(&self) -> ProjectRoom {
+        ProjectRoom {
+            tracks: self.sequencer.track_count(),
+            sequences: self.sequencer.sequences.len(),
+            most_events: self.sequencer.tracks.iter().map(|track| track.most_events()).max().unwrap_or(0),
+        }
+    }
+
+    // Copy session into project, project needs room for it or this will allocate
+    pub fn copy_project(&self, project: &mut Project) {
+        for (track, state) in self.sequencer.tracks.iter().zip(project.tracks.iter_mut()) {
+            track.copy_state(state);
+        }
+
+        for (sequence, copy) in self.sequencer.sequences.iter().zip(project.sequences.iter_mut()) {
+            copy.copy_from(sequence);
+        }
+
+        self.mixer.copy_state(&mut project.mixer);
+        self.surface.copy_views(&mut project.views);
+    }
+
+    // Keep a spare project with room for the session around, the main thread makes room for us
+    fn prepare_spare_project(&mut self) {
+        while let Ok(project) = self.spare_projects.try_recv() {
+            self.room_requested = false;
+            if let Some(outgrown) = self.spare_project.replace(project) {
+                self.project_requests.try_send(ProjectRequest::Free(outgrown)).ok();
+            }
+        }
+
+        let room = self.project_room();
+        let has_room = self.spare_project.as_ref().map(|project| project.has_room(room)).unwrap_or(false);
+        if ! has_room && ! self.room_requested {
+            self.room_requested = self.project_requests.try_send(ProjectRequest::Room(room)).is_ok();
+        }
+    }
 
-            mixer: Mixer::new(client),
-            sequencer: Sequencer::new(client), 
-            surface: Surface::new(),
+    // Copy of session in spare project, None when we don't have room for it yet
+    fn take_project(&mut self) -> Option<Project> {
+        let room = self.project_room();
+        if ! self.spare_project.as_ref().map(|project| project.has_room(room)).unwrap_or(false) {
+            return None
+        }
+
+        let mut project = self.spare_project.take()?;
+        self.copy_project(&mut project);
+        Some(project)
+    }
+
+    // Restore saved project, project waits until we added or removed tracks so we have as many
+    // as it has. What we had ends up in project which is freed by the main thread
+    pub fn load_project(&mut self, project: Project) {
+        if let Some(replaced) = self.project_loading.replace(project) {
+            self.project_requests.try_send(ProjectRequest::Free(replaced)).ok();
+        }
+
+        self.continue_loading();
+    }
+
+    // Tracks are added the same way they're added from the controllers, so their ports are
+    // registered & unregistered on the main thread. Requests that don't fit in the channel are
+    // retried next cycle
+    fn continue_loading(&mut self) {
+        let track_count = match &self.project_loading {
+            Some(project) => project.tracks.len(),
+            None => return,
+        };
+
+        while self.sequencer.track_count_requested() < track_count && self.sequencer.request_track() {}
+        while self.sequencer.track_count() > track_count.max(1) && self.sequencer.remove_track() {}
+        self.surface.resize_tracks(self.sequencer.track_count());
+        self.mixer.resize_tracks(self.sequencer.track_count());
+
+        if self.sequencer.track_count() != track_count {
+            return
+        }
+
+        if let Some(mut project) = self.project_loading.take() {
+            self.sequencer.swap_state(&mut project.tracks, &project.sequences);
+            self.surface.restore_views(&project.views);
+
+            // Synths don't know about our mixer state yet
+            self.mixer.restore(&project.mixer);
+            self.mixer.resend();
+
+            self.project_requests.try_send(ProjectRequest::Free(project)).ok();
+        }
+    }
+
+    // Main thread could not build a track project needs, so it can't be loaded
+    fn abort_loading(&mut self) {
+        if let Some(project) = self.project_loading.take() {
+            log::message(format_args!("Could not load project: tracks could not be added"));
+            self.project_requests.try_send(ProjectRequest::Free(project)).ok();
         }
     }
 }
 
 impl jack::ProcessHandler for ProcessHandler {
     fn process(&mut self, client: &jack::Client, scope: &jack::ProcessScope) -> jack::Control {
+        #[cfg(feature = "alloc-debug")]
+        allocator::enter();
+
         // Get something representing this process cycle
-        let cycle = ProcessCycle::new(client, scope);
+        let mut cycle = ProcessCycle::new(client, scope, self.follows_transport);
+        if cycle.follows_transport && cycle.is_rolling {
+            cycle.continue_from(&self.previous_tick_range);
+        }
+        self.previous_tick_range = cycle.tick_range;
+
+        // Add tracks that were built since last cycle
+        while let Ok(registered) = self.registered_tracks.try_recv() {
+            match registered {
+                Some(track) => {
+                    self.sequencer.add_track(track);
+                    self.surface.resize_tracks(self.sequencer.track_count());
+                    self.mixer.resize_tracks(self.sequencer.track_count());
+                },
+                None => {
+                    self.sequencer.track_request_failed();
+                    self.abort_loading();
+                    self.surface.flash(format_args!("error"), cycle.time_stop);
+                },
+            }
+        }
+        self.continue_loading();
+
+        for controller in self.controllers.iter_mut() {
+            controller.process_midi_input(&cycle, &mut self.sequencer, &mut self.surface, &mut self.mixer);
+        }
 
-        self.apc20.process_midi_input(&cycle, &mut self.sequencer, &mut self.surface, &mut self.mixer);
-        self.apc40.process_midi_input(&cycle, &mut self.sequencer, &mut self.surface, &mut self.mixer);
+        self.prepare_spare_project();
+        self.process_commands(&cycle);
+
+        // Saving waits until we have room to copy the session
+        if self.surface.save_requested {
+            if let Some(project) = self.take_project() {
+                self.surface.save_requested = self.project_requests.try_send(ProjectRequest::Save(project)).is_err();
+            }
+        }
+
+        while let Ok(is_saved) = self.save_results.try_recv() {
+            let text = if is_saved { "saved" } else { "error" };
+            self.surface.flash(format_args!("{}", text), cycle.time_stop);
+        }
 
         if cycle.is_rolling {
             self.sequencer.autoqueue_next_sequence(&cycle);
         }
 
         // Sequencer first at it will cache playing notes, these we can use for sequence visualization
-        self.sequencer.output_midi(&cycle);
+        self.sequencer.output_midi(&cycle, &mut self.mixer);
         self.mixer.output_midi(&cycle);
 
-        self.apc20.output_midi(&cycle, &mut self.sequencer, &mut self.surface);
-        self.apc40.output_midi(&cycle, &mut self.sequencer, &mut self.surface);
+        for (index, controller) in self.controllers.iter_mut().enumerate() {
+            controller.output_midi(&cycle, &mut self.sequencer, &mut self.surface, &self.mixer, &self.bus, index);
+        }
+
+        self.bus.publish(self.status(&cycle));
+
+        #[cfg(feature = "alloc-debug")]
+        {
+            let allocations = allocator::leave();
+            if allocations > 0 {
+                log::message(format_args!("{} allocations in process callback", allocations));
+            }
+        }
 
         jack::Control::Continue
     }
 }
 
 
+// Room in channels the process thread sends requests through
+const PROCESS_CHANNEL_CAPACITY: usize = 16;
+
 fn main() {
+    // Process thread logs through this
+    log::start();
+
+    if let Err(error) = run() {
+        println!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), StartupError> {
+    // Config file & arguments replace what used to be hardcoded
+    let config = Config::from_args(Args::parse())?;
+
     // Setup client
-    let (client, _status) =
-        jack::Client::new("Octothorpe", jack::ClientOptions::NO_START_SERVER).unwrap();
+    let (client, _status) = jack::Client::new(&config.client_name, config.client_options())
+        .map_err(|error| StartupError::Client { name: config.client_name.clone(), error })?;
+
+    // Process thread sends through bounded channels, as sending through unbounded ones allocates
+    let (timebase_sender, timebase_receiver) = sync_channel(PROCESS_CHANNEL_CAPACITY);
+    let (port_request_sender, port_request_receiver) = sync_channel(Sequencer::TRACK_CAPACITY);
+    // Receiving from unbounded channels frees their blocks, so the process thread receives from
+    // bounded ones aswell. Requests are bounded, so sends on the main thread don't have to wait
+    let (registered_track_sender, registered_track_receiver) = sync_channel(Sequencer::TRACK_CAPACITY);
+    let (project_request_sender, project_request_receiver) = sync_channel(PROCESS_CHANNEL_CAPACITY);
+    let (save_result_sender, save_result_receiver) = sync_channel(PROCESS_CHANNEL_CAPACITY);
+    let (spare_project_sender, spare_project_receiver) = sync_channel(PROCESS_CHANNEL_CAPACITY);
+    let (port_registration_sender, port_registration_receiver) = channel();
+
+    // Start with saved project when there is one
+    let project_path = config.project.as_path();
+    let mut project = if project_path.exists() {
+        Project::load(project_path).map_err(|e| println!("{}", e)).ok()
+    } else {
+        None
+    };
+    let track_count = project.as_ref().map(|project| project.tracks.len().max(1)).unwrap_or(config.tracks);
+
+    // Controller profiles are read from disk, tell user what's wrong with them
+    let setups = config.controller_setups()?;
+
+    // Conditional master only takes over when there's no master yet, slaves don't register at all.
+    // Handler is boxed so it stays put while jack calls it
+    let mut timebasehandler = Box::new(TimebaseHandler::new(timebase_receiver, config.beats_per_minute));
+    let is_master = match config.timebase {
+        TimebaseMode::Master => TimebaseHandler::register(&mut timebasehandler, &client, false),
+        TimebaseMode::Conditional => TimebaseHandler::register(&mut timebasehandler, &client, true),
+        TimebaseMode::Slave => false,
+    };
+
+    // Frontends connect to bus before it's handed to the process thread
+    let mut bus = Bus::new();
+    let repl_frontend = bus.connect();
+
+    // Remote control keeps running until we quit, we can do without when port is taken
+    if ! config.osc_address.is_empty() {
+        match OscServer::bind(&config.osc_address, bus.connect()) {
+            Ok(server) => { thread::spawn(move || server.run()); },
+            Err(e) => println!("{}", e),
+        }
+    }
 
-    let (timebase_sender, timebase_receiver) = channel();
+    let mut processhandler = ProcessHandler::new(
+        timebase_sender,
+        port_request_sender,
+        registered_track_receiver,
+        project_request_sender,
+        save_result_receiver,
+        spare_project_receiver,
+        bus,
+        ! is_master,
+        track_count,
+        &config.ports,
+        config.velocity_curve,
+        &setups,
+        &client
+    )?;
+    // Connections are made once we're active
+    let connections = project.as_mut().map(|project| mem::take(&mut project.connections)).unwrap_or_default();
+    if let Some(project) = project {
+        processhandler.load_project(project);
+    }
 
-    let processhandler = ProcessHandler::new(timebase_sender, &client);
-    let timebasehandler = TimebaseHandler::new(timebase_receiver);
+    let notificationhandler = NotificationHandler::new(processhandler.connections(), port_registration_sender);
 
     // Activate client
-    let _async_client = client
-        .activate_async((), processhandler, timebasehandler)
-        .unwrap();
+    let async_client = client
+        .activate_async(notificationhandler, processhandler, ())
+        .map_err(StartupError::Activate)?;
+
+    let patchbay = Patchbay::new(async_client.as_client());
+
+    let connections = thread::scope(|scope| {
+        let client = async_client.as_client();
+        let config = &config;
+        let patchbay = &patchbay;
+
+        // Wire up ports like config & project tell us to
+        for connections in [&config.connect, &connections] {
+            if let Err(e) = patchbay.restore(client, connections) {
+                println!("{}", e);
+            }
+        }
+
+        // Commands are read from stdin until user quits or input ends
+        let (quit_sender, quit_receiver) = channel();
+        scope.spawn(move || {
+            Repl::new(repl_frontend, client, config, patchbay).run();
+            quit_sender.send(()).ok();
+        });
+
+        // Handle port (un)registration & projects for process thread until we quit
+        while quit_receiver.try_recv().is_err() {
+            // Reconnect ports we remember when they show up
+            while let Ok(name) = port_registration_receiver.try_recv() {
+                if let Err(e) = patchbay.port_registered(client, &name) {
+                    println!("{}", e);
+                }
+            }
+
+            while let Ok(request) = project_request_receiver.try_recv() {
+                match request {
+                    ProjectRequest::Room(room) => {
+                        let mut project = Project::new();
+                        project.make_room(room);
+                        spare_project_sender.send(project).ok();
+                    },
+                    ProjectRequest::Save(mut project) => {
+                        project.connections = patchbay.connections(client);
+                        let result = project.save(project_path);
+                        if let Err(e) = &result {
+                            println!("{}", e);
+                        }
+                        save_result_sender.send(result.is_ok()).ok();
+                    },
+                    // Dropping frees project
+                    ProjectRequest::Free(_) => (),
+                }
+            }
+
+            match port_request_receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(PortRequest::Register(index)) => {
+                    let name = Track::port_name(&config.ports.track, index);
+                    let result = client.register_port(name.as_str(), jack::MidiOut::default())
+                        .map(Track::new)
+                        .map_err(|e| format!("Could not register port {}: {}", name, e));
+                    if let Err(e) = &result {
+                        println!("{}", e);
+                    }
+                    registered_track_sender.send(result.ok()).ok();
+                },
+                Ok(PortRequest::Unregister(track)) => {
+                    client.unregister_port(track.into_port()).ok();
+                },
+                Err(_) => (),
+            }
+        }
+
+        // Connections are gone once we deactivate
+        patchbay.connections(client)
+    });
+
+    // Save project on our way out
+    match async_client.deactivate() {
+        Ok((_, _, processhandler, ..)) => {
+            let mut project = Project::new();
+            project.make_room(processhandler.project_room());
+            processhandler.copy_project(&mut project);
+            project.connections = connections;
+            if let Err(e) = project.save(project_path) {
+                println!("{}", e);
+            }
+        },
+        Err(e) => println!("Could not deactivate jack client, project is not saved: {}", e),
+    }
 
-    // Wait for user to input string
-    let mut user_input = String::new();
-    io::stdin().read_line(&mut user_input).ok();
+    Ok(())
 }
 
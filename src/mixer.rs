@@ -1,32 +1,275 @@
 
+use serde::{Serialize, Deserialize};
+use clap::ValueEnum;
 use super::port::*;
+use super::error::StartupError;
 use super::message::*;
 use super::cycle::*;
+use super::events::PlayingNoteEvent;
+use super::sequencer::Sequencer;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MixerChannel {
+    pub volume: u8,
+    pub pan: u8,
+    pub send: u8,
+    // Use volume to scale note velocities instead of sending volume CC
+    #[serde(default)]
+    pub velocity_scaling: bool,
+}
+
+impl MixerChannel {
+    pub fn new() -> Self {
+        Self { volume: 100, pan: 64, send: 0, velocity_scaling: false }
+    }
+}
+
+// How fader values translate to velocity gain, projects saved before curves were lowercase use
+// the capitalized names
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum VelocityCurve {
+    #[serde(alias = "Linear")]
+    Linear,
+    #[serde(alias = "Logarithmic")]
+    Logarithmic,
+}
+
+impl Default for VelocityCurve {
+    fn default() -> Self { VelocityCurve::Logarithmic }
+}
+
+impl VelocityCurve {
+    // Range of logarithmic fader, in dB
+    const DYNAMIC_RANGE: f64 = 40.0;
+
+    pub fn gain(&self, value: u8) -> f64 {
+        let factor = value as f64 / 127.0;
+
+        match self {
+            VelocityCurve::Linear => factor,
+            VelocityCurve::Logarithmic => {
+                if value == 0 { 0.0 } else { 10_f64.powf((factor - 1.0) * Self::DYNAMIC_RANGE / 20.0) }
+            },
+        }
+    }
+}
+
+// CC numbers mixer values are sent as
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ControlChanges {
+    pub volume: u8,
+    pub pan: u8,
+    pub send: u8,
+}
+
+// Where master fader is sent to
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum MasterDestination {
+    // Mixer port of octothorpe
+    Port { channel: u8, cc: u8 },
+    // Output master CC on every track
+    Tracks { cc: u8 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MixerState {
+    pub channels: Vec<MixerChannel>,
+    pub master: u8,
+    pub control_changes: ControlChanges,
+    pub master_destination: MasterDestination,
+    #[serde(default)]
+    pub velocity_curve: VelocityCurve,
+}
+
+impl MixerState {
+    // Defaults for a mixer without channels, channels are added for tracks
+    pub fn new() -> Self {
+        MixerState {
+            channels: vec![],
+            master: 100,
+            control_changes: ControlChanges { volume: 7, pan: 10, send: 91 },
+            master_destination: MasterDestination::Port { channel: 0, cc: 7 },
+            velocity_curve: VelocityCurve::default(),
+        }
+    }
+
+    // Values end up in midi messages, they have to fit
+    pub fn validate(&self) -> Result<(), String> {
+        let controls = &self.control_changes;
+        let values = self.channels.iter().flat_map(|channel| [channel.volume, channel.pan, channel.send]);
+
+        if [self.master, controls.volume, controls.pan, controls.send].iter().copied().chain(values).any(|value| value > 127) {
+            return Err("mixer has values or controls above 127".to_string());
+        }
+
+        match self.master_destination {
+            MasterDestination::Port { channel, .. } if channel > 15 => Err(format!("master is sent on midi channel {}, channels are numbered 0 - 15", channel)),
+            MasterDestination::Port { cc, .. } | MasterDestination::Tracks { cc } if cc > 127 => Err(format!("master is sent as CC {}, CC's go up to 127", cc)),
+            _ => Ok(()),
+        }
+    }
+}
 
 pub struct Mixer {
     output: MidiOut,
     buffer: Vec<TimedMessage>,
+
+    channels: Vec<MixerChannel>,
+    master: u8,
+    control_changes: ControlChanges,
+    master_destination: MasterDestination,
+    velocity_curve: VelocityCurve,
+    // Curve from config, this wins from the curve saved in projects
+    configured_curve: Option<VelocityCurve>,
+
+    // Values that still have to be sent to tracks, (time, track, cc, value)
+    pending: Vec<(u32, usize, u8, u8)>,
 }
 
 impl Mixer {
-    pub fn new(client: &jack::Client) -> Self {
-        let output = client.register_port("Mixer", jack::MidiOut::default()).unwrap();
+    // Messages are buffered in preallocated memory, fader moves won't come close to this
+    const BUFFER_CAPACITY: usize = 512;
 
-        Self { 
+    pub fn new(client: &jack::Client, track_count: usize, port_name: &str, configured_curve: Option<VelocityCurve>) -> Result<Self, StartupError> {
+        let output = register_port(client, port_name, jack::MidiOut::default())?;
+        let state = MixerState::new();
+
+        Ok(Self { 
             output: MidiOut::new(output),
-            buffer: vec![],
+            buffer: Vec::with_capacity(Self::BUFFER_CAPACITY),
+
+            channels: Sequencer::track_vec(MixerChannel::new(), track_count),
+            master: state.master,
+            control_changes: state.control_changes,
+            master_destination: state.master_destination,
+            velocity_curve: configured_curve.unwrap_or(state.velocity_curve),
+            configured_curve,
+
+            pending: Vec::with_capacity(Self::BUFFER_CAPACITY),
+        })
+    }
+
+    pub fn channel(&self, track_index: usize) -> &MixerChannel { &self.channels[track_index] }
+    pub fn master(&self) -> u8 { self.master }
+
+    pub fn resize_tracks(&mut self, track_count: usize) {
+        self.channels.resize(track_count, MixerChannel::new());
+        self.pending.retain(|(_, track_index, _, _)| *track_index < track_count);
+    }
+
+    // Copy mixer into state, reusing memory of state
+    pub fn copy_state(&self, state: &mut MixerState) {
+        state.channels.clone_from(&self.channels);
+        state.master = self.master;
+        state.control_changes = self.control_changes;
+        state.master_destination = self.master_destination;
+        state.velocity_curve = self.velocity_curve;
+    }
+
+    // Restore saved state that has a channel for every track, channels are copied as ours have
+    // room for all tracks
+    pub fn restore(&mut self, state: &MixerState) {
+        self.channels.clone_from(&state.channels);
+        self.master = state.master;
+        self.control_changes = state.control_changes;
+        self.master_destination = state.master_destination;
+        self.velocity_curve = self.configured_curve.unwrap_or(state.velocity_curve);
+    }
+
+    // Send all values again, so synths are in line with our state
+    pub fn resend(&mut self) {
+        for (track_index, channel) in self.channels.iter().enumerate() {
+            if ! channel.velocity_scaling {
+                self.pending.push((0, track_index, self.control_changes.volume, channel.volume));
+            }
+            self.pending.push((0, track_index, self.control_changes.pan, channel.pan));
+            self.pending.push((0, track_index, self.control_changes.send, channel.send));
         }
+
+        self.master_adjusted(0, self.master);
     }
 
-    pub fn fader_adjusted(&mut self, time: u32, fader: u8, value: u8) {
-        // TODO - Output this to corresponding port
-        self.buffer.push(TimedMessage::new(time, Message::Note([0xB0, fader, value])));
+    pub fn fader_adjusted(&mut self, time: u32, track_index: usize, value: u8) {
+        if let Some(channel) = self.channels.get_mut(track_index) {
+            channel.volume = value;
+
+            // Volume is applied to velocities when scaling
+            if ! channel.velocity_scaling {
+                self.pending.push((time, track_index, self.control_changes.volume, value));
+            }
+        }
+    }
+
+    pub fn toggle_velocity_scaling(&mut self, track_index: usize) {
+        if let Some(channel) = self.channels.get_mut(track_index) {
+            channel.velocity_scaling = ! channel.velocity_scaling;
+
+            // Put synth volume back where it should be when we stop scaling
+            if ! channel.velocity_scaling {
+                self.pending.push((0, track_index, self.control_changes.volume, channel.volume));
+            }
+        }
+    }
+
+    /*
+     * Scale velocities of notes for tracks that use velocity scaling, track & master fader act as
+     * gain. Notes that end up without velocity are dropped
+     */
+    pub fn scale_velocities(&self, track_index: usize, notes: &mut Vec<PlayingNoteEvent>) {
+        let channel = match self.channels.get(track_index) {
+            Some(channel) if channel.velocity_scaling => channel,
+            _ => return,
+        };
+
+        let gain = self.velocity_curve.gain(channel.volume) * self.velocity_curve.gain(self.master);
+
+        notes.retain(|note| (note.start_velocity as f64 * gain).round() >= 1.0);
+        notes.iter_mut().for_each(|note| {
+            let velocity = (note.start_velocity as f64 * gain).round();
+            note.start_velocity = if velocity > 127.0 { 127 } else { velocity as u8 };
+        });
+    }
+
+    pub fn pan_adjusted(&mut self, time: u32, track_index: usize, value: u8) {
+        if let Some(channel) = self.channels.get_mut(track_index) {
+            channel.pan = value;
+            self.pending.push((time, track_index, self.control_changes.pan, value));
+        }
+    }
+
+    pub fn send_adjusted(&mut self, time: u32, track_index: usize, value: u8) {
+        if let Some(channel) = self.channels.get_mut(track_index) {
+            channel.send = value;
+            self.pending.push((time, track_index, self.control_changes.send, value));
+        }
     }
 
     pub fn master_adjusted(&mut self, time: u32, value: u8) {
-        // TODO - Output this to corresponding port
-        //vec![TimedMessage::new(time, Message::Note([0xB0 + 15, 127, value]))]
-        self.buffer.push(TimedMessage::new(time, Message::Note([0xB0, 127, value])));
+        self.master = value;
+
+        match self.master_destination {
+            MasterDestination::Port { channel, cc } => {
+                self.buffer.push(TimedMessage::new(time, Message::Note([0xB0 + channel, cc, value])));
+            },
+            MasterDestination::Tracks { cc } => {
+                for track_index in 0 .. self.channels.len() {
+                    self.pending.push((time, track_index, cc, value));
+                }
+            },
+        }
+    }
+
+    // Add messages that should be sent on output of track to messages
+    pub fn track_messages(&mut self, track_index: usize, channel: u8, messages: &mut Vec<TimedMessage>) {
+        self.pending.retain(|(time, index, cc, value)| {
+            if *index == track_index {
+                messages.push(TimedMessage::new(*time, Message::Note([0xB0 + channel, *cc, *value])));
+                false
+            } else {
+                true
+            }
+        });
     }
 
     pub fn output_midi(&mut self, cycle: &ProcessCycle) {
@@ -0,0 +1,422 @@
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use super::bus::{Command, Event, Frontend, Status};
+use super::TimebaseHandler;
+
+// Osc is only served on this machine
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9000";
+
+const READ_TIMEOUT: Duration = Duration::from_millis(20);
+// Playhead feedback is throttled, status changes every cycle
+const FEEDBACK_INTERVAL: Duration = Duration::from_millis(50);
+const BEATS_PER_BAR: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argument {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub arguments: Vec<Argument>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, arguments: Vec<Argument>) -> Self {
+        OscMessage { address: address.to_string(), arguments }
+    }
+
+    // Osc strings are null terminated & padded to 4 bytes
+    fn write_string(bytes: &mut Vec<u8>, string: &str) {
+        bytes.extend_from_slice(string.as_bytes());
+        bytes.push(0);
+        while bytes.len() % 4 != 0 {
+            bytes.push(0);
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        Self::write_string(&mut bytes, &self.address);
+
+        let tags: String = std::iter::once(',')
+            .chain(self.arguments.iter().map(|argument| match argument {
+                Argument::Int(_) => 'i',
+                Argument::Float(_) => 'f',
+                Argument::String(_) => 's',
+                Argument::Bool(true) => 'T',
+                Argument::Bool(false) => 'F',
+            }))
+            .collect();
+        Self::write_string(&mut bytes, &tags);
+
+        for argument in self.arguments.iter() {
+            match argument {
+                Argument::Int(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                Argument::Float(value) => bytes.extend_from_slice(&value.to_be_bytes()),
+                Argument::String(value) => Self::write_string(&mut bytes, value),
+                Argument::Bool(_) => (),
+            }
+        }
+
+        bytes
+    }
+
+    // Get messages from packet, bundles contain multiple messages
+    pub fn decode(bytes: &[u8]) -> Result<Vec<OscMessage>, String> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if bytes.starts_with(b"#bundle\0") {
+            // Skip header & time tag, we handle bundled messages right away
+            reader.position = 16;
+            let mut messages = vec![];
+
+            while reader.position < bytes.len() {
+                let size = reader.int()?;
+                if size < 0 {
+                    return Err(format!("bundle element has negative size {}", size))
+                }
+
+                let element = reader.take(size as usize)?;
+                messages.append(&mut Self::decode(element)?);
+            }
+
+            return Ok(messages)
+        }
+
+        let address = reader.string()?;
+        if ! address.starts_with('/') {
+            return Err(format!("invalid address {}", address))
+        }
+
+        // Messages without type tags have no arguments
+        let tags = if reader.position < bytes.len() { reader.string()? } else { ",".to_string() };
+        let arguments = tags.chars().skip(1)
+            .map(|tag| match tag {
+                'i' => Ok(Argument::Int(reader.int()?)),
+                'f' => Ok(Argument::Float(f32::from_be_bytes(reader.word()?))),
+                's' => Ok(Argument::String(reader.string()?)),
+                'T' => Ok(Argument::Bool(true)),
+                'F' => Ok(Argument::Bool(false)),
+                _ => Err(format!("unsupported argument type {} in {}", tag, address)),
+            })
+            .collect::<Result<Vec<Argument>, String>>()?;
+
+        Ok(vec![OscMessage { address, arguments }])
+    }
+
+    // Ints & floats are both accepted for numbers, controllers like TouchOSC only send floats
+    fn number(&self, index: usize) -> Result<f64, String> {
+        match self.arguments.get(index) {
+            Some(Argument::Int(value)) => Ok(*value as f64),
+            Some(Argument::Float(value)) => Ok(*value as f64),
+            Some(Argument::Bool(value)) => Ok(if *value { 1.0 } else { 0.0 }),
+            _ => Err(format!("{} expects a number as argument {}", self.address, index + 1)),
+        }
+    }
+
+    // Casting would saturate or truncate numbers that don't fit, those are rejected instead
+    fn index(&self, index: usize) -> Result<usize, String> {
+        let number = self.number(index)?;
+        if number < 0.0 {
+            Err(format!("{} argument {} can't be negative", self.address, index + 1))
+        } else if ! number.is_finite() || number > u32::MAX as f64 {
+            Err(format!("{} argument {} is out of range 0 - {}", self.address, index + 1, u32::MAX))
+        } else {
+            Ok(number as usize)
+        }
+    }
+
+    fn byte(&self, index: usize) -> Result<u8, String> {
+        let number = self.index(index)?;
+        if number > 127 { Err(format!("{} argument {} is out of range 0 - 127", self.address, index + 1)) } else { Ok(number as u8) }
+    }
+
+    fn tick(&self, index: usize) -> Result<u32, String> {
+        Ok(self.index(index)? as u32)
+    }
+
+    // Translate message to bus command
+    pub fn command(&self) -> Result<Command, String> {
+        let command = match self.address.as_str() {
+            "/transport/start" => Command::Start,
+            "/transport/stop" => Command::Stop,
+            "/transport/reset" => Command::Reset,
+            "/tempo" => Command::SetTempo(self.number(0)?),
+            "/sequence/queue" => Command::QueueSequence(self.index(0)?),
+            "/sequence/phrase" => {
+                // Negative phrase plays nothing
+                let phrase = self.number(2)?;
+                let phrase = if phrase < 0.0 { None } else { Some(self.byte(2)?) };
+                Command::SetSequencePhrase { sequence: self.index(0)?, track: self.index(1)?, phrase }
+            },
+            "/sequence/toggle" => Command::ToggleSequenceTrack { sequence: self.index(0)?, track: self.index(1)? },
+            "/pattern/note/add" => Command::AddNote {
+                track: self.index(0)?, pattern: self.byte(1)?, start: self.tick(2)?, stop: self.tick(3)?, note: self.byte(4)?, velocity: self.byte(5)?,
+            },
+            "/pattern/note/remove" => Command::RemoveNote { track: self.index(0)?, pattern: self.byte(1)?, start: self.tick(2)?, note: self.byte(3)? },
+            "/pattern/clear" => Command::ClearPattern { track: self.index(0)?, pattern: self.byte(1)? },
+            "/undo" => Command::Undo,
+            "/redo" => Command::Redo,
+            "/save" => Command::Save,
+            _ => return Err(format!("unknown address {}", self.address)),
+        };
+
+        Ok(command)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.position.checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("message is cut off")?;
+
+        let bytes = &self.bytes[self.position .. end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<[u8; 4], String> {
+        let bytes = self.take(4)?;
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn int(&mut self) -> Result<i32, String> {
+        Ok(i32::from_be_bytes(self.word()?))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.bytes[self.position ..];
+        let length = rest.iter().position(|byte| *byte == 0).ok_or("string is not terminated")?;
+        let string = String::from_utf8(rest[.. length].to_vec()).map_err(|_| "string is not utf-8")?;
+
+        // Skip terminator & padding
+        self.take((length + 4) / 4 * 4)?;
+        Ok(string)
+    }
+}
+
+/*
+ * Receives osc on udp & passes it to the bus. Every peer that sent us something gets feedback of
+ * playhead, transport & leds of the controllers
+ */
+pub struct OscServer {
+    socket: UdpSocket,
+    frontend: Frontend,
+    peers: Vec<SocketAddr>,
+
+    status: Option<Status>,
+    feedback_sent: Option<Instant>,
+    // Status changed since we last sent feedback, it's sent once feedback is due
+    is_feedback_pending: bool,
+}
+
+impl OscServer {
+    pub fn bind(address: &str, frontend: Frontend) -> Result<Self, String> {
+        let socket = UdpSocket::bind(address).map_err(|e| format!("Could not start osc server on {}: {}", address, e))?;
+        socket.set_read_timeout(Some(READ_TIMEOUT)).map_err(|e| e.to_string())?;
+
+        Ok(OscServer { socket, frontend, peers: vec![], status: None, feedback_sent: None, is_feedback_pending: false })
+    }
+
+    pub fn local_address(&self) -> Option<SocketAddr> {
+        self.socket.local_addr().ok()
+    }
+
+    pub fn run(mut self) {
+        loop {
+            self.poll();
+        }
+    }
+
+    // Handle incoming message when there is one & send feedback
+    pub fn poll(&mut self) {
+        let mut buffer = [0; 4096];
+
+        if let Ok((length, peer)) = self.socket.recv_from(&mut buffer) {
+            if ! self.peers.contains(&peer) {
+                self.peers.push(peer);
+                // Show new peer where we are
+                self.feedback_sent = None;
+            }
+
+            let result = OscMessage::decode(&buffer[.. length])
+                .and_then(|messages| {
+                    messages.iter().try_for_each(|message| self.frontend.send(message.command()?))
+                });
+
+            if let Err(error) = result {
+                self.send_to(peer, &OscMessage::new("/error", vec![Argument::String(error)]));
+            }
+        }
+
+        let events: Vec<Event> = self.frontend.events().collect();
+
+        for event in events {
+            match event {
+                Event::Status(status) => {
+                    self.is_feedback_pending = self.is_feedback_pending || self.status != Some(status);
+                    self.status = Some(status);
+                },
+                Event::Failed(command, reason) => {
+                    self.broadcast(&OscMessage::new("/error", vec![Argument::String(format!("{:?}: {}", command, reason))]));
+                },
+                Event::Led { controller, message } => {
+                    let arguments = std::iter::once(controller as i32).chain(message.iter().map(|byte| *byte as i32))
+                        .map(Argument::Int)
+                        .collect();
+                    self.broadcast(&OscMessage::new("/led", arguments));
+                },
+            }
+        }
+
+        let is_feedback_due = self.feedback_sent.map(|sent| sent.elapsed() >= FEEDBACK_INTERVAL).unwrap_or(true);
+        if let (Some(status), true) = (self.status, is_feedback_due && (self.is_feedback_pending || self.feedback_sent.is_none())) {
+            self.feedback_sent = Some(Instant::now());
+            self.is_feedback_pending = false;
+
+            for message in Self::feedback(&status) {
+                self.broadcast(&message);
+            }
+        }
+    }
+
+    fn feedback(status: &Status) -> Vec<OscMessage> {
+        let ticks_per_beat = TimebaseHandler::TICKS_PER_BEAT as u32;
+        let beat = status.tick / ticks_per_beat;
+
+        vec![
+            OscMessage::new("/playhead", vec![
+                Argument::Int(status.tick as i32),
+                Argument::Int((beat / BEATS_PER_BAR + 1) as i32),
+                Argument::Int((beat % BEATS_PER_BAR + 1) as i32),
+            ]),
+            OscMessage::new("/transport", vec![Argument::Bool(status.is_rolling)]),
+            OscMessage::new("/tempo", vec![Argument::Float(status.beats_per_minute as f32)]),
+            OscMessage::new("/sequence/playing", vec![Argument::Int(status.sequence_playing as i32)]),
+            OscMessage::new("/sequence/queued", vec![Argument::Int(status.sequence_queued.map(|index| index as i32).unwrap_or(-1))]),
+        ]
+    }
+
+    fn send_to(&self, peer: SocketAddr, message: &OscMessage) {
+        self.socket.send_to(&message.encode(), peer).ok();
+    }
+
+    fn broadcast(&self, message: &OscMessage) {
+        let bytes = message.encode();
+        for peer in self.peers.iter() {
+            self.socket.send_to(&bytes, peer).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bus::Bus;
+
+    #[test]
+    fn codec() {
+        let message = OscMessage::new("/pattern/note/add", vec![
+            Argument::Int(1), Argument::Float(0.5), Argument::String("abc".to_string()), Argument::Bool(true),
+        ]);
+        let bytes = message.encode();
+
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(&bytes[.. 20], b"/pattern/note/add\0\0\0");
+        assert_eq!(OscMessage::decode(&bytes), Ok(vec![message.clone()]));
+
+        // Bundle with one element
+        let mut bundle = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+        bundle.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+        bundle.extend_from_slice(&bytes);
+        assert_eq!(OscMessage::decode(&bundle), Ok(vec![message]));
+
+        assert!(OscMessage::decode(b"/tempo\0\0,f\0\0\0\0").is_err());
+
+        // Negative element sizes are rejected instead of overflowing
+        let mut bundle = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+        bundle.extend_from_slice(&(-4i32).to_be_bytes());
+        assert!(OscMessage::decode(&bundle).is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        let note = |start: f32| OscMessage::new("/pattern/note/remove", vec![
+            Argument::Int(0), Argument::Int(0), Argument::Float(start), Argument::Int(60),
+        ]);
+
+        assert_eq!(note(1920.0).command(), Ok(Command::RemoveNote { track: 0, pattern: 0, start: 1920, note: 60 }));
+        assert!(note(f32::INFINITY).command().is_err());
+        assert!(note(f32::NAN).command().is_err());
+        assert!(note(2_f32.powi(33)).command().is_err());
+    }
+
+    #[test]
+    fn server() {
+        let mut bus = Bus::new();
+        let mut server = OscServer::bind("127.0.0.1:0", bus.connect()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut buffer = [0; 1024];
+
+        let message = OscMessage::new("/sequence/queue", vec![Argument::Float(2.0)]);
+        client.send_to(&message.encode(), server.local_address().unwrap()).unwrap();
+        server.poll();
+        assert_eq!(bus.next_command(), Some((0, Command::QueueSequence(2))));
+
+        // Peer gets feedback of status
+        bus.publish(Status { tick: 1920 * 5, ..Status::default() });
+        server.poll();
+        let length = client.recv(&mut buffer).unwrap();
+        let playhead = OscMessage::new("/playhead", vec![Argument::Int(1920 * 5), Argument::Int(2), Argument::Int(2)]);
+        assert_eq!(OscMessage::decode(&buffer[.. length]), Ok(vec![playhead]));
+
+        // Errors are sent back
+        client.send_to(&OscMessage::new("/nope", vec![]).encode(), server.local_address().unwrap()).unwrap();
+        server.poll();
+        let error = loop {
+            let length = client.recv(&mut buffer).unwrap();
+            let messages = OscMessage::decode(&buffer[.. length]).unwrap();
+            if messages[0].address == "/error" { break messages[0].clone() }
+        };
+        assert_eq!(error.arguments, vec![Argument::String("unknown address /nope".to_string())]);
+    }
+
+    #[test]
+    fn pending_feedback() {
+        let mut bus = Bus::new();
+        let mut server = OscServer::bind("127.0.0.1:0", bus.connect()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut buffer = [0; 1024];
+
+        client.send_to(&OscMessage::new("/transport/start", vec![]).encode(), server.local_address().unwrap()).unwrap();
+        bus.publish(Status { is_rolling: true, ..Status::default() });
+        server.poll();
+
+        // Stop comes in before feedback is due, it's sent once it is
+        bus.publish(Status::default());
+        server.poll();
+        std::thread::sleep(FEEDBACK_INTERVAL);
+        server.poll();
+
+        let mut transport = vec![];
+        while let Ok(length) = client.recv(&mut buffer) {
+            let messages = OscMessage::decode(&buffer[.. length]).unwrap();
+            transport.extend(messages.into_iter().filter(|message| message.address == "/transport"));
+        }
+        assert_eq!(transport.last().map(|message| message.arguments.clone()), Some(vec![Argument::Bool(false)]));
+    }
+}
@@ -0,0 +1,138 @@
+
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+
+/*
+ * Connections of one of our ports, port is named without client name, peers with. Config uses
+ * these as connect rules, projects save them
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Connect {
+    pub port: String,
+    pub to: Vec<String>,
+}
+
+// Add peers of other to connections, without doubling them
+fn merge(connections: &mut Vec<Connect>, other: &[Connect]) {
+    for connect in other {
+        match connections.iter_mut().find(|existing| existing.port == connect.port) {
+            Some(existing) => {
+                for peer in connect.to.iter() {
+                    if ! existing.to.contains(peer) {
+                        existing.to.push(peer.clone());
+                    }
+                }
+            },
+            None => connections.push(connect.clone()),
+        }
+    }
+}
+
+/*
+ * Remembers what our ports should be connected to, so we can restore connections of projects &
+ * reconnect peers that come back after they disappeared. Jack won't let us connect from it's own
+ * threads, so this is used from main & frontend threads
+ */
+pub struct Patchbay {
+    client_name: String,
+    remembered: Mutex<Vec<Connect>>,
+}
+
+impl Patchbay {
+    pub fn new(client: &jack::Client) -> Self {
+        Patchbay { client_name: client.name().to_string(), remembered: Mutex::new(vec![]) }
+    }
+
+    fn full_name(&self, port: &str) -> String {
+        format!("{}:{}", self.client_name, port)
+    }
+
+    // Connect our port to peer in the direction jack wants, skip ports that aren't around (yet)
+    fn connect(&self, client: &jack::Client, port: &str, peer: &str) -> Result<(), String> {
+        let name = self.full_name(port);
+        let ours = match client.port_by_name(&name) {
+            Some(ours) => ours,
+            None => return Ok(()),
+        };
+
+        if client.port_by_name(peer).is_none() || ours.get_connections().iter().any(|connected| connected == peer) {
+            return Ok(())
+        }
+
+        let result = if ours.flags().contains(jack::PortFlags::IS_INPUT) {
+            client.connect_ports_by_name(peer, &name)
+        } else {
+            client.connect_ports_by_name(&name, peer)
+        };
+
+        result.map_err(|e| format!("Could not connect {} to {}: {}", port, peer, e))
+    }
+
+    fn connect_all<'a>(&self, client: &jack::Client, connections: impl Iterator<Item = &'a Connect>) -> Result<(), String> {
+        let errors: Vec<String> = connections
+            .flat_map(|connect| connect.to.iter().map(move |peer| (connect.port.as_str(), peer.as_str())))
+            .filter_map(|(port, peer)| self.connect(client, port, peer).err())
+            .collect();
+
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("\n")) }
+    }
+
+    // Remember connections & make the ones we can, peers that are missing are connected when they show up
+    pub fn restore(&self, client: &jack::Client, connections: &[Connect]) -> Result<(), String> {
+        let mut remembered = self.remembered.lock().unwrap();
+        merge(&mut remembered, connections);
+
+        self.connect_all(client, connections.iter())
+    }
+
+    // Port showed up, connect it when it's one of ours or a peer we remember
+    pub fn port_registered(&self, client: &jack::Client, name: &str) -> Result<(), String> {
+        let remembered = self.remembered.lock().unwrap();
+        let connections = remembered.iter()
+            .map(|connect| {
+                let is_ours = self.full_name(&connect.port) == name;
+                let to = connect.to.iter().filter(|peer| is_ours || *peer == name).cloned().collect();
+                Connect { port: connect.port.clone(), to }
+            })
+            .collect::<Vec<Connect>>();
+
+        self.connect_all(client, connections.iter())
+    }
+
+    // Current connections of our ports, together with remembered ones of peers that are gone
+    pub fn connections(&self, client: &jack::Client) -> Vec<Connect> {
+        let prefix = self.full_name("");
+        let current: Vec<Connect> = client.ports(None, None, jack::PortFlags::empty()).into_iter()
+            .filter(|name| name.starts_with(&prefix))
+            .filter_map(|name| {
+                let to = client.port_by_name(&name)?.get_connections();
+                Some(Connect { port: name[prefix.len() ..].to_string(), to })
+            })
+            .filter(|connect| ! connect.to.is_empty())
+            .collect();
+
+        let mut remembered = self.remembered.lock().unwrap();
+        // Peers that are around but not connected were disconnected on purpose
+        remembered.iter_mut().for_each(|connect| connect.to.retain(|peer| client.port_by_name(peer).is_none()));
+        merge(&mut remembered, &current);
+        remembered.retain(|connect| ! connect.to.is_empty());
+
+        remembered.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merging() {
+        let connect = |port: &str, to: &[&str]| Connect { port: port.to_string(), to: to.iter().map(|peer| peer.to_string()).collect() };
+        let mut connections = vec![connect("Track 1", &["synth:in"])];
+
+        merge(&mut connections, &[connect("Track 1", &["synth:in", "drums:in"]), connect("Mixer", &["synth:in"])]);
+
+        assert_eq!(connections, vec![connect("Track 1", &["synth:in", "drums:in"]), connect("Mixer", &["synth:in"])]);
+    }
+}
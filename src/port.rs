@@ -1,15 +1,37 @@
 
 use super::message::TimedMessage;
+use super::track::Track;
+use super::error::StartupError;
+use super::log;
+
+// Register port on start, telling user what port jack wouldn't give us
+pub fn register_port<PS: jack::PortSpec>(client: &jack::Client, name: &str, spec: PS) -> Result<jack::Port<PS>, StartupError> {
+    client.register_port(name, spec).map_err(|error| StartupError::Port { name: name.to_string(), error })
+}
 
 pub struct MidiOut {
     pub port: jack::Port<jack::MidiOut>,
+    // Preallocated, so messages can be collected without allocating on the process thread
+    buffer: Vec<TimedMessage>,
 }
 
 // We use a wrapper so we can sort the messages before outputting them to jack, as out off order
 // messages produce runtime errors
 impl MidiOut {
+    const BUFFER_CAPACITY: usize = 512;
+
     pub fn new(port: jack::Port<jack::MidiOut>) -> Self {
-        MidiOut { port }
+        MidiOut { port, buffer: Vec::with_capacity(Self::BUFFER_CAPACITY) }
+    }
+
+    // Take buffer to collect messages in, it's handed back with write_buffer
+    pub fn take_buffer(&mut self) -> Vec<TimedMessage> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    pub fn write_buffer(&mut self, process_scope: &jack::ProcessScope, mut messages: Vec<TimedMessage>) {
+        self.write_midi(process_scope, &mut messages);
+        self.buffer = messages;
     }
 
     /*
@@ -20,15 +42,38 @@ impl MidiOut {
 
         // Sort messages based on time in timed message as jack will complain about unordered
         // messages
-        messages.sort();
+        sort_messages(messages);
         messages.drain(0..).for_each(|message| { 
             match writer.write(&message.to_raw_midi()) {
-                Err(e) => {
-                    println!("Error: {}", e);
-                    println!("{:?}\n", message);
-                },
+                Err(e) => log::message(format_args!("Error: {} {:?}", e, message)),
                 Ok(_) => (),
             }
         });
     }
 }
+
+/*
+ * Vec::sort may allocate, so we use an insertion sort. It keeps the order of messages at the same
+ * time, which matters for note off & note on of the same note, and messages are mostly in order
+ */
+fn sort_messages(messages: &mut Vec<TimedMessage>) {
+    for index in 1 .. messages.len() {
+        let mut position = index;
+
+        while position > 0 && messages[position - 1] > messages[position] {
+            messages.swap(position - 1, position);
+            position -= 1;
+        }
+    }
+}
+
+/*
+ * Jack does not allow us to (un)register ports from the process callback, so we pass these
+ * requests to the main thread, which will send tracks with registered ports back. Removed tracks
+ * are sent along whole, so their patterns & phrases are freed there too
+ */
+pub enum PortRequest {
+    // Register port for track with index
+    Register(usize),
+    Unregister(Track),
+}
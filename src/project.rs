@@ -0,0 +1,250 @@
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use super::track::{Track, TrackState};
+use super::sequence::Sequence;
+use super::loopable::Loopable;
+use super::sequencer::Sequencer;
+use super::mixer::{MixerState, MixerChannel};
+use super::surface::TrackViews;
+use super::patchbay::Connect;
+
+/*
+ * Everything we save to disk, ports & controller state are left out as they are recreated on start,
+ * views of loopables are saved so we can continue where we left off, so are connections of our ports
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Project {
+    pub tracks: Vec<TrackState>,
+    pub sequences: Vec<Sequence>,
+    pub mixer: MixerState,
+    #[serde(default)]
+    pub views: Vec<TrackViews>,
+    // Filled in by main thread, process thread can't ask jack about connections
+    #[serde(default)]
+    pub connections: Vec<Connect>,
+}
+
+// Room a project needs for a session, most events is the most events any loopable has
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProjectRoom {
+    pub tracks: usize,
+    pub sequences: usize,
+    pub most_events: usize,
+}
+
+/*
+ * Copying the session into a project would allocate on the process thread, so the main thread
+ * makes room in spare projects for it. Projects the process thread is done with are sent to the
+ * main thread aswell, so they're freed there
+ */
+pub enum ProjectRequest {
+    Room(ProjectRoom),
+    Save(Project),
+    Free(Project),
+}
+
+impl Project {
+    pub const DEFAULT_FILE: &'static str = "octothorpe.oct";
+    // Extra room for events, so a project still fits after a couple of edits
+    const ROOM_TO_GROW: usize = 64;
+
+    // Project without tracks, sessions are copied into it
+    pub fn new() -> Self {
+        Project { tracks: vec![], sequences: vec![], mixer: MixerState::new(), views: vec![], connections: vec![] }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+
+        let mut project: Project = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("Could not read project {}: {}", path.display(), e))?;
+
+        project.complete();
+        project.validate().map_err(|e| format!("Invalid project {}: {}", path.display(), e))?;
+        Ok(project)
+    }
+
+    // Fill in what older projects don't have
+    fn complete(&mut self) {
+        self.tracks.iter_mut().for_each(|track| track.complete_knobs());
+        self.mixer.channels.resize(self.tracks.len(), MixerChannel::new());
+    }
+
+    // Files can be edited by hand or come from older versions, reject what would trip up the process thread
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tracks.is_empty() || self.tracks.len() > Sequencer::TRACK_CAPACITY {
+            return Err(format!("project should have 1 - {} tracks", Sequencer::TRACK_CAPACITY));
+        }
+
+        for (index, track) in self.tracks.iter().enumerate() {
+            if track.channel > 15 {
+                return Err(format!("track {} uses midi channel {}, channels are numbered 0 - 15", index, track.channel));
+            }
+            if track.knob_values.len() != Track::KNOB_COUNT || track.knob_controls.len() != Track::KNOB_COUNT {
+                return Err(format!("track {} should have {} knobs", index, Track::KNOB_COUNT));
+            }
+            if track.knob_values.iter().chain(track.knob_controls.iter()).any(|value| *value > 127) {
+                return Err(format!("track {} has knob values or controls above 127", index));
+            }
+
+            // Loopables are looped by their length, they can't loop without one
+            if track.phrases.iter().any(|phrase| phrase.length() == 0) || track.patterns.iter().any(|pattern| pattern.length == Some(0)) {
+                return Err(format!("track {} has patterns or phrases with a length of 0", index));
+            }
+
+            let notes = track.patterns.iter().flat_map(|pattern| pattern.note_events.iter());
+            if notes.flat_map(|note| [Some(note.note), Some(note.start_velocity), note.stop_velocity]).any(|value| value.map(|value| value > 127).unwrap_or(false)) {
+                return Err(format!("track {} has notes or velocities above 127", index));
+            }
+
+            // Phrases play patterns & timeline plays phrases by index
+            let patterns = track.phrases.iter().flat_map(|phrase| phrase.pattern_events.iter().map(|event| event.pattern));
+            let phrases = track.timeline.phrase_events.iter().map(|event| event.phrase);
+            if patterns.chain(phrases).any(|index| index >= 5) {
+                return Err(format!("track {} plays patterns or phrases that don't exist, they are numbered 0 - 4", index));
+            }
+        }
+
+        if self.sequences.len() != 5 {
+            return Err(format!("project should have 5 sequences, not {}", self.sequences.len()));
+        }
+        if self.sequences.iter().any(|sequence| ! sequence.fits(self.tracks.len())) {
+            return Err(format!("sequences don't match the {} tracks of project", self.tracks.len()));
+        }
+        if self.sequences.iter().flat_map(|sequence| sequence.phrases().iter()).any(|phrase| phrase.map(|phrase| phrase >= 5).unwrap_or(false)) {
+            return Err("sequences play phrases that don't exist, they are numbered 0 - 4".to_string());
+        }
+        if self.mixer.channels.len() != self.tracks.len() {
+            return Err(format!("mixer doesn't have a channel for each of the {} tracks of project", self.tracks.len()));
+        }
+
+        self.mixer.validate()
+    }
+
+    // Can session be copied in without allocating
+    pub fn has_room(&self, room: ProjectRoom) -> bool {
+        self.tracks.len() == room.tracks
+            && self.tracks.iter().all(|track| track.has_room(room.most_events))
+            && self.sequences.len() == room.sequences
+            && self.sequences.iter().all(|sequence| sequence.has_room(room.tracks))
+            && self.mixer.channels.capacity() >= room.tracks
+            && self.views.capacity() >= room.tracks
+            && self.connections.is_empty()
+    }
+
+    // Make room for session, this allocates so it's done off the process thread
+    pub fn make_room(&mut self, room: ProjectRoom) {
+        let events = room.most_events + Self::ROOM_TO_GROW;
+
+        self.tracks.resize_with(room.tracks, TrackState::new);
+        self.tracks.iter_mut().for_each(|track| track.reserve(events));
+        self.sequences.resize_with(room.sequences, || Sequence::new(0, 0));
+        self.sequences.iter_mut().for_each(|sequence| sequence.reserve(room.tracks));
+        self.mixer.channels.reserve(room.tracks);
+        self.views.reserve(room.tracks);
+        self.connections.clear();
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+
+        serde_json::to_writer(BufWriter::new(file), self)
+            .map_err(|e| format!("Could not write project {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::events::{LoopablePhraseEvent, LoopableNoteEvent};
+    use super::super::mixer::*;
+
+    fn project(track_count: usize) -> Project {
+        Project {
+            tracks: (0 .. track_count).map(|_| TrackState::new()).collect(),
+            sequences: (0 .. 5).map(|phrase| Sequence::new(phrase, track_count)).collect(),
+            mixer: MixerState {
+                channels: vec![MixerChannel::new(); track_count],
+                master: 127,
+                control_changes: ControlChanges { volume: 7, pan: 10, send: 91 },
+                master_destination: MasterDestination::Tracks { cc: 12 },
+                velocity_curve: VelocityCurve::Linear,
+            },
+            views: vec![],
+            connections: vec![],
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(project(2).validate().is_ok());
+        assert!(project(0).validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.tracks[1].channel = 16;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.tracks[0].knob_values[3] = 128;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.sequences[4] = Sequence::new(4, 3);
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.mixer.master_destination = MasterDestination::Port { channel: 16, cc: 7 };
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.sequences[0].set_phrase(1, 5);
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.tracks[1].timeline.phrase_events.push(LoopablePhraseEvent::new(0, 96, 5));
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.tracks[1].phrases[2].set_length(0);
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.tracks[0].patterns[3].length = Some(0);
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.tracks[0].patterns[0].note_events.push(LoopableNoteEvent::new(0, 128, 100));
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        invalid.tracks[0].patterns[0].note_events.push(LoopableNoteEvent::new(0, 60, 128));
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = project(2);
+        let mut note = LoopableNoteEvent::new(0, 60, 100);
+        note.stop_velocity = Some(200);
+        invalid.tracks[0].patterns[0].note_events.push(note);
+        assert!(invalid.validate().is_err());
+
+        // Older projects get knobs & mixer channels they didn't have
+        let mut older = project(2);
+        older.tracks[0].knob_values.clear();
+        older.mixer.channels.clear();
+        older.complete();
+        assert!(older.validate().is_ok());
+    }
+
+    #[test]
+    fn room() {
+        let mut snapshot = Project::new();
+        let room = ProjectRoom { tracks: 2, sequences: 5, most_events: 100 };
+        assert!(! snapshot.has_room(room));
+
+        snapshot.make_room(room);
+        assert!(snapshot.has_room(room));
+        assert!(! snapshot.has_room(ProjectRoom { tracks: 3, ..room }));
+    }
+}
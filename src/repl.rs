@@ -0,0 +1,305 @@
+
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::bus::{Command, Event, Frontend};
+use super::project::Project;
+use super::config::Config;
+use super::patchbay::Patchbay;
+use super::tui::Tui;
+
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_millis(500);
+// Wait this long for the process thread to tell us how a command went
+const RESULT_TIMEOUT: Duration = Duration::from_millis(200);
+// How often we look for a signal while we wait for piped input
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+const SIG_DFL: usize = 0;
+
+extern "C" {
+    fn signal(signum: c_int, handler: usize) -> usize;
+}
+
+// Set by SIGINT & SIGTERM when input is piped, so we quit & save like we do on quit
+static IS_SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+// Default handlers are back after the first signal, so a second one stops us right away
+extern "C" fn signalled(_signum: c_int) {
+    IS_SIGNALLED.store(true, Ordering::SeqCst);
+
+    unsafe {
+        signal(SIGINT, SIG_DFL);
+        signal(SIGTERM, SIG_DFL);
+    }
+}
+
+const HELP: &str = "\
+Tracks, patterns, phrases & sequences count from 0, ticks are 1920 per beat
+  play | stop | reset                        transport
+  tempo <bpm>                                set tempo
+  queue <sequence>                           queue sequence
+  phrase <sequence> <track> <phrase|->       set phrase sequence plays on track
+  note <track> <pattern> <start> <stop> <note> [velocity]
+  clear track <track> pattern|phrase <index> clear pattern or phrase
+  clear track <track> timeline               clear timeline
+  dump pattern <track> <pattern>             print notes of pattern
+  undo | redo
+  save [file] | load <file>                  without file, save to project we started with
+  ui                                         open terminal ui
+  help | quit";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Command(Command),
+    Save(Option<PathBuf>),
+    Load(PathBuf),
+    Dump { track: usize, pattern: u8 },
+    Ui,
+    Help,
+    Quit,
+    // Empty lines & comments
+    Nothing,
+}
+
+fn number<T: std::str::FromStr>(words: &[&str], index: usize, name: &str) -> Result<T, String> {
+    let word = words.get(index).ok_or_else(|| format!("{} is missing", name))?;
+    word.parse().map_err(|_| format!("{} should be a number, got \"{}\"", name, word))
+}
+
+fn keyword(words: &[&str], index: usize, expected: &str) -> Result<(), String> {
+    match words.get(index) {
+        Some(word) if *word == expected => Ok(()),
+        Some(word) => Err(format!("expected \"{}\", got \"{}\"", expected, word)),
+        None => Err(format!("expected \"{}\"", expected)),
+    }
+}
+
+fn parse(line: &str) -> Result<Action, String> {
+    let words: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+
+    let action = match words.as_slice() {
+        [] => Action::Nothing,
+        ["play"] => Action::Command(Command::Start),
+        ["stop"] => Action::Command(Command::Stop),
+        ["reset"] => Action::Command(Command::Reset),
+        ["undo"] => Action::Command(Command::Undo),
+        ["redo"] => Action::Command(Command::Redo),
+        ["tempo", ..] => Action::Command(Command::SetTempo(number(&words, 1, "bpm")?)),
+        ["queue", ..] => Action::Command(Command::QueueSequence(number(&words, 1, "sequence")?)),
+        ["phrase", ..] => {
+            let phrase = if words.get(3) == Some(&"-") { None } else { Some(number(&words, 3, "phrase")?) };
+            Action::Command(Command::SetSequencePhrase { sequence: number(&words, 1, "sequence")?, track: number(&words, 2, "track")?, phrase })
+        },
+        ["note", ..] => Action::Command(Command::AddNote {
+            track: number(&words, 1, "track")?,
+            pattern: number(&words, 2, "pattern")?,
+            start: number(&words, 3, "start")?,
+            stop: number(&words, 4, "stop")?,
+            note: number(&words, 5, "note")?,
+            velocity: if words.len() > 6 { number(&words, 6, "velocity")? } else { 127 },
+        }),
+        ["clear", ..] => {
+            keyword(&words, 1, "track")?;
+            let track = number(&words, 2, "track")?;
+
+            match words.get(3) {
+                Some(&"pattern") => Action::Command(Command::ClearPattern { track, pattern: number(&words, 4, "pattern")? }),
+                Some(&"phrase") => Action::Command(Command::ClearPhrase { track, phrase: number(&words, 4, "phrase")? }),
+                Some(&"timeline") => Action::Command(Command::ClearTimeline { track }),
+                _ => return Err("clear what? pattern, phrase or timeline".to_string()),
+            }
+        },
+        ["dump", ..] => {
+            keyword(&words, 1, "pattern")?;
+            Action::Dump { track: number(&words, 2, "track")?, pattern: number(&words, 3, "pattern")? }
+        },
+        ["save"] => Action::Save(None),
+        ["save", file] => Action::Save(Some(PathBuf::from(file))),
+        ["load", file] => Action::Load(PathBuf::from(file)),
+        ["load"] => return Err("load what? give a file".to_string()),
+        ["ui"] => Action::Ui,
+        ["help"] => Action::Help,
+        ["quit"] | ["exit"] => Action::Quit,
+        [command, ..] => return Err(format!("unknown command \"{}\", try help", command)),
+    };
+
+    Ok(action)
+}
+
+/*
+ * Line based commands on stdin, files of commands can be piped in. Typing we quit at the end of
+ * input, like we did before there were commands. Piped input keeps us running after it ended,
+ * until it tells us to quit or we get SIGINT or SIGTERM
+ */
+pub struct Repl<'a> {
+    frontend: Frontend,
+    client: &'a jack::Client,
+    config: &'a Config,
+    patchbay: &'a Patchbay,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(frontend: Frontend, client: &'a jack::Client, config: &'a Config, patchbay: &'a Patchbay) -> Self {
+        Repl { frontend, client, config, patchbay }
+    }
+
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let is_interactive = stdin.is_terminal();
+
+        // Piped input is read on a thread of it's own, reading retries when it's interrupted by
+        // a signal, so we wait for lines & signals here. Typed input is read here, as the
+        // terminal ui reads from the terminal aswell
+        let piped_lines = if is_interactive {
+            println!("{}, type help for commands", self.client.name());
+            None
+        } else {
+            unsafe {
+                signal(SIGINT, signalled as extern "C" fn(c_int) as usize);
+                signal(SIGTERM, signalled as extern "C" fn(c_int) as usize);
+            }
+            Some(Self::read_lines())
+        };
+
+        loop {
+            let line = match &piped_lines {
+                Some(lines) => match Self::next_piped_line(lines) {
+                    Some(line) => line,
+                    None => return,
+                },
+                None => {
+                    print!("> ");
+                    io::stdout().flush().ok();
+
+                    let mut line = String::new();
+                    match stdin.lock().read_line(&mut line) {
+                        Ok(0) | Err(_) => return,
+                        Ok(_) => line,
+                    }
+                },
+            };
+
+            match parse(&line) {
+                Ok(Action::Quit) => return,
+                Ok(action) => if let Err(error) = self.execute(action) { println!("error: {}", error) },
+                Err(error) => println!("error: {}", error),
+            }
+        }
+    }
+
+    fn read_lines() -> Receiver<String> {
+        let (sender, receiver) = channel();
+
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    return
+                }
+            }
+        });
+
+        receiver
+    }
+
+    // Next line of piped input, none once we got a signal. Setup scripts pipe in their commands,
+    // we keep playing after they're done
+    fn next_piped_line(lines: &Receiver<String>) -> Option<String> {
+        loop {
+            if IS_SIGNALLED.load(Ordering::SeqCst) {
+                return None
+            }
+
+            match lines.recv_timeout(SIGNAL_INTERVAL) {
+                Ok(line) => return Some(line),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(SIGNAL_INTERVAL),
+            }
+        }
+    }
+
+    fn execute(&mut self, action: Action) -> Result<(), String> {
+        match action {
+            Action::Command(command) => self.send(command)?,
+            Action::Save(path) => {
+                let mut project = self.frontend.snapshot(SNAPSHOT_TIMEOUT)?.project;
+                let path = path.unwrap_or_else(|| self.config.project.clone());
+                project.connections = self.patchbay.connections(self.client);
+                project.save(&path)?;
+                println!("saved {}", path.display());
+            },
+            Action::Load(path) => {
+                let mut project = Project::load(&path)?;
+                let connections = mem::take(&mut project.connections);
+                self.frontend.load(project)?;
+                self.patchbay.restore(self.client, &connections)?;
+                println!("loaded {}", path.display());
+            },
+            Action::Dump { track, pattern } => {
+                let snapshot = self.frontend.snapshot(SNAPSHOT_TIMEOUT)?;
+                let track = snapshot.project.tracks.get(track).ok_or_else(|| format!("track {} does not exist", track))?;
+                let pattern = track.patterns.get(pattern as usize).ok_or_else(|| format!("pattern {} does not exist", pattern))?;
+
+                for event in pattern.note_events.iter() {
+                    println!("{:?}", event);
+                }
+            },
+            Action::Ui => Tui::new(&self.frontend, self.client, &self.config.ports.track).run()?,
+            Action::Help => println!("{}", HELP),
+            Action::Quit | Action::Nothing => (),
+        }
+
+        Ok(())
+    }
+
+    // Send command & report when process thread could not apply it
+    fn send(&self, command: Command) -> Result<(), String> {
+        // Forget what happened before
+        self.frontend.events().for_each(drop);
+        self.frontend.send(command)?;
+
+        // Status is published after commands are applied, second one is sure to come after ours
+        let started = Instant::now();
+        let mut statuses = 0;
+
+        while statuses < 2 && started.elapsed() < RESULT_TIMEOUT {
+            for event in self.frontend.events() {
+                match event {
+                    Event::Failed(failed, reason) if failed == command => return Err(reason.to_string()),
+                    Event::Status(_) => statuses += 1,
+                    _ => (),
+                }
+            }
+
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("tempo 120"), Ok(Action::Command(Command::SetTempo(120.0))));
+        assert_eq!(parse("  queue 3  # comment"), Ok(Action::Command(Command::QueueSequence(3))));
+        assert_eq!(parse("clear track 2 pattern 1"), Ok(Action::Command(Command::ClearPattern { track: 2, pattern: 1 })));
+        assert_eq!(parse("dump pattern 1 0"), Ok(Action::Dump { track: 1, pattern: 0 }));
+        assert_eq!(parse("phrase 0 1 -"), Ok(Action::Command(Command::SetSequencePhrase { sequence: 0, track: 1, phrase: None })));
+        assert_eq!(parse("save file.oct"), Ok(Action::Save(Some(PathBuf::from("file.oct")))));
+        assert_eq!(parse("# just a comment"), Ok(Action::Nothing));
+
+        assert_eq!(parse("tempo fast"), Err("bpm should be a number, got \"fast\"".to_string()));
+        assert_eq!(parse("clear track 2"), Err("clear what? pattern, phrase or timeline".to_string()));
+        assert_eq!(parse("jump"), Err("unknown command \"jump\", try help".to_string()));
+    }
+}
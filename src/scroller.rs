@@ -1,102 +1,129 @@
 
-use super::message::Message;
+use std::fmt;
 
+/*
+ * Renders text as a 5 row high bitmap, surface scrolls it over the grids of the controllers. Bitmap
+ * is part of the scroller so flashing text from the process thread doesn't allocate, text that
+ * doesn't fit is cut off
+ */
 #[derive(Debug)]
 pub struct Scroller {
-    buffer: Vec<u8>,
-    current_frame: usize,
+    bitmap: [u8; Self::HEIGHT * Self::MAX_WIDTH],
+    width: usize,
 }
 
 impl Scroller {
-    pub fn new(mut string: String) -> Self {
-        string.push_str("  ");
+    pub const HEIGHT: usize = 5;
+    // Fits about 16 letters, more than fit on the grids of 2 controllers anyway
+    const MAX_WIDTH: usize = 96;
 
-        Scroller{
-            buffer: Scroller::get_text_as_vector(string),
-            current_frame: 0,
-        }
+    pub fn new() -> Self {
+        Scroller { bitmap: [0; Self::HEIGHT * Self::MAX_WIDTH], width: 0 }
     }
 
-    // Increase framecount
-    pub fn next_frame(&mut self) {
-        self.current_frame += 1;
+    // Replace text, use with format_args! so numbers can be shown without allocating
+    pub fn set_text(&mut self, text: fmt::Arguments) {
+        self.width = 0;
+        // Only fails when we ran out of room, which cuts off text
+        fmt::Write::write_fmt(self, text).ok();
     }
 
-    pub fn print_frame(&mut self, buffer: &mut Vec<Message>)  {
-        for x in 0..8 {
-            for y in 0..5 {
-                // Get index in our buffer that we should show in frame
-                let index = (x + self.current_frame) % (self.buffer.len() / 5) + (self.buffer.len() / 5 * y);
-
-                let message = Message::Note(
-                    0,
-                    [0x90 + x as u8, 0x35 + y as u8, self.buffer[index]],
-                );
-        
-                buffer.push(message);
-            }
-        }
+    // Width of text in leds
+    pub fn width(&self) -> usize { self.width }
+
+    // Is led in column x lit on row, counted from the top
+    pub fn is_lit(&self, x: usize, row: usize) -> bool {
+        x < self.width && row < Self::HEIGHT && self.bitmap[row * Self::MAX_WIDTH + x] > 0
     }
 
-    // Get string as letter vector
-    pub fn get_text_as_vector(text: String) -> Vec<u8> {
-        // Get letters on each row
-        (0..5)
-            .map(|row| {
-                text.chars()
-                    .map(|letter| { 
-                        // Get letter vector
-                        let vec = Scroller::get_letter(letter);
-                        // Get width of letter
-                        let width = vec.len() / 5;
-
-                        // Return slice of it based on row
-                        let mut sliced = vec[std::ops::Range { start: row * width, end: (row + 1) * width }].to_vec();
-                        // Add whitespace
-                        sliced.push(0);
-                        // Return slice
-                        sliced
-                    })
-                    // Fold into one vector
-                    .fold(Vec::new(), |mut acc, mut x| { acc.append(&mut x); acc })
-            })
-            // Fold into one vector
-            .fold(Vec::new(), |mut acc, mut x| { acc.append(&mut x); acc })
+    // Copy letter behind text, followed by a column of whitespace
+    fn push_letter(&mut self, letter: char) -> fmt::Result {
+        let glyph = Self::glyph(letter.to_ascii_lowercase());
+        let width = glyph.len() / Self::HEIGHT;
+
+        if self.width + width + 1 > Self::MAX_WIDTH {
+            return Err(fmt::Error);
+        }
+
+        for row in 0 .. Self::HEIGHT {
+            let start = row * Self::MAX_WIDTH + self.width;
+            self.bitmap[start .. start + width].copy_from_slice(&glyph[row * width .. (row + 1) * width]);
+            self.bitmap[start + width] = 0;
+        }
+
+        self.width += width + 1;
+        Ok(())
     }
 
-    pub fn get_letter(letter: char) -> Vec<u8> {
+    fn glyph(letter: char) -> &'static [u8] {
         match letter {
-            '0' => vec![0, 1, 0, 1, 0, 1, 1, 0, 1, 1, 0, 1, 0, 1, 0],
-            '.' => vec![0, 0, 0, 0, 1],
-            '\'' => vec![1, 1, 0, 0, 0],
-            '!' => vec![1, 1, 1, 0, 1],
-            'a' => vec![1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 0, 1],
-            'b' => vec![1, 1, 0, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1],
-            'c' => vec![1, 1, 1, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1, 1],
-            'd' => vec![1, 1, 0, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 1, 1],
-            'e' => vec![1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 1, 1],
-            'f' => vec![1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 0, 0],
-            'g' => vec![1, 1, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 1, 1, 1],
-            'h' => vec![1, 0, 1, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 0, 1],
-            'i' => vec![1, 1, 1, 1, 1],
-            'j' => vec![1, 1, 1, 0, 0, 1, 0, 0, 1, 1, 0, 1, 0, 1, 1],
-            'k' => vec![1, 0, 1, 1, 0, 1, 1, 1, 0, 1, 0, 1, 1, 0, 1],
-            'l' => vec![1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1, 1],
-            'm' => vec![1, 1, 1, 1, 0, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1],
-            'n' => vec![1, 1, 0, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1],
-            'o' => vec![1, 1, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 1, 1],
-            'p' => vec![1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 0, 1, 0, 0],
-            'q' => vec![1, 1, 1, 1, 0, 1, 1, 1, 1, 0, 0, 1, 0, 0, 1],
-            'r' => vec![1, 1, 1, 1, 0, 1, 1, 1, 0, 1, 0, 1, 1, 0, 1],
-            's' => vec![1, 1, 1, 1, 0, 0, 1, 1, 1, 0, 0, 1, 1, 1, 1],
-            't' => vec![1, 1, 1, 0, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 0],
-            'u' => vec![1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 1, 1],
-            'v' => vec![1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1, 0, 1, 0],
-            'w' => vec![1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 0, 1, 1, 1, 0],
-            'x' => vec![1, 0, 1, 1, 0, 1, 0, 1, 0, 1, 0, 1, 1, 0, 1],
-            'y' => vec![1, 0, 1, 1, 0, 1, 1, 1, 1, 0, 1, 0, 0, 1, 0],
-            'z' => vec![1, 1, 1, 0, 0, 1, 0, 1, 0, 1, 0, 0, 1, 1, 1],
-            _ => vec![0, 0, 0, 0, 0, 0, 0, 0, 0],
+            '0' => &[0, 1, 0, 1, 0, 1, 1, 0, 1, 1, 0, 1, 0, 1, 0],
+            '1' => &[0, 1, 0, 1, 1, 0, 0, 1, 0, 0, 1, 0, 1, 1, 1],
+            '2' => &[1, 1, 1, 0, 0, 1, 1, 1, 1, 1, 0, 0, 1, 1, 1],
+            '3' => &[1, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 1, 1],
+            '4' => &[1, 0, 1, 1, 0, 1, 1, 1, 1, 0, 0, 1, 0, 0, 1],
+            '5' => &[1, 1, 1, 1, 0, 0, 1, 1, 1, 0, 0, 1, 1, 1, 1],
+            '6' => &[1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 1, 1, 1, 1],
+            '7' => &[1, 1, 1, 0, 0, 1, 0, 1, 0, 0, 1, 0, 0, 1, 0],
+            '8' => &[1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1],
+            '9' => &[1, 1, 1, 1, 0, 1, 1, 1, 1, 0, 0, 1, 1, 1, 1],
+            '-' => &[0, 0, 0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 0],
+            '.' => &[0, 0, 0, 0, 1],
+            '\'' => &[1, 1, 0, 0, 0],
+            '!' => &[1, 1, 1, 0, 1],
+            'a' => &[1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 0, 1],
+            'b' => &[1, 1, 0, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1],
+            'c' => &[1, 1, 1, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1, 1],
+            'd' => &[1, 1, 0, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 1, 1],
+            'e' => &[1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 1, 1],
+            'f' => &[1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0, 1, 0, 0],
+            'g' => &[1, 1, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 1, 1, 1],
+            'h' => &[1, 0, 1, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 0, 1],
+            'i' => &[1, 1, 1, 1, 1],
+            'j' => &[1, 1, 1, 0, 0, 1, 0, 0, 1, 1, 0, 1, 0, 1, 1],
+            'k' => &[1, 0, 1, 1, 0, 1, 1, 1, 0, 1, 0, 1, 1, 0, 1],
+            'l' => &[1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1, 1],
+            'm' => &[1, 1, 1, 1, 0, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1],
+            'n' => &[1, 1, 0, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1],
+            'o' => &[1, 1, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 1, 1],
+            'p' => &[1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 0, 1, 0, 0],
+            'q' => &[1, 1, 1, 1, 0, 1, 1, 1, 1, 0, 0, 1, 0, 0, 1],
+            'r' => &[1, 1, 1, 1, 0, 1, 1, 1, 0, 1, 0, 1, 1, 0, 1],
+            's' => &[1, 1, 1, 1, 0, 0, 1, 1, 1, 0, 0, 1, 1, 1, 1],
+            't' => &[1, 1, 1, 0, 1, 0, 0, 1, 0, 0, 1, 0, 0, 1, 0],
+            'u' => &[1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 1, 1],
+            'v' => &[1, 0, 1, 1, 0, 1, 1, 0, 1, 1, 0, 1, 0, 1, 0],
+            'w' => &[1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 1, 0, 1, 0, 1, 0, 1, 1, 1, 0],
+            'x' => &[1, 0, 1, 1, 0, 1, 0, 1, 0, 1, 0, 1, 1, 0, 1],
+            'y' => &[1, 0, 1, 1, 0, 1, 1, 1, 1, 0, 1, 0, 0, 1, 0],
+            'z' => &[1, 1, 1, 0, 0, 1, 0, 1, 0, 1, 0, 0, 1, 1, 1],
+            _ => &[0, 0, 0, 0, 0, 0, 0, 0, 0],
         }
     }
 }
+
+impl fmt::Write for Scroller {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        text.chars().try_for_each(|letter| self.push_letter(letter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let mut scroller = Scroller::new();
+        scroller.set_text(format_args!("Seq {}", 3));
+
+        // Letters are 3 wide & space 1, all followed by whitespace
+        assert_eq!(scroller.width(), 18);
+        assert!(scroller.is_lit(0, 0) && ! scroller.is_lit(3, 0));
+        // Bottom row of 3
+        assert!(scroller.is_lit(14, 4) && scroller.is_lit(16, 4) && ! scroller.is_lit(17, 4));
+
+        scroller.set_text(format_args!("{}", "wwwwwwwwwwwwwwwwwwww"));
+        assert_eq!(scroller.width(), 96);
+    }
+}
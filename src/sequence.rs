@@ -1,22 +1,45 @@
 
+use serde::{Serialize, Deserialize};
 use super::track::Track;
 use super::loopable::*;
 
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Sequence {
-    // Phrase that's playing for track, array index = track
-    phrases: [Option<u8>; 16],
-    active: [bool; 16],
+    // Phrase that's playing for track, vec index = track
+    phrases: Vec<Option<u8>>,
+    active: Vec<bool>,
 }
 
 impl Sequence {
-    pub fn new(phrase: u8) -> Self {
+    pub fn new(phrase: u8, track_count: usize) -> Self {
         Sequence {
-            phrases: [Some(phrase); 16],
-            active: [true; 16],
+            phrases: vec![Some(phrase); track_count],
+            active: vec![true; track_count],
         }
     }
 
-    pub fn phrases(&self) -> &[Option<u8>; 16] {
+    // Make room for tracks up front
+    pub fn reserve(&mut self, track_count: usize) {
+        self.phrases.reserve(track_count);
+        self.active.reserve(track_count);
+    }
+
+    // Copy other sequence, reusing memory we have
+    pub fn copy_from(&mut self, other: &Self) {
+        self.phrases.clone_from(&other.phrases);
+        self.active.clone_from(&other.active);
+    }
+
+    pub fn has_room(&self, track_count: usize) -> bool {
+        self.phrases.capacity() >= track_count && self.active.capacity() >= track_count
+    }
+
+    // Sequences have a phrase & active flag for every track
+    pub fn fits(&self, track_count: usize) -> bool {
+        self.phrases.len() == track_count && self.active.len() == track_count
+    }
+
+    pub fn phrases(&self) -> &[Option<u8>] {
         &self.phrases
     }
 
@@ -25,7 +48,7 @@ impl Sequence {
     }
 
     pub fn set_phrases(&mut self, phrase: u8) {
-        self.phrases = [Some(phrase); 16];
+        self.phrases.iter_mut().for_each(|option| *option = Some(phrase));
     }
 
     pub fn set_phrase(&mut self, track: usize, phrase: u8) {
@@ -44,6 +67,17 @@ impl Sequence {
         self.active[track as usize] = ! self.active[track as usize];
     }
 
+    // New tracks start out empty, so adding a track does not change what's playing
+    pub fn add_track(&mut self) {
+        self.phrases.push(None);
+        self.active.push(true);
+    }
+
+    pub fn remove_track(&mut self) {
+        self.phrases.pop();
+        self.active.pop();
+    }
+
     pub fn length(&self, tracks: &[Track]) -> u32 {
         self.phrases().iter().enumerate()
             .filter_map(|(track_index, phrase_option)| {
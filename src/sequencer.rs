@@ -1,59 +1,77 @@
 
+use std::sync::mpsc::{SyncSender, TrySendError};
 use super::TickRange;
 use super::cycle::*;
-use super::track::Track;
+use super::port::{self, PortRequest};
+use super::error::StartupError;
+use super::mixer::Mixer;
+use super::track::{Track, TrackState};
 use super::sequence::Sequence;
 use super::loopable::*;
 use super::events::*;
+use super::history::*;
+use super::message::TimedMessage;
 
 pub struct Sequencer {
-    pub tracks: [Track; 16],
+    pub tracks: Vec<Track>,
     pub sequences: [Sequence; 5],
 
     pub sequence_playing: usize,
     pub sequence_queued: Option<usize>,
     pub last_sequence_started: u32,
+
+    // Tracks are added once main thread registered their ports
+    port_requests: SyncSender<PortRequest>,
+    tracks_requested: usize,
+    // Timebase handler picks up tempo changes
+    tempo: SyncSender<f64>,
+
+    history: History,
+
+    // Buffers for notes & messages of tracks
+    notes: Vec<PlayingNoteEvent>,
+    messages: Vec<TimedMessage>,
 }
 
 impl Sequencer {
-    pub fn new(client: &jack::Client) -> Self {
-        // Build tracks array, shame there's no way to do this elegantly without a macro as far as i can tell
-        let tracks = [
-            Track::new(client, 1),
-            Track::new(client, 2),
-            Track::new(client, 3),
-            Track::new(client, 4),
-            Track::new(client, 5),
-            Track::new(client, 6),
-            Track::new(client, 7),
-            Track::new(client, 8),
-            Track::new(client, 9),
-            Track::new(client, 10),
-            Track::new(client, 11),
-            Track::new(client, 12),
-            Track::new(client, 13),
-            Track::new(client, 14),
-            Track::new(client, 15),
-            Track::new(client, 16),
-        ];
+    const BUFFER_CAPACITY: usize = 1024;
+    // Most tracks we can have, per track state is allocated up front for this many tracks
+    pub const TRACK_CAPACITY: usize = 64;
+
+    pub fn new(client: &jack::Client, track_count: usize, track_port: &str, port_requests: SyncSender<PortRequest>, tempo: SyncSender<f64>) -> Result<Self, StartupError> {
+        let mut tracks = Vec::with_capacity(Self::TRACK_CAPACITY);
+        for index in 0 .. track_count {
+            let output = port::register_port(client, &Track::port_name(track_port, index), jack::MidiOut::default())?;
+            tracks.push(Track::new(output));
+        }
 
         // Build sequence we can trigger
-        let sequences = [
-            Sequence::new(0),
-            Sequence::new(1),
-            Sequence::new(2),
-            Sequence::new(3),
-            Sequence::new(4),
+        let mut sequences = [
+            Sequence::new(0, track_count),
+            Sequence::new(1, track_count),
+            Sequence::new(2, track_count),
+            Sequence::new(3, track_count),
+            Sequence::new(4, track_count),
         ];
+        sequences.iter_mut().for_each(|sequence| sequence.reserve(Self::TRACK_CAPACITY));
 
-        Sequencer {
+        Ok(Sequencer {
             tracks,
             sequences,
 
             sequence_playing: 0,
             sequence_queued: None,
             last_sequence_started: 0,
-        }
+
+            port_requests,
+            tracks_requested: 0,
+            tempo,
+
+            history: History::new(),
+
+            notes: Vec::with_capacity(Self::BUFFER_CAPACITY),
+            messages: Vec::with_capacity(Self::BUFFER_CAPACITY),
+        })
     }
 
     pub fn track_mut(&mut self, index: usize) -> &mut Track {
@@ -64,10 +82,131 @@ impl Sequencer {
         &self.tracks[index]
     }
 
+    pub fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    // Per track state with room for all tracks we could get, so adding tracks does not allocate
+    pub fn track_vec<T: Clone>(value: T, track_count: usize) -> Vec<T> {
+        let mut values = Vec::with_capacity(Self::TRACK_CAPACITY);
+        values.resize(track_count, value);
+        values
+    }
+
+    // Ask main thread for a new track, track is added when it arrives in add_track. Returns wether
+    // track was requested
+    pub fn request_track(&mut self) -> bool {
+        let index = self.tracks.len() + self.tracks_requested;
+        if index >= Self::TRACK_CAPACITY {
+            return false
+        }
+
+        let is_requested = self.port_requests.try_send(PortRequest::Register(index)).is_ok();
+        if is_requested {
+            self.tracks_requested += 1;
+        }
+        is_requested
+    }
+
+    // Tracks we have once the main thread built the tracks we asked for
+    pub fn track_count_requested(&self) -> usize {
+        self.tracks.len() + self.tracks_requested
+    }
+
+    // Main thread could not register port for requested track
+    pub fn track_request_failed(&mut self) {
+        if self.tracks_requested > 0 {
+            self.tracks_requested -= 1;
+        }
+    }
+
+    // Tracks are built on the main thread, as building them allocates
+    pub fn add_track(&mut self, track: Track) {
+        if self.tracks_requested > 0 {
+            self.tracks_requested -= 1;
+        }
+
+        self.tracks.push(track);
+        self.sequences.iter_mut().for_each(|sequence| sequence.add_track());
+        self.history.clear();
+    }
+
+    /*
+     * Remove last track, we always keep one track around. Main thread unregisters the port of the
+     * track & frees it, when there's no room to send it there we keep the track. Returns wether
+     * track was removed
+     */
+    pub fn remove_track(&mut self) -> bool {
+        if self.tracks.len() <= 1 {
+            return false
+        }
+
+        let track = match self.tracks.pop() {
+            Some(track) => track,
+            None => return false,
+        };
+
+        match self.port_requests.try_send(PortRequest::Unregister(track)) {
+            Ok(()) => {
+                self.sequences.iter_mut().for_each(|sequence| sequence.remove_track());
+                self.history.clear();
+                true
+            },
+            // Tracks have room for all tracks, putting it back does not allocate
+            Err(TrySendError::Full(request)) | Err(TrySendError::Disconnected(request)) => {
+                if let PortRequest::Unregister(track) = request {
+                    self.tracks.push(track);
+                }
+                false
+            },
+        }
+    }
+
+    // Restore saved tracks & sequences, what our tracks had ends up in tracks so it can be freed
+    // off the process thread. Sequences are copied as they have room for all tracks. Edits of what
+    // we had can't be undone anymore
+    pub fn swap_state(&mut self, tracks: &mut [TrackState], sequences: &[Sequence]) {
+        for (track, state) in self.tracks.iter_mut().zip(tracks.iter_mut()) {
+            track.swap_state(state);
+            track.resend_knobs();
+        }
+
+        for (sequence, saved) in self.sequences.iter_mut().zip(sequences.iter()) {
+            sequence.copy_from(saved);
+        }
+
+        self.history.clear();
+    }
+
+    // Remember target before editing it, so edit can be undone
+    pub fn remember(&mut self, target: EditTarget) {
+        self.history.remember(target, &self.tracks, &self.sequences);
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.history.undo(&mut self.tracks, &mut self.sequences)
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.history.redo(&mut self.tracks, &mut self.sequences)
+    }
+
     pub fn get_sequence(&mut self, index: usize) -> &mut Sequence {
         &mut self.sequences[index]
     }
 
+    // Track is heard when it's not muted and no other track is soloed
+    pub fn is_track_audible(&self, index: usize) -> bool {
+        let track = &self.tracks[index];
+        let is_any_soloed = self.tracks.iter().any(|track| track.soloed);
+
+        ! track.muted && (! is_any_soloed || track.soloed)
+    }
+
+    pub fn set_tempo(&self, beats_per_minute: f64) {
+        self.tempo.try_send(beats_per_minute).ok();
+    }
+
     pub fn start(&mut self, cycle: &ProcessCycle) {
         // Start playing notes, as it could be we halted mid track
         self.tracks.iter_mut().for_each(|track| {
@@ -96,7 +235,15 @@ impl Sequencer {
         });
     }
 
+    // Clearing timelines of all tracks is undone in one go, only timelines that have phrases are
+    // remembered so the step takes as little history as it can
     pub fn reset_timeline(&mut self) {
+        let tracks = &self.tracks;
+        let targets = (0 .. tracks.len())
+            .filter(|index| ! tracks[*index].timeline.phrase_events.is_empty())
+            .map(EditTarget::Timeline);
+        self.history.record_step(targets, tracks, &self.sequences);
+
         self.tracks.iter_mut().for_each(|track| {
             track.timeline.clear_events();
         });
@@ -110,12 +257,12 @@ impl Sequencer {
         let sequence_length = sequence.length(&self.tracks);
         let stop = start + sequence_length;
 
-        let active_phrases: Vec<(usize, u8)> = sequence.phrases().iter().enumerate()
-            .filter(|(_, phrase_option)| phrase_option.is_some())
-            .map(|(track_index, phrase_option)| (track_index, phrase_option.unwrap()))
-            .collect();
-        
-        for (track_index, phrase_index) in active_phrases {
+        for track_index in 0 .. self.tracks.len() {
+            let phrase_index = match self.sequences[sequence_index].get_phrase(track_index) {
+                Some(phrase_index) => phrase_index,
+                None => continue,
+            };
+
             let mut phrase_start = start;
             let phrase_length = self.track(track_index).phrase(phrase_index).length();
 
@@ -125,6 +272,7 @@ impl Sequencer {
 
                 let event = LoopablePhraseEvent::new(phrase_start, phrase_stop, phrase_index);
                 self.track_mut(track_index).timeline.add_complete_event(event);
+                self.history.add_phrase_event(track_index, event);
 
                 phrase_start += phrase_length;
             }
@@ -137,7 +285,7 @@ impl Sequencer {
         self.tracks.iter()
             .map(|track| track.timeline.get_last_stop())
             .max()
-            .unwrap()
+            .unwrap_or(0)
     }
 
     pub fn autoqueue_next_sequence(&mut self, cycle: &ProcessCycle) {
@@ -154,34 +302,35 @@ impl Sequencer {
     }
 
     // Get tick ranges of phrases that are playing in current cycle
-    pub fn playing_phrases(&self, track_index: usize, tick_range: &TickRange) -> Vec<(TickRange, u32, u8)> {
+    pub fn playing_phrases(&self, track_index: usize, tick_range: &TickRange) -> impl Iterator<Item = (TickRange, u32, u8)> + '_ {
+        let tick_range = *tick_range;
+
         // Get phrase events that fall in tick_range
         self.track(track_index).timeline.events().iter()
             .filter(|event| event.stop().is_some())
-            .filter(|event| tick_range.overlaps(&TickRange::new(event.start(), event.stop().unwrap())))
+            .filter(move |event| tick_range.overlaps(&TickRange::new(event.start(), event.stop().unwrap())))
             // Only play start or only play end when they fall within tick_range
-            .map(|event| {
+            .map(move |event| {
                 if tick_range.contains(event.stop().unwrap()) {
                     (TickRange::new(tick_range.start, event.stop().unwrap()), event.start(), event.phrase)
                 } else if tick_range.contains(event.start()) {
                     (TickRange::new(event.start(), tick_range.stop), event.start(), event.phrase)
                 } else {
-                    (*tick_range, event.start(), event.phrase)
+                    (tick_range, event.start(), event.phrase)
                 }
             })
-            .collect()
     }
 
     // Get tick ranges of patterns that are playing in tick_range
-    pub fn playing_patterns(&self, tick_range: &TickRange, track_index: usize, phrase_index: u8, sequence_start: u32) -> Vec<(u8, u32, TickRange, u32, u32)> {
+    pub fn playing_patterns(&self, tick_range: &TickRange, track_index: usize, phrase_index: u8, sequence_start: u32) -> impl Iterator<Item = (u8, u32, TickRange, u32, u32)> + '_ {
         let track = &self.tracks[track_index];
         let phrase = track.phrase(phrase_index);
 
         // Get range relative to sequence
-        let sequence_range = TickRange::new(tick_range.start - sequence_start, tick_range.stop - sequence_start);
+        let sequence_range = TickRange::new(tick_range.start.saturating_sub(sequence_start), tick_range.stop.saturating_sub(sequence_start));
         let phrase_ranges = phrase.looping_ranges(&sequence_range);
 
-        phrase_ranges.into_iter()
+        phrase_ranges
             .flat_map(move |(phrase_range, phrase_offset)| {
                 phrase.pattern_events.iter()
                     // Only pattern events that stop
@@ -191,7 +340,7 @@ impl Sequencer {
                     // Looping ranges are 2 ranges, start & end. Get absolute ranges and their
                     // corresponding offset in the pattern
                     .flat_map(move |pattern_event| {
-                        pattern_event.absolute_tick_ranges(phrase.length()).into_iter()
+                        pattern_event.absolute_tick_ranges(phrase.length())
                             .filter(move |(pattern_event_range, _)| pattern_event_range.overlaps(&phrase_range))
                             .map(move |(pattern_event_range, pattern_event_offset)| {
                                 let pattern_event_length = pattern_event.length(phrase.length());
@@ -211,40 +360,54 @@ impl Sequencer {
                             })
                     })
             })
-            .collect()
+    }
+
+    // Add notes starting in this cycle for track to notes
+    fn starting_notes(&self, cycle: &ProcessCycle, track_index: usize, notes: &mut Vec<PlayingNoteEvent>) {
+        let playing_phrases = self.playing_phrases(track_index, &cycle.tick_range);
+
+        let starting_notes = playing_phrases
+            .flat_map(|(tick_range, sequence_start, phrase_index)| {
+                // TODO - Make the switch to first getting pattern events, then converting
+                // those to notes
+                self.playing_patterns(&tick_range, track_index, phrase_index, sequence_start)
+                    .flat_map(move |(pattern_index, absolute_start, relative_range, pattern_event_length, absolute_offset)| {
+                        let pattern = self.tracks[track_index].pattern(pattern_index);
+
+                        // Get pattern based starting notes, and add offset based on phrase
+                        // iteration & sequence start
+                        pattern.starting_notes(absolute_start, relative_range, pattern_event_length)
+                            .map(move |mut playing_note| {
+                                playing_note.start += absolute_offset;
+                                playing_note.stop += absolute_offset;
+                                playing_note
+                            })
+                    })
+            });
+
+        notes.extend(starting_notes);
     }
 
     // TODO - Direct queueing
-    pub fn output_midi(&mut self, cycle: &ProcessCycle) {
-        if ! cycle.is_rolling {
-            return
-        }
+    pub fn output_midi(&mut self, cycle: &ProcessCycle, mixer: &mut Mixer) {
+        // Buffers are reused every track & cycle, so we don't allocate on the process thread
+        let mut notes = std::mem::take(&mut self.notes);
+        let mut messages = std::mem::take(&mut self.messages);
 
         for track_index in 0 .. self.tracks.len() {
-            let playing_phrases = self.playing_phrases(track_index, &cycle.tick_range);
-
-            //let mut starting_notes = vec![];
-            let notes: Vec<PlayingNoteEvent> = playing_phrases.into_iter()
-                .flat_map(|(tick_range, sequence_start, phrase_index)| {
-                    // TODO - Make the switch to first getting pattern events, then converting
-                    // those to notes
-                    self.playing_patterns(&tick_range, track_index, phrase_index, sequence_start).into_iter()
-                        .flat_map(|(pattern_index, absolute_start, relative_range, pattern_event_length, absolute_offset)| {
-                            let pattern = self.tracks[track_index].pattern(pattern_index);
-
-                            // Get pattern based starting notes, and add offset based on phrase
-                            // iteration & sequence start
-                            pattern.starting_notes(absolute_start, relative_range, pattern_event_length).into_iter()
-                                .map(move |mut playing_note| {
-                                    playing_note.start += absolute_offset;
-                                    playing_note.stop += absolute_offset;
-                                    playing_note
-                                })
-                        })
-                })
-                .collect();
-
-            self.tracks[track_index].output_midi(cycle, notes);
+            // Mixer values are sent to the track output, also when we're not rolling
+            mixer.track_messages(track_index, self.tracks[track_index].channel, &mut messages);
+            if cycle.is_rolling {
+                self.starting_notes(cycle, track_index, &mut notes);
+            }
+            mixer.scale_velocities(track_index, &mut notes);
+
+            let is_audible = self.is_track_audible(track_index);
+            // Track drains both buffers
+            self.tracks[track_index].output_midi(cycle, &mut notes, is_audible, &mut messages);
         }
+
+        self.notes = notes;
+        self.messages = messages;
     }
 }
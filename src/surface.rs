@@ -1,7 +1,10 @@
 
+use std::fmt;
+use serde::{Serialize, Deserialize};
 use super::controller::input::*;
 use super::TimebaseHandler;
 use super::Sequencer;
+use super::scroller::Scroller;
 use super::loopable::*;
 use super::events::*;
 
@@ -12,6 +15,13 @@ pub enum View {
     Timeline,
 }
 
+// What the activator, solo & arm rows are used for
+#[derive(Debug, PartialEq)]
+pub enum RowMode {
+    Loopable,
+    Mixer,
+}
+
 pub enum TrackView {
     Split,
     Pattern,
@@ -24,17 +34,46 @@ pub enum LoopableType {
     Phrase { shown: [u8; 16] },
     Pattern { shown: [u8; 16] },
 }
+// Zoom & scroll position of a loopable, so loopables are shown the way we left them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LoopableView {
+    pub offset_x: u32,
+    pub offset_y: u8,
+    pub zoom_level: u8,
+    // Part of loopable view stays in, every loopable has it's own
+    #[serde(default)]
+    pub view_loop: Option<(u32, u32)>,
+}
+
+// Views of every pattern & phrase of a track
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackViews {
+    pub patterns: [LoopableView; 5],
+    pub phrases: [LoopableView; 5],
+}
+
+impl TrackViews {
+    pub fn new() -> Self {
+        let pattern = LoopableView { offset_x: 0, offset_y: Surface::DEFAULT_BASE_NOTE, zoom_level: 4, view_loop: None };
+        let phrase = LoopableView { offset_x: 0, offset_y: 0, zoom_level: 4, view_loop: None };
+
+        TrackViews { patterns: [pattern; 5], phrases: [phrase; 5] }
+    }
+}
+
 pub struct LoopableGrid {
     offset_x: u32,
     offset_y: u8,
     zoom_level: u8,
     ticks_per_button: u32,
+    // Start & stop tick of part of loopable view stays in
+    view_loop: Option<(u32, u32)>,
     pub loopable_type: LoopableType,
 }
 
 impl LoopableGrid {
     pub fn new(loopable_type: LoopableType, offset_y: u8, ticks_per_button: u32) -> Self {
-        Self { offset_x: 0, offset_y, zoom_level: 4, ticks_per_button, loopable_type }
+        Self { offset_x: 0, offset_y, zoom_level: 4, ticks_per_button, view_loop: None, loopable_type }
     }
 
     pub fn ticks_per_button(&self) -> u32 {
@@ -50,11 +89,19 @@ impl LoopableGrid {
     }
 
     pub fn set_offset_x(&mut self, ticks: u32, max: u32) { 
+        // Moving out of view loop takes us back to start of loop
+        let ticks = match self.view_loop {
+            Some((start, stop)) if ticks < start || ticks >= stop => start,
+            _ => ticks,
+        };
         let adjusted_offset = (ticks / self.ticks_per_button()) * self.ticks_per_button();
         self.offset_x = if adjusted_offset < max { adjusted_offset } else { max };
     }
     pub fn offset_x(&self) -> u32 { self.offset_x }
 
+    pub fn view_loop(&self) -> Option<(u32, u32)> { self.view_loop }
+    pub fn set_view_loop(&mut self, view_loop: Option<(u32, u32)>) { self.view_loop = view_loop }
+
     pub fn set_offset_y(&mut self, offset: u8) { 
         let offset = match self.loopable_type {
             LoopableType::Pattern { .. } => {
@@ -68,6 +115,20 @@ impl LoopableGrid {
     pub fn offset_y(&self) -> u8 { self.offset_y }
 
     pub fn zoom_level(&self) -> u8 { self.zoom_level }
+
+    pub fn view(&self) -> LoopableView {
+        LoopableView { offset_x: self.offset_x, offset_y: self.offset_y, zoom_level: self.zoom_level, view_loop: self.view_loop }
+    }
+
+    // Views could come from a project file, keep them in range
+    pub fn set_view(&mut self, view: LoopableView) {
+        if view.zoom_level >= 1 && view.zoom_level <= 8 {
+            self.set_zoom_level(view.zoom_level);
+        }
+        self.set_offset_y(view.offset_y);
+        self.offset_x = (view.offset_x / self.ticks_per_button()) * self.ticks_per_button();
+        self.view_loop = view.view_loop.filter(|(start, stop)| start < stop);
+    }
     pub fn set_zoom_level(&mut self, level: u8) {
         // Why not support 7?
         if level != 7 {
@@ -79,6 +140,7 @@ impl LoopableGrid {
 
 pub struct Surface {
     pub view: View,
+    pub row_mode: RowMode,
     pub button_memory: ButtonMemory,
     pub event_memory: EventMemory,
 
@@ -86,46 +148,147 @@ pub struct Surface {
     pub phrase_grid: LoopableGrid,
     pub timeline_grid: LoopableGrid,
 
-    track_shown: u8,
+    track_shown: usize,
+    track_bank: usize,
+    // Amount of tracks shown at once by all controllers together
+    track_bank_size: usize,
     sequence_shown: u8,
 
-    phrase_shown: [u8; 16],
-    phrase_zoom_level: u8,
-    phrase_offsets: [u32; 16],
+    phrase_shown: Vec<u8>,
+    pattern_shown: Vec<u8>,
+    // Grids show shown loopables, views of other loopables are kept here until they are shown
+    views: Vec<TrackViews>,
 
-    pattern_shown: [u8; 16],
-    pattern_zoom_level: u8,
-    pattern_offsets: [u32; 16],
-    pattern_base_notes: [u8; 16],
+    // Page of device knobs per track
+    knob_page_shown: Vec<u8>,
+
+    // Text flashed over the grids & when we started showing it, none when nothing's flashing
+    flash: Scroller,
+    flash_start: Option<u64>,
+    tap_times: Vec<u64>,
+    // Process thread passes project to main thread to save it
+    pub save_requested: bool,
 }
 
 impl Surface {
-
-    pub fn new() -> Self {
+    // Flashed text stands still for a bit before scrolling out of view
+    const FLASH_HOLD_USECS: u64 = 800000;
+    const FLASH_SCROLL_USECS: u64 = 80000;
+    // Taps further apart start a new tempo
+    const TAP_TEMPO_TIMEOUT_USECS: u64 = 2000000;
+    const TAP_TEMPO_TAPS: usize = 4;
+    // Base note puts C in center row of the APC's
+    pub const DEFAULT_BASE_NOTE: u8 = 58;
+
+    pub fn new(track_count: usize, track_bank_size: usize) -> Self {
         let pattern_ticks_per_button = TimebaseHandler::TICKS_PER_BEAT as u32 * 2;
         let phrase_ticks_per_button = pattern_ticks_per_button * 4;
         let timeline_ticks_per_button = phrase_ticks_per_button * 4;
 
         Surface { 
             view: View::Track, 
+            row_mode: RowMode::Loopable,
             button_memory: ButtonMemory::new(),
             event_memory: EventMemory::new(),
 
-            pattern_grid: LoopableGrid::new(LoopableType::Pattern { shown: [0; 16] }, 58, pattern_ticks_per_button),
+            pattern_grid: LoopableGrid::new(LoopableType::Pattern { shown: [0; 16] }, Self::DEFAULT_BASE_NOTE, pattern_ticks_per_button),
             phrase_grid: LoopableGrid::new(LoopableType::Phrase { shown: [0; 16] }, 0, phrase_ticks_per_button),
             timeline_grid: LoopableGrid::new(LoopableType::Timeline, 0, timeline_ticks_per_button),
 
             track_shown: 0,
+            track_bank: 0,
+            track_bank_size,
             sequence_shown: 0,
 
-            phrase_shown: [0; 16],
-            phrase_zoom_level: 4,
-            phrase_offsets: [0; 16],
+            phrase_shown: Sequencer::track_vec(0, track_count),
+            pattern_shown: Sequencer::track_vec(0, track_count),
+            views: Sequencer::track_vec(TrackViews::new(), track_count),
+
+            knob_page_shown: Sequencer::track_vec(0, track_count),
+
+            flash: Scroller::new(),
+            flash_start: None,
+            tap_times: Vec::with_capacity(Self::TAP_TEMPO_TAPS + 1),
+            save_requested: false,
+        }
+    }
+
+    // Show text on grids of controllers, replacing what was flashed before
+    pub fn flash(&mut self, text: fmt::Arguments, usecs: u64) {
+        self.flash.set_text(text);
+        self.flash_start = Some(usecs);
+    }
+
+    // Get flashed text & the column of text that should be shown on first column of the grids,
+    // grids are given back to the controllers once text scrolled out of view
+    pub fn flash_frame(&mut self, usecs: u64) -> Option<(&Scroller, usize)> {
+        let start = self.flash_start?;
+        let scrolled = usecs.saturating_sub(start).saturating_sub(Self::FLASH_HOLD_USECS) / Self::FLASH_SCROLL_USECS;
+
+        if scrolled as usize > self.flash.width() {
+            self.flash_start = None;
+            None
+        } else {
+            Some((&self.flash, scrolled as usize))
+        }
+    }
+
+    // Register tap, returns tempo once we've got enough taps
+    pub fn tap_tempo(&mut self, usecs: u64) -> Option<f64> {
+        if let Some(last) = self.tap_times.last() {
+            if usecs.saturating_sub(*last) > Self::TAP_TEMPO_TIMEOUT_USECS {
+                self.tap_times.clear();
+            }
+        }
+
+        self.tap_times.push(usecs);
+        if self.tap_times.len() > Self::TAP_TEMPO_TAPS {
+            self.tap_times.remove(0);
+        }
+
+        match (self.tap_times.first(), self.tap_times.last()) {
+            (Some(first), Some(last)) if last > first => {
+                let usecs_per_beat = (last - first) as f64 / (self.tap_times.len() - 1) as f64;
+                Some(60000000.0 / usecs_per_beat)
+            },
+            _ => None,
+        }
+    }
+
+    // Keep per track state in line with the tracks in sequencer
+    pub fn resize_tracks(&mut self, track_count: usize) {
+        self.phrase_shown.resize(track_count, 0);
+        self.pattern_shown.resize(track_count, 0);
+        self.views.resize(track_count, TrackViews::new());
+        self.knob_page_shown.resize(track_count, 0);
+
+        // Shown track was removed, show the view of the track we fall back to
+        if self.track_shown >= track_count {
+            self.track_shown = track_count - 1;
+            self.recall_view();
+        }
+
+        let banks = self.track_banks(track_count);
+        if self.track_bank >= banks {
+            self.track_bank = banks - 1;
+        }
+    }
+
+    fn track_banks(&self, track_count: usize) -> usize {
+        track_count.div_ceil(self.track_bank_size)
+    }
+
+    pub fn track_bank_offset(&self) -> usize { self.track_bank * self.track_bank_size }
+
+    pub fn next_track_bank(&mut self, track_count: usize) {
+        if self.track_bank + 1 < self.track_banks(track_count) {
+            self.track_bank += 1;
+        }
+    }
 
-            pattern_shown: [0; 16],
-            pattern_zoom_level: 4,
-            pattern_offsets: [0; 16],
-            pattern_base_notes: [60; 16],
+    pub fn previous_track_bank(&mut self) {
+        if self.track_bank > 0 {
+            self.track_bank -= 1;
         }
     }
 
@@ -133,14 +296,65 @@ impl Surface {
         self.view = view;
     }
 
-    pub fn show_track(&mut self, index: u8) { self.track_shown = index; }
-    pub fn track_shown(&self) -> usize { self.track_shown as usize }
+    pub fn toggle_row_mode(&mut self) {
+        self.row_mode = match self.row_mode {
+            RowMode::Loopable => RowMode::Mixer,
+            RowMode::Mixer => RowMode::Loopable,
+        };
+    }
+
+    pub fn show_track(&mut self, index: usize) {
+        self.remember_view();
+        self.track_shown = index;
+        self.recall_view();
+    }
+    pub fn track_shown(&self) -> usize { self.track_shown }
     pub fn show_sequence(&mut self, index: u8) { self.sequence_shown = index; }
     pub fn sequence_shown(&self) -> usize { self.sequence_shown as usize }
     pub fn phrase_shown(&self, track_index: usize) -> u8 { self.phrase_shown[track_index] }
-    pub fn show_phrase(&mut self, track_index: usize, index: u8) { self.phrase_shown[track_index] = index }
+    pub fn show_phrase(&mut self, track_index: usize, index: u8) {
+        self.remember_view();
+        self.phrase_shown[track_index] = index;
+        self.recall_view();
+    }
     pub fn pattern_shown(&self, track_index: usize) -> u8 { self.pattern_shown[track_index] }
-    pub fn show_pattern(&mut self, track_index: usize, index: u8) { self.pattern_shown[track_index] = index }
+    pub fn show_pattern(&mut self, track_index: usize, index: u8) {
+        self.remember_view();
+        self.pattern_shown[track_index] = index;
+        self.recall_view();
+    }
+
+    // Keep view of shown pattern & phrase around, so we can show them like this again later
+    fn remember_view(&mut self) {
+        let views = &mut self.views[self.track_shown];
+        views.patterns[self.pattern_shown[self.track_shown] as usize] = self.pattern_grid.view();
+        views.phrases[self.phrase_shown[self.track_shown] as usize] = self.phrase_grid.view();
+    }
+
+    fn recall_view(&mut self) {
+        let views = &self.views[self.track_shown];
+        self.pattern_grid.set_view(views.patterns[self.pattern_shown[self.track_shown] as usize]);
+        self.phrase_grid.set_view(views.phrases[self.phrase_shown[self.track_shown] as usize]);
+    }
+
+    // Copy views of all tracks into views to save them in project, reusing it's memory
+    pub fn copy_views(&self, views: &mut Vec<TrackViews>) {
+        views.clone_from(&self.views);
+        let shown = &mut views[self.track_shown];
+        shown.patterns[self.pattern_shown[self.track_shown] as usize] = self.pattern_grid.view();
+        shown.phrases[self.phrase_shown[self.track_shown] as usize] = self.phrase_grid.view();
+    }
+
+    // Restore views from project, tracks without saved views keep theirs
+    pub fn restore_views(&mut self, views: &[TrackViews]) {
+        for (view, saved) in self.views.iter_mut().zip(views) {
+            *view = saved.clone();
+        }
+
+        self.recall_view();
+    }
+    pub fn knob_page_shown(&self, track_index: usize) -> u8 { self.knob_page_shown[track_index] }
+    pub fn show_knob_page(&mut self, track_index: usize, page: u8) { self.knob_page_shown[track_index] = page }
 
     pub fn pattern_ticks_per_button(&self) -> u32 { self.pattern_grid.ticks_per_button() }
     pub fn pattern_ticks_in_grid(&self) -> u32 { self.pattern_ticks_per_button() * 8 }
@@ -148,17 +362,23 @@ impl Surface {
     pub fn phrase_ticks_in_grid(&self) -> u32 { self.phrase_ticks_per_button() * 8 }
     pub fn timeline_ticks_in_grid(&self) -> u32 { self.timeline_grid.ticks_per_button() }
 
+    /*
+     * Scrub through shown loopables, factor is position between 0 & 1 relative to the length of
+     * every loopable
+     */
     pub fn set_offsets_by_factor(&mut self, sequencer: &Sequencer, track_index: usize, factor: f64) {
-        //let max_phrase_offset = self.max_phrase_offset(sequencer, track_index);
-        //let phrase_offset = (max_phrase_offset as f64 * factor) as u32;
-        //self.set_phrase_offset(sequencer, track_index, phrase_offset);
-        //let max_pattern_offset = self.max_pattern_offset(sequencer, track_index);
-        //let pattern_offset = (max_pattern_offset as f64 * factor) as u32;
-        //self.set_pattern_offset(sequencer, track_index, pattern_offset);
-        //let max_timeline_offset = self.max_timeline_offset(sequencer);
-        //let timeline_offset = (max_timeline_offset as f64 * factor) as u32;
-        //self.set_timeline_offset(sequencer, timeline_offset);
-        // TODO - Timeline
+        let track = sequencer.track(track_index);
+
+        let pattern_length = track.pattern(self.pattern_shown(track_index)).length();
+        let max_pattern_offset = self.pattern_grid.max_offset_x(pattern_length, 8);
+        self.pattern_grid.set_offset_x((max_pattern_offset as f64 * factor) as u32, max_pattern_offset);
+
+        let phrase_length = track.phrase(self.phrase_shown(track_index)).length();
+        let max_phrase_offset = self.phrase_grid.max_offset_x(phrase_length, 8);
+        self.phrase_grid.set_offset_x((max_phrase_offset as f64 * factor) as u32, max_phrase_offset);
+
+        let max_timeline_offset = self.timeline_grid.max_offset_x(sequencer.timeline_end(), 8);
+        self.timeline_grid.set_offset_x((max_timeline_offset as f64 * factor) as u32, max_timeline_offset);
     }
 }
 
@@ -176,8 +396,11 @@ pub struct EventMemory {
 }
 
 impl EventMemory {
+    // Knob & fader values are part of event type, so events would pile up without a limit
+    const CAPACITY: usize = 256;
+
     fn new() -> Self {
-        Self { occurred_events: vec![] }
+        Self { occurred_events: Vec::with_capacity(Self::CAPACITY) }
     }
 
     pub fn register_event(&mut self, controller_track_offset: u8, time: u64, event_type: InputEventType) {
@@ -186,8 +409,12 @@ impl EventMemory {
 
         if let Some(event) = previous {
             event.time = time;
-        } else {
+        } else if self.occurred_events.len() < Self::CAPACITY {
             self.occurred_events.push(OccurredInputEvent { controller_track_offset, time, event_type });
+        } else {
+            // Memory is full, forget the event that occurred longest ago
+            let oldest = self.occurred_events.iter_mut().min_by_key(|event| event.time).unwrap();
+            *oldest = OccurredInputEvent { controller_track_offset, time, event_type };
         }
     }
 
@@ -229,8 +456,11 @@ pub struct ButtonMemory {
  * This will keep track of button presses so we can support double press & range press
  */
 impl ButtonMemory {
+    // More buttons than this are not held at once, presses beyond it are ignored
+    const CAPACITY: usize = 64;
+
     pub fn new() -> Self {
-        Self { pressed_buttons: vec![] }
+        Self { pressed_buttons: Vec::with_capacity(Self::CAPACITY) }
     }
 
     //pub fn register_event(&mut self, controller_track_offset: u8, time: u64, InputEvent:)
@@ -238,7 +468,9 @@ impl ButtonMemory {
     // We pressed a button!
     pub fn press(&mut self, controller_track_offset: u8, button_type: ButtonType) {
         // Save pressed_button to keep track of modifing keys (multiple keys pressed twice)
-        self.pressed_buttons.push(ButtonPress { controller_track_offset, button_type, });
+        if self.pressed_buttons.len() < Self::CAPACITY {
+            self.pressed_buttons.push(ButtonPress { controller_track_offset, button_type, });
+        }
     }
 
     pub fn release(&mut self, controller_track_offset: u8, _end: u64, button_type: ButtonType) {
@@ -270,3 +502,24 @@ impl ButtonMemory {
             .and_then(|pressed_button| Some(pressed_button))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn view_loops() {
+        let mut surface = Surface::new(2, 16);
+        surface.phrase_grid.set_view_loop(Some((0, 1536)));
+
+        // Other phrases & tracks don't loop the view we looped
+        surface.show_phrase(0, 1);
+        assert_eq!(surface.phrase_grid.view_loop(), None);
+        surface.show_track(1);
+        assert_eq!(surface.phrase_grid.view_loop(), None);
+
+        surface.show_track(0);
+        surface.show_phrase(0, 0);
+        assert_eq!(surface.phrase_grid.view_loop(), Some((0, 1536)));
+    }
+}
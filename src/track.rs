@@ -1,10 +1,71 @@
 
+use serde::{Serialize, Deserialize};
 use super::port::*;
 use super::loopable::*;
 use super::cycle::*;
 use super::events::*;
 use super::message::*;
 
+// Everything about a track we save to a project
+#[derive(Serialize, Deserialize)]
+pub struct TrackState {
+    pub patterns: [Pattern; 5],
+    pub phrases: [Phrase; 5],
+    pub timeline: Timeline,
+
+    pub muted: bool,
+    pub soloed: bool,
+    #[serde(default)]
+    pub armed: bool,
+    pub channel: u8,
+
+    #[serde(default)]
+    pub knob_values: Vec<u8>,
+    #[serde(default = "Track::default_knob_controls")]
+    pub knob_controls: Vec<u8>,
+}
+
+impl TrackState {
+    pub fn new() -> Self {
+        TrackState {
+            patterns: [Pattern::new(), Pattern::new(), Pattern::new(), Pattern::new(), Pattern::new()],
+            phrases: [Phrase::new(), Phrase::new(), Phrase::new(), Phrase::new(), Phrase::new()],
+            timeline: Timeline::new(),
+
+            muted: false,
+            soloed: false,
+            armed: false,
+            channel: 0,
+
+            knob_values: vec![0; Track::KNOB_COUNT],
+            knob_controls: Track::default_knob_controls(),
+        }
+    }
+
+    // Older projects don't have (all) knobs
+    pub fn complete_knobs(&mut self) {
+        self.knob_values.resize(Track::KNOB_COUNT, 0);
+        self.knob_controls.truncate(Track::KNOB_COUNT);
+        let known = self.knob_controls.len();
+        self.knob_controls.extend(Track::default_knob_controls().into_iter().skip(known));
+    }
+
+    // Make room for events in every loopable, so tracks can be copied in without allocating
+    pub fn reserve(&mut self, events: usize) {
+        self.patterns.iter_mut().for_each(|pattern| pattern.note_events.reserve(events));
+        self.phrases.iter_mut().for_each(|phrase| phrase.pattern_events.reserve(events));
+        self.timeline.phrase_events.reserve(events);
+    }
+
+    pub fn has_room(&self, events: usize) -> bool {
+        self.patterns.iter().all(|pattern| pattern.note_events.capacity() >= events)
+            && self.phrases.iter().all(|phrase| phrase.pattern_events.capacity() >= events)
+            && self.timeline.phrase_events.capacity() >= events
+            && self.knob_values.capacity() >= Track::KNOB_COUNT
+            && self.knob_controls.capacity() >= Track::KNOB_COUNT
+    }
+}
+
 pub struct Track {
     // TODO - these are public as we're testing with premade patterns
     pub patterns: [Pattern; 5],
@@ -13,75 +74,212 @@ pub struct Track {
 
     playing_notes: Vec<PlayingNoteEvent>,
 
-    //knob_values: [u8; 128],
+    pub muted: bool,
+    pub soloed: bool,
+    pub armed: bool,
+
+    // Midi channel notes & mixer values are sent on
+    pub channel: u8,
+
+    // Values of device knobs & the CC's they are sent as on channel of track, page after page
+    pub knob_values: Vec<u8>,
+    pub knob_controls: Vec<u8>,
+    // Knob CC's that still have to be sent, (time, cc, value)
+    pending_controls: Vec<(u32, u8, u8)>,
 
     output: MidiOut,
 }
 
 impl Track {
-    pub fn new(client: &jack::Client, id: u8) -> Self {
+    // Ports are registered outside of track as they can't be registered from the process thread
+    pub fn new(output: jack::Port<jack::MidiOut>) -> Self {
         let patterns = [Pattern::new(), Pattern::new(), Pattern::new(), Pattern::new(), Pattern::new()];
         let phrases = [Phrase::new(), Phrase::new(), Phrase::new(), Phrase::new(), Phrase::new()];
 
-        //let input = client.register_port("APC20 in", jack::MidiIn::default()).unwrap();
-        let output = client.register_port(format!("Track {}", id).as_str(), jack::MidiOut::default()).unwrap();
-
         Track {
             phrases,
             patterns,
             timeline: Timeline::new(),
 
-            playing_notes: vec![],
+            playing_notes: Vec::with_capacity(Self::PLAYING_NOTES_CAPACITY),
+
+            muted: false,
+            soloed: false,
+            armed: false,
+
+            channel: 0,
+
+            knob_values: vec![0; Self::KNOB_COUNT],
+            knob_controls: Self::default_knob_controls(),
+            pending_controls: Vec::with_capacity(Self::PENDING_CONTROL_CAPACITY),
 
             output: MidiOut::new(output),
         }
     }
 
+    // Knobs per track, pages of the device knobs of the APC40
+    pub const KNOB_PAGES: u8 = 4;
+    pub const KNOBS_PER_PAGE: u8 = 8;
+    // Notes playing at the same time on a track, more notes will allocate
+    const PLAYING_NOTES_CAPACITY: usize = 256;
+
+    pub const KNOB_COUNT: usize = Self::KNOB_PAGES as usize * Self::KNOBS_PER_PAGE as usize;
+    // Knob turns we buffer in a cycle, a couple of turns of every knob
+    const PENDING_CONTROL_CAPACITY: usize = Self::KNOB_COUNT * 4;
+
+    // General purpose & undefined CC's, so we don't step on synths volume, pan etc.
+    pub fn default_knob_controls() -> Vec<u8> {
+        (16 .. 32).chain(102 .. 118).collect()
+    }
+
+    // Knob index of knob on page
+    pub fn knob_index(page: u8, index: u8) -> u8 {
+        page * Self::KNOBS_PER_PAGE + index
+    }
+
+    // Port names are based on position, track 0 is "Track 1"
+    pub fn port_name(prefix: &str, index: usize) -> String {
+        format!("{} {}", prefix, index + 1)
+    }
+
+    // Give up track, returning its port so it can be unregistered
+    pub fn into_port(self) -> jack::Port<jack::MidiOut> {
+        self.output.port
+    }
+
+    // Copy track into state, reusing memory of state
+    pub fn copy_state(&self, state: &mut TrackState) {
+        state.patterns.iter_mut().zip(self.patterns.iter()).for_each(|(copy, pattern)| copy.copy_from(pattern));
+        state.phrases.iter_mut().zip(self.phrases.iter()).for_each(|(copy, phrase)| copy.copy_from(phrase));
+        state.timeline.copy_from(&self.timeline);
+
+        state.muted = self.muted;
+        state.soloed = self.soloed;
+        state.armed = self.armed;
+        state.channel = self.channel;
+
+        state.knob_values.clone_from(&self.knob_values);
+        state.knob_controls.clone_from(&self.knob_controls);
+    }
+
+    // Restore saved state, what we had ends up in state so it can be freed off the process thread
+    pub fn swap_state(&mut self, state: &mut TrackState) {
+        std::mem::swap(&mut self.patterns, &mut state.patterns);
+        std::mem::swap(&mut self.phrases, &mut state.phrases);
+        std::mem::swap(&mut self.timeline, &mut state.timeline);
+
+        self.muted = state.muted;
+        self.soloed = state.soloed;
+        self.armed = state.armed;
+        self.channel = state.channel;
+
+        std::mem::swap(&mut self.knob_values, &mut state.knob_values);
+        std::mem::swap(&mut self.knob_controls, &mut state.knob_controls);
+    }
+
+    // Most events any of the loopables of track has
+    pub fn most_events(&self) -> usize {
+        self.patterns.iter().map(|pattern| pattern.note_events.len())
+            .chain(self.phrases.iter().map(|phrase| phrase.pattern_events.len()))
+            .chain(Some(self.timeline.phrase_events.len()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn knob_value(&self, index: u8) -> u8 {
+        self.knob_values.get(index as usize).copied().unwrap_or(0)
+    }
+
+    // Remember knob value & send it to synth on next output
+    pub fn knob_turned(&mut self, time: u32, index: u8, value: u8) {
+        if let (Some(stored), Some(control)) = (self.knob_values.get_mut(index as usize), self.knob_controls.get(index as usize)) {
+            *stored = value;
+
+            // Turns that don't fit replace the pending turn of their knob, so we don't allocate
+            if self.pending_controls.len() < Self::PENDING_CONTROL_CAPACITY {
+                self.pending_controls.push((time, *control, value));
+            } else if let Some(pending) = self.pending_controls.iter_mut().rev().find(|(_, pending, _)| pending == control) {
+                *pending = (time, *control, value);
+            }
+        }
+    }
+
+    // Send all knob values again, so synths are in line with our state. Pending turns are left
+    // out, as their knobs end up at the values we resend anyway
+    pub fn resend_knobs(&mut self) {
+        let controls = self.knob_controls.iter().zip(self.knob_values.iter())
+            .map(|(control, value)| (0, *control, *value));
+
+        self.pending_controls.clear();
+        self.pending_controls.extend(controls);
+    }
+
     pub fn pattern(&self, index: u8) -> &Pattern { &self.patterns[index as usize] }
     pub fn pattern_mut(&mut self, index: u8) -> &mut Pattern { &mut self.patterns[index as usize] }
 
     pub fn phrase(&self, index: u8) -> &Phrase { &self.phrases[index as usize] }
     pub fn phrase_mut(&mut self, index: u8) -> &mut Phrase { &mut self.phrases[index as usize] }
 
+    // Copying reuses memory of the loopable we copy to
     pub fn clone_pattern(&mut self, from: u8, to: u8) {
-        self.patterns[to as usize] = self.patterns[from as usize].clone();
+        copy_within(&mut self.patterns, from as usize, to as usize, Pattern::copy_from);
     }
 
     pub fn clone_phrase(&mut self, from: u8, to: u8) {
-        self.phrases[to as usize] = self.phrases[from as usize].clone();
+        copy_within(&mut self.phrases, from as usize, to as usize, Phrase::copy_from);
     }
 
     pub fn clear_playing_notes(&mut self) {
-        self.playing_notes = vec![];
+        self.playing_notes.clear();
     }
 
     // Start all notes in playing notes array. Used when starting mid-track
     pub fn start_playing_notes(&mut self, cycle: &ProcessCycle) {
-        let mut messages = self.playing_notes.iter()
-            .map(|note| TimedMessage::new(0, Message::Note([0x90, note.note, note.start_velocity])))
-            .collect();
+        let mut messages = self.output.take_buffer();
+        messages.extend(self.playing_notes.iter()
+            .map(|note| TimedMessage::new(0, Message::Note([0x90 + self.channel, note.note, note.start_velocity]))));
 
-        self.output.write_midi(cycle.scope, &mut messages);
+        self.output.write_buffer(cycle.scope, messages);
     }
 
     // Stop playing notes, used when stopping mid-track
     pub fn stop_playing_notes(&mut self, cycle: &ProcessCycle) {
-        let mut messages = self.playing_notes.iter()
-            .map(|note| TimedMessage::new(0, Message::Note([0x80, note.note, note.stop_velocity])))
-            .collect();
+        let mut messages = self.output.take_buffer();
+        messages.extend(self.playing_notes.iter()
+            .map(|note| TimedMessage::new(0, Message::Note([0x80 + self.channel, note.note, note.stop_velocity]))));
 
-        self.output.write_midi(cycle.scope, &mut messages);
+        self.output.write_buffer(cycle.scope, messages);
     }
 
-    pub fn output_midi(&mut self, cycle: &ProcessCycle, starting_notes: Vec<PlayingNoteEvent>) {
-        // Always play note off messages
-        let mut messages = vec![];
+    pub fn toggle_muted(&mut self) { self.muted = ! self.muted }
+    pub fn toggle_soloed(&mut self) { self.soloed = ! self.soloed }
+    pub fn toggle_armed(&mut self) { self.armed = ! self.armed }
+
+    // Messages contains other messages for track, like mixer CC's
+    // Starting notes & messages are drained, so the buffers can be reused for the next track
+    pub fn output_midi(&mut self, cycle: &ProcessCycle, starting_notes: &mut Vec<PlayingNoteEvent>, is_audible: bool, messages: &mut Vec<TimedMessage>) {
+        let channel = self.channel;
 
+        messages.extend(self.pending_controls.drain(..)
+            .map(|(time, control, value)| TimedMessage::new(time, Message::Note([0xB0 + channel, control, value]))));
+
+        // Silence notes that are still playing when track got muted
+        if ! is_audible {
+            let note_off = self.playing_notes.drain(..)
+                .map(|note| TimedMessage::new(0, Message::Note([0x80 + channel, note.note, note.stop_velocity])));
+
+            messages.extend(note_off);
+            starting_notes.clear();
+            self.output.write_midi(cycle.scope, messages);
+            return
+        }
+
+        // Always play note off messages
         self.playing_notes.retain(|note| {
             // Play & remove notes that fall in cycle
             if cycle.tick_range.contains(note.stop) {
                 let frame = cycle.tick_to_frame(note.stop);
-                messages.push(TimedMessage::new(frame, Message::Note([0x80, note.note, note.stop_velocity])));
+                messages.push(TimedMessage::new(frame, Message::Note([0x80 + channel, note.note, note.stop_velocity])));
                 false
             } else {
                 true
@@ -92,17 +290,28 @@ impl Track {
         let note_on = starting_notes.iter()
             .map(|note| {
                 let frame = cycle.tick_to_frame(note.start);
-                TimedMessage::new(frame, Message::Note([0x90, note.note, note.start_velocity]))
+                TimedMessage::new(frame, Message::Note([0x90 + channel, note.note, note.start_velocity]))
             });
 
         messages.extend(note_on);
 
 
         // Remember playing notes to later trigger note off message & output note on messages
-        self.playing_notes.extend(starting_notes);
+        self.playing_notes.extend(starting_notes.drain(..));
 
         // Output note off mesassages && write midi
         //self.output.output_messages(&mut messages);
-        self.output.write_midi(cycle.scope, &mut messages);
+        self.output.write_midi(cycle.scope, messages);
+    }
+}
+
+// Copy loopable to another loopable in the same array, leaving it alone when they're the same
+fn copy_within<T>(loopables: &mut [T], from: usize, to: usize, copy: fn(&mut T, &T)) {
+    if from < to {
+        let (head, tail) = loopables.split_at_mut(to);
+        copy(&mut tail[0], &head[from]);
+    } else if from > to {
+        let (head, tail) = loopables.split_at_mut(from);
+        copy(&mut head[to], &tail[0]);
     }
 }
@@ -0,0 +1,568 @@
+
+use std::io::{self, Stdout, Write};
+use std::time::{Duration, Instant};
+use crossterm::{cursor, event, queue, terminal};
+use crossterm::event::{KeyCode, KeyEventKind};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use super::bus::{self, Command, Frontend, Snapshot, Status};
+use super::loopable::*;
+use super::events::*;
+use super::track::Track;
+use super::TimebaseHandler;
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+// Same velocity the controllers add notes with
+const VELOCITY: u8 = 127;
+const BEATS_PER_BAR: u32 = 4;
+// Room for note names & track names left of grids
+const LABEL_WIDTH: usize = 9;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_millis(200);
+const FRAME_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum View {
+    Pattern,
+    Timeline,
+    Sequence,
+}
+
+/*
+ * Terminal ui, shows & edits the session through the bus so we can work without controllers.
+ * Session state comes from snapshots, which are refreshed every now and then & after every edit
+ */
+pub struct Tui<'a> {
+    frontend: &'a Frontend,
+    client: &'a jack::Client,
+    // Track ports are named after this
+    track_port: &'a str,
+
+    snapshot: Option<Snapshot>,
+    refreshed: Option<Instant>,
+    status: Status,
+    // Connections of track ports, refreshed with snapshot
+    routing: Vec<String>,
+    // Shown at the bottom, failed commands end up here
+    message: String,
+
+    view: View,
+    track: usize,
+    pattern: u8,
+    // Phrase that's added to timeline
+    phrase: u8,
+    sequence: usize,
+
+    // Cursor & scroll position of pattern & timeline, in columns
+    pattern_column: u32,
+    pattern_offset: u32,
+    timeline_column: u32,
+    timeline_offset: u32,
+    note: u8,
+    top_note: u8,
+    track_offset: usize,
+
+    pattern_ticks_per_column: u32,
+    timeline_ticks_per_column: u32,
+}
+
+impl<'a> Tui<'a> {
+    pub fn new(frontend: &'a Frontend, client: &'a jack::Client, track_port: &'a str) -> Self {
+        let ticks_per_beat = TimebaseHandler::TICKS_PER_BEAT as u32;
+
+        Tui {
+            frontend,
+            client,
+            track_port,
+
+            snapshot: None,
+            refreshed: None,
+            status: Status::default(),
+            routing: vec![],
+            message: String::new(),
+
+            view: View::Pattern,
+            track: 0,
+            pattern: 0,
+            phrase: 0,
+            sequence: 0,
+
+            pattern_column: 0,
+            pattern_offset: 0,
+            timeline_column: 0,
+            timeline_offset: 0,
+            note: 60,
+            top_note: 72,
+            track_offset: 0,
+
+            // 16th notes & beats
+            pattern_ticks_per_column: ticks_per_beat / 4,
+            timeline_ticks_per_column: ticks_per_beat,
+        }
+    }
+
+    // Run until user quits, terminal is restored also when drawing fails
+    pub fn run(&mut self) -> Result<(), String> {
+        let mut stdout = io::stdout();
+
+        terminal::enable_raw_mode().map_err(|e| format!("Could not start terminal ui: {}", e))?;
+        queue!(stdout, terminal::EnterAlternateScreen, cursor::Hide).ok();
+
+        let result = self.event_loop(&mut stdout);
+
+        queue!(stdout, cursor::Show, terminal::LeaveAlternateScreen).ok();
+        stdout.flush().ok();
+        terminal::disable_raw_mode().ok();
+        result
+    }
+
+    fn event_loop(&mut self, stdout: &mut Stdout) -> Result<(), String> {
+        loop {
+            for event in self.frontend.events() {
+                match event {
+                    bus::Event::Status(status) => self.status = status,
+                    bus::Event::Failed(command, reason) => self.message = format!("{:?}: {}", command, reason),
+                    bus::Event::Led { .. } => (),
+                }
+            }
+
+            if self.refreshed.map(|refreshed| refreshed.elapsed() > REFRESH_INTERVAL).unwrap_or(true) {
+                self.refresh();
+            }
+
+            self.draw(stdout).map_err(|e| format!("Could not draw terminal ui: {}", e))?;
+
+            if event::poll(FRAME_INTERVAL).map_err(|e| e.to_string())? {
+                if let event::Event::Key(key) = event::read().map_err(|e| e.to_string())? {
+                    if key.kind == KeyEventKind::Press && ! self.process_key(key.code) {
+                        return Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.refreshed = Some(Instant::now());
+
+        match self.frontend.snapshot(SNAPSHOT_TIMEOUT) {
+            Ok(snapshot) => {
+                self.status = snapshot.status;
+                self.track = self.track.min(snapshot.project.tracks.len().saturating_sub(1));
+                self.snapshot = Some(snapshot);
+            },
+            Err(error) => self.message = error,
+        }
+
+        let client_name = self.client.name().to_string();
+        self.routing = (0 .. self.status.track_count)
+            .map(|index| {
+                self.client.port_by_name(&format!("{}:{}", client_name, Track::port_name(self.track_port, index)))
+                    .map(|port| port.get_connections().join(", "))
+                    .unwrap_or_default()
+            })
+            .collect();
+    }
+
+    fn send(&mut self, command: Command) {
+        match self.frontend.send(command) {
+            // Edits should show right away
+            Ok(_) => self.refreshed = None,
+            Err(error) => self.message = error,
+        }
+    }
+
+    // Returns false when user wants to quit
+    fn process_key(&mut self, code: KeyCode) -> bool {
+        let track_count = self.status.track_count.max(1);
+
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Tab => {
+                self.view = match self.view {
+                    View::Pattern => View::Timeline,
+                    View::Timeline => View::Sequence,
+                    View::Sequence => View::Pattern,
+                };
+            },
+            KeyCode::Char(' ') => self.send(if self.status.is_rolling { Command::Stop } else { Command::Start }),
+            KeyCode::Char('r') => self.send(Command::Reset),
+            KeyCode::Char('s') => self.send(Command::Save),
+            KeyCode::Char('u') => self.send(Command::Undo),
+            KeyCode::Char('U') => self.send(Command::Redo),
+            KeyCode::Char(',') | KeyCode::Char('<') => self.track = self.track.saturating_sub(1),
+            KeyCode::Char('.') | KeyCode::Char('>') => self.track = (self.track + 1).min(track_count - 1),
+            KeyCode::Char('+') | KeyCode::Char('=') => self.zoom(true),
+            KeyCode::Char('-') => self.zoom(false),
+            KeyCode::Char('c') => match self.view {
+                View::Pattern => self.send(Command::ClearPattern { track: self.track, pattern: self.pattern }),
+                View::Timeline => self.send(Command::ClearTimeline { track: self.track }),
+                View::Sequence => (),
+            },
+            KeyCode::Char(digit @ '0' ..= '5') => {
+                let index = digit as u8 - b'0';
+
+                match (self.view, index) {
+                    (View::Pattern, 1 ..= 5) => self.pattern = index - 1,
+                    (View::Timeline, 1 ..= 5) => self.phrase = index - 1,
+                    (View::Sequence, _) => {
+                        let phrase = if index == 0 { None } else { Some(index - 1) };
+                        self.send(Command::SetSequencePhrase { sequence: self.sequence, track: self.track, phrase });
+                    },
+                    _ => (),
+                }
+            },
+            KeyCode::Char('a') if self.view == View::Sequence => {
+                self.send(Command::ToggleSequenceTrack { sequence: self.sequence, track: self.track });
+            },
+            KeyCode::Enter => self.toggle(),
+            KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down => self.move_cursor(code, track_count),
+            _ => (),
+        }
+
+        true
+    }
+
+    fn move_cursor(&mut self, code: KeyCode, track_count: usize) {
+        match (self.view, code) {
+            (View::Pattern, KeyCode::Left) => self.pattern_column = self.pattern_column.saturating_sub(1),
+            (View::Pattern, KeyCode::Right) => self.pattern_column += 1,
+            (View::Pattern, KeyCode::Up) => self.note = (self.note + 1).min(127),
+            (View::Pattern, KeyCode::Down) => self.note = self.note.saturating_sub(1),
+            (View::Timeline, KeyCode::Left) => self.timeline_column = self.timeline_column.saturating_sub(1),
+            (View::Timeline, KeyCode::Right) => self.timeline_column += 1,
+            (View::Sequence, KeyCode::Left) => self.sequence = self.sequence.saturating_sub(1),
+            (View::Sequence, KeyCode::Right) => self.sequence = (self.sequence + 1).min(4),
+            (_, KeyCode::Up) => self.track = self.track.saturating_sub(1),
+            (_, KeyCode::Down) => self.track = (self.track + 1).min(track_count - 1),
+            _ => (),
+        }
+    }
+
+    // Keep tick under cursor around when zooming
+    fn zoom(&mut self, is_in: bool) {
+        let (ticks_per_column, column, minimum, maximum) = match self.view {
+            View::Pattern => (&mut self.pattern_ticks_per_column, &mut self.pattern_column, 60, 1920),
+            View::Timeline => (&mut self.timeline_ticks_per_column, &mut self.timeline_column, 480, 7680),
+            View::Sequence => return,
+        };
+
+        let tick = *column * *ticks_per_column;
+        *ticks_per_column = if is_in { *ticks_per_column / 2 } else { *ticks_per_column * 2 }.max(minimum).min(maximum);
+        *column = tick / *ticks_per_column;
+    }
+
+    // Add or remove event under cursor, queue sequence in sequence view
+    fn toggle(&mut self) {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let track = match snapshot.project.tracks.get(self.track) {
+            Some(track) => track,
+            None => return,
+        };
+
+        let command = match self.view {
+            View::Pattern => {
+                let start = self.pattern_column * self.pattern_ticks_per_column;
+                let pattern = &track.patterns[self.pattern as usize];
+                let existing = pattern.note_events.iter()
+                    .find(|event| event.note == self.note && event.overlaps_tick_range(start, start + 1));
+
+                match existing {
+                    Some(event) => Command::RemoveNote { track: self.track, pattern: self.pattern, start: event.start, note: event.note },
+                    None => Command::AddNote {
+                        track: self.track, pattern: self.pattern, start, stop: start + self.pattern_ticks_per_column, note: self.note, velocity: VELOCITY,
+                    },
+                }
+            },
+            View::Timeline => {
+                let start = self.timeline_column * self.timeline_ticks_per_column;
+                let existing = track.timeline.phrase_events.iter()
+                    .find(|event| event.overlaps_tick_range(start, start + 1));
+
+                match existing {
+                    Some(event) => Command::RemovePhraseEvent { track: self.track, start: event.start, phrase: event.phrase },
+                    None => {
+                        let length = track.phrases[self.phrase as usize].length();
+                        Command::AddPhraseEvent { track: self.track, start, stop: start + length, phrase: self.phrase }
+                    },
+                }
+            },
+            View::Sequence => Command::QueueSequence(self.sequence),
+        };
+
+        self.send(command);
+    }
+
+    fn draw(&mut self, stdout: &mut Stdout) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        // Header, help & ruler on top, message at the bottom
+        let rows = height.saturating_sub(4);
+        let columns = width.saturating_sub(LABEL_WIDTH) as u32;
+
+        let (mut lines, cursor) = match self.view {
+            View::Pattern => self.pattern_lines(rows, columns),
+            View::Timeline => self.timeline_lines(rows, columns),
+            View::Sequence => self.sequence_lines(rows),
+        };
+        lines.insert(0, self.header());
+        lines.insert(1, self.help().to_string());
+        lines.resize(height.saturating_sub(1), String::new());
+        lines.push(self.message.clone());
+
+        for (y, line) in lines.iter().enumerate() {
+            let line: String = line.chars().take(width).collect();
+            queue!(stdout, cursor::MoveTo(0, y as u16), Print(line), terminal::Clear(terminal::ClearType::UntilNewLine))?;
+        }
+
+        // Cursor is drawn in reverse over the view, which starts at line 2
+        if let Some((x, y, character)) = cursor {
+            if x < width && y + 2 < height {
+                queue!(stdout, cursor::MoveTo(x as u16, (y + 2) as u16), SetAttribute(Attribute::Reverse), Print(character), SetAttribute(Attribute::Reset))?;
+            }
+        }
+
+        stdout.flush()
+    }
+
+    fn header(&self) -> String {
+        let ticks_per_beat = TimebaseHandler::TICKS_PER_BEAT as u32;
+        let beat = self.status.tick / ticks_per_beat;
+        let queued = self.status.sequence_queued.map(|index| format!(", queued {}", index + 1)).unwrap_or_default();
+
+        format!(
+            "Octothorpe  {}  {}.{}.{:04}  {:.0} bpm  sequence {}{}  track {} of {}",
+            if self.status.is_rolling { "playing" } else { "stopped" },
+            beat / BEATS_PER_BAR + 1, beat % BEATS_PER_BAR + 1, self.status.tick % ticks_per_beat,
+            self.status.beats_per_minute,
+            self.status.sequence_playing + 1, queued,
+            self.track + 1, self.status.track_count,
+        )
+    }
+
+    fn help(&self) -> &'static str {
+        match self.view {
+            View::Pattern => "[tab] view [space] play [arrows] cursor [enter] note [1-5] pattern [,.] track [+-] zoom [c] clear [u/U] undo/redo [s] save [q] quit",
+            View::Timeline => "[tab] view [space] play [arrows] cursor [enter] phrase [1-5] phrase to add [+-] zoom [c] clear [u/U] undo/redo [s] save [q] quit",
+            View::Sequence => "[tab] view [space] play [arrows] cursor [enter] queue [0-5] phrase [a] active [u/U] undo/redo [s] save [q] quit",
+        }
+    }
+
+    // Ruler with bar numbers, first row of grid views
+    fn ruler(offset: u32, columns: u32, ticks_per_column: u32, playhead: Option<u32>) -> String {
+        let ticks_per_bar = TimebaseHandler::TICKS_PER_BEAT as u32 * BEATS_PER_BAR;
+        let mut ruler = " ".repeat(LABEL_WIDTH);
+        let mut skip = 0;
+
+        for column in offset .. offset + columns {
+            let tick = column * ticks_per_column;
+            let is_playhead = playhead.map(|playhead| playhead >= tick && playhead < tick + ticks_per_column).unwrap_or(false);
+
+            if is_playhead {
+                ruler.push('v');
+            } else if skip > 0 {
+                skip -= 1;
+            } else if tick % ticks_per_bar == 0 {
+                let bar = (tick / ticks_per_bar + 1).to_string();
+                skip = bar.len() - 1;
+                ruler.push_str(&bar);
+            } else {
+                ruler.push(' ');
+            }
+        }
+
+        ruler
+    }
+
+    // Keep cursor column in view
+    fn scroll(offset: &mut u32, column: u32, columns: u32) {
+        if column < *offset {
+            *offset = column;
+        } else if columns > 0 && column >= *offset + columns {
+            *offset = column - columns + 1;
+        }
+    }
+
+    fn pattern_lines(&mut self, rows: usize, columns: u32) -> (Vec<String>, Option<(usize, usize, char)>) {
+        Self::scroll(&mut self.pattern_offset, self.pattern_column, columns);
+
+        // Keep cursor note in view, there's no view in terminals without room for notes
+        let rows = rows.min(128);
+        if rows == 0 {
+            return (vec![], None);
+        }
+        if self.note > self.top_note {
+            self.top_note = self.note;
+        } else if (self.top_note - self.note) as usize >= rows {
+            self.top_note = self.note + rows as u8 - 1;
+        }
+        self.top_note = self.top_note.max((rows as u8).saturating_sub(1)).min(127);
+
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return (vec![], None),
+        };
+        // Snapshot lags behind the track count of status we clamp to, after loading a project
+        // with less tracks we wait for the next one
+        let pattern = match snapshot.project.tracks.get(self.track) {
+            Some(track) => &track.patterns[self.pattern as usize],
+            None => return (vec![], None),
+        };
+        let grid = pattern_grid(pattern, self.top_note, rows, self.pattern_offset, columns, self.pattern_ticks_per_column);
+
+        let mut lines = vec![Self::ruler(self.pattern_offset, columns, self.pattern_ticks_per_column, None)];
+        lines.extend(grid);
+
+        let x = LABEL_WIDTH + (self.pattern_column - self.pattern_offset) as usize;
+        let y = (self.top_note - self.note) as usize + 1;
+        let character = lines.get(y).and_then(|line| line.chars().nth(x)).unwrap_or(' ');
+        (lines, Some((x, y, character)))
+    }
+
+    fn timeline_lines(&mut self, rows: usize, columns: u32) -> (Vec<String>, Option<(usize, usize, char)>) {
+        Self::scroll(&mut self.timeline_offset, self.timeline_column, columns);
+        self.track_offset = scroll_rows(self.track_offset, self.track, rows);
+
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return (vec![], None),
+        };
+
+        let playhead = if self.status.is_rolling { Some(self.status.tick) } else { None };
+        let mut lines = vec![Self::ruler(self.timeline_offset, columns, self.timeline_ticks_per_column, playhead)];
+
+        for (index, track) in snapshot.project.tracks.iter().enumerate().skip(self.track_offset).take(rows) {
+            let mut line = format!("{:<width$}", Track::port_name(self.track_port, index), width = LABEL_WIDTH);
+
+            for column in self.timeline_offset .. self.timeline_offset + columns {
+                let start = column * self.timeline_ticks_per_column;
+                let stop = start + self.timeline_ticks_per_column;
+                let event = track.timeline.phrase_events.iter().find(|event| event.overlaps_tick_range(start, stop));
+
+                line.push(match event {
+                    Some(event) if event.start >= start => (b'1' + event.phrase) as char,
+                    Some(_) => '=',
+                    None => '.',
+                });
+            }
+
+            lines.push(line);
+        }
+
+        let x = LABEL_WIDTH + (self.timeline_column - self.timeline_offset) as usize;
+        let y = self.track - self.track_offset + 1;
+        let character = lines.get(y).and_then(|line| line.chars().nth(x)).unwrap_or(' ');
+        (lines, Some((x, y, character)))
+    }
+
+    // Phrases every sequence plays per track & where tracks are connected to
+    fn sequence_lines(&mut self, rows: usize) -> (Vec<String>, Option<(usize, usize, char)>) {
+        self.track_offset = scroll_rows(self.track_offset, self.track, rows);
+
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return (vec![], None),
+        };
+
+        let mut header = " ".repeat(LABEL_WIDTH);
+        for index in 0 .. snapshot.project.sequences.len() {
+            let marker = if index == self.status.sequence_playing { '>' } else if Some(index) == self.status.sequence_queued { '*' } else { ' ' };
+            header.push_str(&format!("{}S{} ", marker, index + 1));
+        }
+        header.push_str(" output");
+        let mut lines = vec![header];
+
+        for index in (0 .. snapshot.project.tracks.len()).skip(self.track_offset).take(rows) {
+            let mut line = format!("{:<width$}", Track::port_name(self.track_port, index), width = LABEL_WIDTH);
+
+            for sequence in snapshot.project.sequences.iter() {
+                // Inactive phrases are shown between brackets
+                line.push_str(&match (sequence.get_phrase(index), sequence.active_phrase(index)) {
+                    (Some(phrase), Some(_)) => format!("  {} ", phrase + 1),
+                    (Some(phrase), None) => format!(" ({})", phrase + 1),
+                    (None, _) => "  - ".to_string(),
+                });
+            }
+
+            line.push_str("  ");
+            line.push_str(self.routing.get(index).map(|routing| routing.as_str()).unwrap_or(""));
+            lines.push(line);
+        }
+
+        let x = LABEL_WIDTH + self.sequence * 4 + 2;
+        let y = self.track - self.track_offset + 1;
+        let character = lines.get(y).and_then(|line| line.chars().nth(x)).unwrap_or(' ');
+        (lines, Some((x, y, character)))
+    }
+}
+
+fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+// Keep row in view
+fn scroll_rows(offset: usize, row: usize, rows: usize) -> usize {
+    if row < offset {
+        row
+    } else if rows > 0 && row >= offset + rows {
+        row - rows + 1
+    } else {
+        offset
+    }
+}
+
+/*
+ * Piano roll of pattern, # is where a note starts, = is where it's held. Columns past the length of
+ * the pattern are left empty
+ */
+fn pattern_grid(pattern: &Pattern, top_note: u8, rows: usize, offset: u32, columns: u32, ticks_per_column: u32) -> Vec<String> {
+    let length = pattern.length();
+
+    (0 .. rows)
+        .take_while(|row| *row <= top_note as usize)
+        .map(|row| {
+            let note = top_note - row as u8;
+            let mut line = format!("{:<width$}", note_name(note), width = LABEL_WIDTH);
+
+            for column in offset .. offset + columns {
+                let start = column * ticks_per_column;
+                let stop = start + ticks_per_column;
+                let events = pattern.note_events.iter().filter(|event| event.note == note && event.overlaps_tick_range(start, stop));
+
+                let character = events.fold(None, |character, event| {
+                    if event.start >= start && event.start < stop { Some('#') } else { character.or(Some('=')) }
+                });
+
+                line.push(match character {
+                    Some(character) => character,
+                    None if start >= length => ' ',
+                    None if start % TimebaseHandler::TICKS_PER_BEAT as u32 == 0 => ':',
+                    None => '.',
+                });
+            }
+
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn piano_roll() {
+        let mut pattern = Pattern::new();
+        let mut event = LoopableNoteEvent::new(480, 60, 127);
+        event.set_stop(1440);
+        event.stop_velocity = Some(127);
+        pattern.add_complete_event(event);
+
+        let grid = pattern_grid(&pattern, 61, 2, 0, 5, 480);
+        assert_eq!(grid[0], "C#4      :...:");
+        assert_eq!(grid[1], "C4       :#=.:");
+    }
+}